@@ -0,0 +1,332 @@
+//! Parses the clip info files (`CLIPINF/*.clpi`) that describe the streams of
+//! a clip, so that segments can be labelled and checked without a playlist.
+//! All multi-byte fields are big-endian.
+
+use super::{be_u16, be_u32};
+use crate::{
+    libav::{Framerate, ScanType, VideoMetadata},
+    Segment,
+};
+use anyhow::Context;
+use std::{fs::File, io::Read};
+
+/// The size of a source packet: a transport stream packet and its 4 byte
+/// arrival time stamp.
+const SOURCE_PACKET_SIZE: u64 = 192;
+
+const CODING_TYPE_MPEG1_VIDEO: u8 = 0x01;
+const CODING_TYPE_MPEG2_VIDEO: u8 = 0x02;
+const CODING_TYPE_AVC: u8 = 0x1b;
+const CODING_TYPE_MVC: u8 = 0x20;
+const CODING_TYPE_HEVC: u8 = 0x24;
+const CODING_TYPE_VC1: u8 = 0xea;
+
+/// A stream of the clip, as listed in its program info.
+#[derive(Debug, Clone)]
+pub struct ClipStream {
+    pub pid: u16,
+    pub coding_type: u8,
+    /// The ISO 639-2 code of audio and subtitle streams.
+    pub language: Option<String>,
+    /// The frame rate and scan type of video streams.
+    pub video: Option<VideoMetadata>,
+}
+
+/// A point in the clip where decoding of the video can start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryPoint {
+    /// In 45 kHz ticks.
+    pub pts: u32,
+    /// The number of the source packet, which are 192 bytes each.
+    pub spn: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipInfo {
+    pub streams: Vec<ClipStream>,
+    /// The start and end presentation time of every STC sequence, in 45 kHz
+    /// ticks.
+    pub presentation_times: Vec<(u32, u32)>,
+    /// The entry points of the first stream in the EP map, which is the video.
+    pub entry_points: Vec<EntryPoint>,
+}
+
+impl ClipStream {
+    pub fn is_video(&self) -> bool {
+        matches!(
+            self.coding_type,
+            CODING_TYPE_MPEG1_VIDEO
+                | CODING_TYPE_MPEG2_VIDEO
+                | CODING_TYPE_AVC
+                | CODING_TYPE_MVC
+                | CODING_TYPE_HEVC
+                | CODING_TYPE_VC1
+        )
+    }
+
+    pub fn is_audio(&self) -> bool {
+        matches!(self.coding_type, 0x03 | 0x04 | 0x80..=0x86 | 0xa1 | 0xa2)
+    }
+}
+
+impl ClipInfo {
+    /// Reads the clip info of the given segment, from the `CLIPINF` directory
+    /// next to its `STREAM` directory.
+    pub fn read(segment: &Segment) -> anyhow::Result<ClipInfo> {
+        let path = {
+            let mut p = segment.path.clone();
+            p.pop();
+            p.pop();
+            p.push("CLIPINF");
+            p.push(format!("{}.clpi", segment.clip_name()));
+            p
+        };
+
+        let mut data = Vec::new();
+        match segment.image {
+            Some(ref image) => image
+                .open_file(&path.to_string_lossy())?
+                .read_to_end(&mut data)?,
+            None => File::open(&path)?.read_to_end(&mut data)?,
+        };
+        ClipInfo::parse(&data).with_context(|| format!("Invalid clip info {}", path.display()))
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<ClipInfo> {
+        if data.get(..4) != Some(&b"HDMV"[..]) {
+            anyhow::bail!("not a clip info file");
+        }
+        let sequence_info = be_u32(data, 8)? as usize;
+        let program_info = be_u32(data, 12)? as usize;
+        let cpi = be_u32(data, 16)? as usize;
+
+        // both start with their length and a reserved byte
+        let mut presentation_times = Vec::new();
+        let num_atc_sequences = *data.get(sequence_info + 5).unwrap_or(&0);
+        let mut offset = sequence_info + 6;
+        for _ in 0..num_atc_sequences {
+            let num_stc_sequences = *data.get(offset + 4).unwrap_or(&0);
+            offset += 6;
+            for _ in 0..num_stc_sequences {
+                presentation_times.push((be_u32(data, offset + 6)?, be_u32(data, offset + 10)?));
+                offset += 14;
+            }
+        }
+
+        let mut streams = Vec::new();
+        let num_programs = *data.get(program_info + 5).unwrap_or(&0);
+        let mut offset = program_info + 6;
+        for _ in 0..num_programs {
+            let num_streams = *data.get(offset + 6).unwrap_or(&0);
+            offset += 8;
+            for _ in 0..num_streams {
+                let pid = be_u16(data, offset)?;
+                let len = *data.get(offset + 2).unwrap_or(&0) as usize;
+                let attributes = data
+                    .get(offset + 3..offset + 3 + len)
+                    .filter(|a| !a.is_empty())
+                    .with_context(|| format!("stream {:#X} is truncated", pid))?;
+                streams.push(parse_stream(pid, attributes));
+                offset += 3 + len;
+            }
+        }
+
+        Ok(ClipInfo {
+            streams,
+            presentation_times,
+            entry_points: parse_ep_map(data, cpi)?,
+        })
+    }
+
+    /// The languages of the audio streams, by PID.
+    pub fn audio_languages(&self) -> Vec<(i32, String)> {
+        self.streams
+            .iter()
+            .filter(|s| s.is_audio())
+            .filter_map(|s| Some((s.pid as i32, s.language.clone()?)))
+            .collect()
+    }
+
+    /// The presentation duration of the clip, in seconds.
+    pub fn duration(&self) -> f64 {
+        self.presentation_times
+            .iter()
+            .map(|&(start, end)| end.wrapping_sub(start) as u64)
+            .sum::<u64>() as f64
+            / 45000.0
+    }
+
+    /// The frame rate and scan type of the first video stream.
+    pub fn video_metadata(&self) -> Option<VideoMetadata> {
+        self.streams.iter().find_map(|s| s.video)
+    }
+
+    /// The number of frames of the first video stream, according to the
+    /// presentation duration of the clip.
+    pub fn video_frames(&self) -> Option<i32> {
+        Some(self.video_metadata()?.frames(self.duration()) as i32)
+    }
+
+    /// The byte offset of the last entry point that is at least `seconds`
+    /// before the end of the clip, or the start of the clip if there is none.
+    /// Returns `None` if the clip has more than one STC sequence.
+    pub fn tail_offset(&self, seconds: f64) -> Option<u64> {
+        let end = match self.presentation_times[..] {
+            [(_, end)] => end,
+            _ => return None,
+        };
+        let target = end.saturating_sub((seconds * 45000.0) as u32);
+        Some(
+            self.entry_points
+                .iter()
+                .rev()
+                .find(|e| e.pts <= target)
+                .map_or(0, |e| e.spn as u64 * SOURCE_PACKET_SIZE),
+        )
+    }
+}
+
+// parses the entry points of the first stream of the EP map in the CPI
+fn parse_ep_map(data: &[u8], cpi: usize) -> anyhow::Result<Vec<EntryPoint>> {
+    // the CPI starts with its length, and is empty if there's no EP map
+    if cpi == 0 || be_u32(data, cpi)? == 0 || data.get(cpi + 5).map(|t| t & 0x0f) != Some(1) {
+        return Ok(Vec::new());
+    }
+    let ep_map = cpi + 6;
+    if data.get(ep_map + 1).copied().unwrap_or_default() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let header = ep_map + 2;
+    let counts = be_u32(data, header + 4)?;
+    let num_coarse = (be_u16(data, header + 2)? as usize & 0x03) << 14 | (counts >> 18) as usize;
+    let num_fine = (counts & 0x3ffff) as usize;
+    let start = ep_map + be_u32(data, header + 8)? as usize;
+    let fine_start = start + be_u32(data, start)? as usize;
+
+    // every coarse entry refers to the first of its fine entries
+    let coarse = (0..num_coarse)
+        .map(|i| {
+            let entry = start + 4 + i * 8;
+            let word = be_u32(data, entry)?;
+            Ok((
+                (word >> 14) as usize,
+                word & 0x3fff,
+                be_u32(data, entry + 4)?,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut entry_points = Vec::with_capacity(num_fine);
+    for (i, &(first_fine, coarse_pts, coarse_spn)) in coarse.iter().enumerate() {
+        let last_fine = coarse.get(i + 1).map_or(num_fine, |c| c.0);
+        for j in first_fine..last_fine.min(num_fine) {
+            let fine = be_u32(data, fine_start + j * 4)?;
+            entry_points.push(EntryPoint {
+                pts: ((coarse_pts & !0x01) << 18) + (((fine >> 17) & 0x7ff) << 8),
+                spn: (coarse_spn & !0x1ffff) + (fine & 0x1ffff),
+            });
+        }
+    }
+    Ok(entry_points)
+}
+
+// parses the stream coding info, which starts with the coding type
+fn parse_stream(pid: u16, attributes: &[u8]) -> ClipStream {
+    let mut stream = ClipStream {
+        pid,
+        coding_type: attributes[0],
+        language: None,
+        video: None,
+    };
+    let language = |offset: usize| {
+        attributes
+            .get(offset..offset + 3)
+            .map(|l| String::from_utf8_lossy(l).into_owned())
+    };
+    if stream.is_video() {
+        stream.video = attributes.get(1).and_then(|&b| {
+            // 480i, 576i and 1080i
+            let scan = match b >> 4 {
+                1 | 2 | 4 => ScanType::Interlaced,
+                _ => ScanType::Progressive,
+            };
+            Some(VideoMetadata::new(framerate(b & 0x0f)?, scan))
+        });
+    } else if stream.is_audio() {
+        stream.language = language(2);
+    } else {
+        stream.language = match stream.coding_type {
+            // presentation and interactive graphics
+            0x90 | 0x91 => language(1),
+            // text subtitles, after the character code
+            0x92 => language(2),
+            _ => None,
+        };
+    }
+    stream
+}
+
+fn framerate(code: u8) -> Option<Framerate> {
+    let (numerator, denominator) = match code {
+        1 => (24000, 1001),
+        2 => (24, 1),
+        3 => (25, 1),
+        4 => (30000, 1001),
+        6 => (50, 1),
+        7 => (60000, 1001),
+        _ => return None,
+    };
+    Some(Framerate {
+        numerator,
+        denominator,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_and_duration_are_parsed() {
+        let mut data = b"HDMV0200".to_vec();
+        data.extend(&40u32.to_be_bytes());
+        data.extend(&80u32.to_be_bytes());
+        data.extend(&120u32.to_be_bytes());
+        data.resize(40, 0);
+        // sequence info: one ATC sequence with one STC sequence of 60 seconds
+        data.extend(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0]);
+        data.extend(&[0x10, 0x01, 0, 0, 0, 0]);
+        data.extend(&(8u32 << 18).to_be_bytes());
+        data.extend(&((8u32 << 18) + 60 * 45000).to_be_bytes());
+        // program info: a 23.976 fps HEVC stream and a TrueHD stream
+        data.resize(80, 0);
+        data.extend(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0x01, 0x00, 2, 0]);
+        data.extend(&[0x10, 0x11, 5, 0x24, 0x61, 0x30, 0, 0]);
+        data.extend(&[0x11, 0x00, 5, 0x83, 0x63]);
+        data.extend(b"eng");
+        // CPI: an EP map of the video stream with two entry points, at the
+        // start and at 11.38 seconds
+        data.resize(120, 0);
+        data.extend(&[0, 0, 0, 1, 0, 1, 0, 1]);
+        data.extend(&[0x10, 0x11, 0x00, 0x04, 0x00, 0x04, 0x00, 0x02, 0, 0, 0, 14]);
+        data.extend(&[0, 0, 0, 12, 0, 0, 0, 8, 0, 0, 0, 0]);
+        data.extend(&[0, 0, 0, 0]);
+        data.extend(&((2000u32 << 17) | 1000).to_be_bytes());
+
+        let clip = ClipInfo::parse(&data).unwrap();
+        assert_eq!(clip.audio_languages(), [(0x1100, String::from("eng"))]);
+        assert_eq!(clip.duration(), 60.0);
+        assert_eq!(clip.video_frames(), Some(1439));
+        assert_eq!(clip.tail_offset(2.0), Some(1000 * 192));
+        assert_eq!(clip.tail_offset(60.0), Some(0));
+
+        // 1080i at 29.97 frames per second
+        let stream = parse_stream(0x1011, &[0x1b, 0x44, 0, 0]);
+        assert_eq!(stream.video.map(|v| v.scan), Some(ScanType::Interlaced));
+        let clip = ClipInfo {
+            streams: vec![stream],
+            ..clip
+        };
+        assert_eq!(clip.video_frames(), Some(1798));
+    }
+}
@@ -0,0 +1,64 @@
+//! Parses `index.bdmv`, which lists the titles of a disc and the object each
+//! of them starts. All multi-byte fields are big-endian.
+
+use super::{be_u16, be_u32};
+
+// the object types of a title
+const OBJECT_TYPE_HDMV: u8 = 1;
+const OBJECT_TYPE_BDJ: u8 = 2;
+
+/// The object a title starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TitleObject {
+    /// An HDMV movie object, by its index in `MovieObject.bdmv`.
+    Hdmv(u16),
+    /// A BD-J application, whose playlists can't be determined statically.
+    BdJ,
+}
+
+/// Parses `index.bdmv`, and returns the objects of its titles. Title 1 is the
+/// first one.
+pub fn parse(data: &[u8]) -> anyhow::Result<Vec<TitleObject>> {
+    if data.get(..4) != Some(&b"INDX"[..]) {
+        anyhow::bail!("not an index.bdmv file");
+    }
+    let indexes = be_u32(data, 8)? as usize;
+
+    // skip the length, and the first playback and top menu objects, which
+    // aren't titles
+    let titles = indexes + 4 + 12 + 12;
+    let num_titles = be_u16(data, titles)?;
+    (0..num_titles as usize)
+        .map(|i| {
+            let title = titles + 2 + i * 12;
+            let object_type = data.get(title).copied().unwrap_or_default() >> 6;
+            match object_type {
+                OBJECT_TYPE_HDMV => Ok(TitleObject::Hdmv(be_u16(data, title + 6)?)),
+                OBJECT_TYPE_BDJ => Ok(TitleObject::BdJ),
+                t => anyhow::bail!("title {} has the unknown object type {}", i + 1, t),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn titles_are_parsed() {
+        let mut data = b"INDX0200".to_vec();
+        data.extend(&40u32.to_be_bytes());
+        data.resize(40, 0);
+        // the length, and the first playback and top menu objects
+        data.resize(40 + 4 + 24, 0);
+        data.extend(&2u16.to_be_bytes());
+        data.extend(&[0x40, 0, 0, 0, 0x40, 0, 0, 3, 0, 0, 0, 0]);
+        data.extend(&[0x80, 0, 0, 0, 0x40, 0, b'0', b'0', b'0', b'0', b'1', 0]);
+
+        assert_eq!(
+            parse(&data).unwrap(),
+            [TitleObject::Hdmv(3), TitleObject::BdJ]
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Finds the main feature among the playlists of a blu-ray disc. Discs often
+//! carry dozens of playlists: extras, menus, duplicates of the movie with
+//! other credits, and decoys that loop over the same clips. The titles of
+//! the disc are mapped to their playlists where possible.
+
+use crate::udf::UdfImage;
+use anyhow::Context;
+use index::TitleObject;
+use log::*;
+use mpls::{Mpls, TimeStamp};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub mod clip_info;
+mod index;
+mod movie_object;
+
+/// Playlists shorter than this are not considered by default, in seconds.
+pub const DEFAULT_MIN_DURATION: f64 = 600.0;
+
+// the stream coding type of Dolby TrueHD audio in playlists and clip info
+const TRUEHD_CODING_TYPE: u8 = 0x83;
+
+/// A blu-ray disc, either as a directory or as an ISO image.
+pub struct Disc {
+    /// The BDMV directory. Inside an image, this is `/BDMV`.
+    bdmv: PathBuf,
+    image: Option<Arc<UdfImage>>,
+}
+
+/// The clip of a play item, and the part of it that is played.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClipRange {
+    pub clip: String,
+    /// In 45 kHz ticks.
+    pub in_time: u64,
+    pub out_time: u64,
+}
+
+/// What ranking needs to know about a playlist.
+#[derive(Debug, Clone)]
+pub struct PlaylistSummary {
+    /// The file name, e.g. `00800.mpls`.
+    pub name: String,
+    pub clips: Vec<ClipRange>,
+    pub angles: usize,
+    /// Whether the playlist has a TrueHD audio stream.
+    pub truehd: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Rejection {
+    /// Plays exactly the same clips as another playlist.
+    Duplicate {
+        of: String,
+    },
+    TooShort,
+    /// Plays the same part of a clip more than once.
+    Looping,
+}
+
+/// A playlist, ranked as a main feature candidate.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaylistCandidate {
+    pub name: String,
+    /// In seconds.
+    pub duration: f64,
+    pub segments: usize,
+    pub angles: usize,
+    pub truehd: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<Rejection>,
+    /// The numbers of the titles that play this playlist.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub titles: Vec<usize>,
+}
+
+/// A title of the disc, as listed in `index.bdmv`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Title {
+    /// The title number, starting at 1.
+    pub number: usize,
+    /// Whether the title is a BD-J application, whose playlists are unknown.
+    pub bdj: bool,
+    /// The file names of the playlists the title plays, as far as they can
+    /// be determined statically.
+    pub playlists: Vec<String>,
+}
+
+impl Disc {
+    /// Opens a disc from its root directory, its BDMV directory, or an ISO
+    /// image.
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Disc> {
+        let path = path.as_ref();
+        if path.is_file() {
+            let image = UdfImage::open(path)
+                .with_context(|| format!("Failed to open disc image at {}", path.display()))?;
+            return Ok(Disc {
+                bdmv: PathBuf::from("/BDMV"),
+                image: Some(Arc::new(image)),
+            });
+        }
+
+        let bdmv = if path.join("BDMV").is_dir() {
+            path.join("BDMV")
+        } else {
+            path.to_path_buf()
+        };
+        if !bdmv.join("PLAYLIST").is_dir() {
+            anyhow::bail!("{} is not a blu-ray BDMV directory.", path.display());
+        }
+        Ok(Disc { bdmv, image: None })
+    }
+
+    pub fn image(&self) -> Option<&Arc<UdfImage>> {
+        self.image.as_ref()
+    }
+
+    /// The path of the playlist with the given file name.
+    pub fn playlist_path(&self, name: &str) -> PathBuf {
+        self.bdmv.join("PLAYLIST").join(name)
+    }
+
+    /// The file names of all playlists, in order.
+    pub fn playlist_names(&self) -> anyhow::Result<Vec<String>> {
+        let dir = self.bdmv.join("PLAYLIST");
+        let mut names = match self.image {
+            Some(ref image) => image.read_dir(&dir.to_string_lossy())?,
+            None => std::fs::read_dir(&dir)?
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect(),
+        };
+        names.retain(|n| n.to_ascii_lowercase().ends_with(".mpls"));
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn read_playlist(&self, name: &str) -> anyhow::Result<Mpls> {
+        let path = self.playlist_path(name);
+        let mpls = match self.image {
+            Some(ref image) => Mpls::from(image.open_file(&path.to_string_lossy())?),
+            None => Mpls::from(File::open(&path)?),
+        };
+        mpls.with_context(|| format!("Failed to parse playlist {}", name))
+    }
+
+    // reads a file of the BDMV directory, e.g. `index.bdmv`
+    fn read_file(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self.bdmv.join(name);
+        let mut data = Vec::new();
+        match self.image {
+            Some(ref image) => image
+                .open_file(&path.to_string_lossy())?
+                .read_to_end(&mut data)?,
+            None => File::open(&path)?.read_to_end(&mut data)?,
+        };
+        Ok(data)
+    }
+
+    /// Reads the titles from `index.bdmv`, and resolves the playlists of the
+    /// HDMV titles from `MovieObject.bdmv`.
+    pub fn titles(&self) -> anyhow::Result<Vec<Title>> {
+        let titles = self
+            .read_file("index.bdmv")
+            .and_then(|data| index::parse(&data))
+            .context("Failed to read index.bdmv")?;
+        let objects = self
+            .read_file("MovieObject.bdmv")
+            .and_then(|data| movie_object::parse(&data))
+            .context("Failed to read MovieObject.bdmv")?;
+
+        Ok(titles
+            .iter()
+            .enumerate()
+            .map(|(i, object)| Title {
+                number: i + 1,
+                bdj: *object == TitleObject::BdJ,
+                playlists: match *object {
+                    TitleObject::Hdmv(o) => movie_object::playlists(&objects, o)
+                        .into_iter()
+                        .map(|p| format!("{:05}.mpls", p))
+                        .collect(),
+                    TitleObject::BdJ => Vec::new(),
+                },
+            })
+            .collect())
+    }
+
+    /// Reads every playlist and ranks them, best main feature candidate first,
+    /// along with the titles of the disc. Playlists that can't be read are
+    /// skipped, and so are the titles if they can't be read.
+    pub fn candidates(
+        &self,
+        min_duration: f64,
+    ) -> anyhow::Result<(Vec<PlaylistCandidate>, Vec<Title>)> {
+        let mut summaries = Vec::new();
+        for name in self.playlist_names()? {
+            match self.read_playlist(&name) {
+                Ok(mpls) => summaries.push(PlaylistSummary::new(name, &mpls)),
+                Err(e) => warn!("Skipping playlist {}: {:#}", name, e),
+            }
+        }
+        let mut candidates = rank(summaries, min_duration);
+
+        let titles = self.titles().unwrap_or_else(|e| {
+            warn!("The titles of the disc are unknown: {:#}", e);
+            Vec::new()
+        });
+        for c in &mut candidates {
+            c.titles = titles
+                .iter()
+                .filter(|t| t.playlists.iter().any(|p| p.eq_ignore_ascii_case(&c.name)))
+                .map(|t| t.number)
+                .collect();
+        }
+        Ok((candidates, titles))
+    }
+}
+
+impl PlaylistSummary {
+    pub fn new(name: String, mpls: &Mpls) -> PlaylistSummary {
+        let play_items = &mpls.play_list.play_items;
+        PlaylistSummary {
+            name,
+            clips: play_items
+                .iter()
+                .map(|p| ClipRange {
+                    clip: p.clip.file_name.clone(),
+                    in_time: ticks(&p.in_time),
+                    out_time: ticks(&p.out_time),
+                })
+                .collect(),
+            angles: mpls.angles().len(),
+            truehd: play_items.first().is_some_and(|p| {
+                p.stream_number_table
+                    .primary_audio_streams
+                    .iter()
+                    .any(|s| s.attrs.coding_type == TRUEHD_CODING_TYPE)
+            }),
+        }
+    }
+
+    /// In seconds.
+    pub fn duration(&self) -> f64 {
+        self.clips
+            .iter()
+            .map(|c| c.out_time.saturating_sub(c.in_time))
+            .sum::<u64>() as f64
+            / 45000.0
+    }
+}
+
+// MPLS time stamps count a 45 kHz clock
+fn ticks(time: &TimeStamp) -> u64 {
+    (time.seconds() * 45000.0).round() as u64
+}
+
+/// Rejects duplicate, short and looping playlists, and sorts the rest by how
+/// likely they are the main feature: playlists with TrueHD audio first, then
+/// the longest, then the one with fewer segments. Rejected playlists follow in
+/// the same order.
+pub fn rank(mut playlists: Vec<PlaylistSummary>, min_duration: f64) -> Vec<PlaylistCandidate> {
+    playlists.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut candidates = Vec::with_capacity(playlists.len());
+    for (i, playlist) in playlists.iter().enumerate() {
+        let duration = playlist.duration();
+        let mut seen = HashSet::new();
+        let rejected =
+            if let Some(original) = playlists[..i].iter().find(|p| p.clips == playlist.clips) {
+                Some(Rejection::Duplicate {
+                    of: original.name.clone(),
+                })
+            } else if duration < min_duration {
+                Some(Rejection::TooShort)
+            } else if !playlist.clips.iter().all(|c| seen.insert(c)) {
+                Some(Rejection::Looping)
+            } else {
+                None
+            };
+        candidates.push(PlaylistCandidate {
+            name: playlist.name.clone(),
+            duration,
+            segments: playlist.clips.len(),
+            angles: playlist.angles,
+            truehd: playlist.truehd,
+            rejected,
+            titles: Vec::new(),
+        });
+    }
+
+    // durations within a second of each other count as the same
+    candidates.sort_by(|a, b| {
+        a.rejected
+            .is_some()
+            .cmp(&b.rejected.is_some())
+            .then(b.truehd.cmp(&a.truehd))
+            .then((b.duration.round() as u64).cmp(&(a.duration.round() as u64)))
+            .then(a.segments.cmp(&b.segments))
+            .then(a.name.cmp(&b.name))
+    });
+    candidates
+}
+
+/// The best main feature candidate, if any playlist wasn't rejected.
+pub fn main_feature(candidates: &[PlaylistCandidate]) -> Option<&PlaylistCandidate> {
+    candidates.first().filter(|c| c.rejected.is_none())
+}
+
+/// The best ranked playlist of the given title, even if it was rejected as a
+/// main feature.
+pub fn title_playlist(
+    candidates: &[PlaylistCandidate],
+    title: usize,
+) -> Option<&PlaylistCandidate> {
+    candidates.iter().find(|c| c.titles.contains(&title))
+}
+
+fn be_u16(b: &[u8], offset: usize) -> anyhow::Result<u16> {
+    match b.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => anyhow::bail!("file is truncated at byte {}", offset),
+    }
+}
+
+fn be_u32(b: &[u8], offset: usize) -> anyhow::Result<u32> {
+    match b.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        None => anyhow::bail!("file is truncated at byte {}", offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(name: &str, clips: &[(&str, u64)], truehd: bool) -> PlaylistSummary {
+        PlaylistSummary {
+            name: name.to_string(),
+            clips: clips
+                .iter()
+                .map(|&(clip, minutes)| ClipRange {
+                    clip: clip.to_string(),
+                    in_time: 0,
+                    out_time: minutes * 60 * 45000,
+                })
+                .collect(),
+            angles: 1,
+            truehd,
+        }
+    }
+
+    #[test]
+    fn main_feature_is_ranked_first() {
+        let candidates = rank(
+            vec![
+                playlist("00001.mpls", &[("00010", 1)], true),
+                playlist("00800.mpls", &[("00055", 60), ("00056", 60)], true),
+                playlist("00801.mpls", &[("00055", 60), ("00056", 60)], true),
+                playlist("00802.mpls", &[("00055", 60), ("00055", 60)], true),
+                playlist("00803.mpls", &[("00057", 130)], false),
+                playlist("00804.mpls", &[("00058", 30)], true),
+            ],
+            DEFAULT_MIN_DURATION,
+        );
+        let names: Vec<_> = candidates.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "00800.mpls",
+                "00804.mpls",
+                "00803.mpls",
+                "00801.mpls",
+                "00802.mpls",
+                "00001.mpls"
+            ]
+        );
+        assert_eq!(main_feature(&candidates).unwrap().name, "00800.mpls");
+        assert_eq!(
+            candidates[3].rejected,
+            Some(Rejection::Duplicate {
+                of: "00800.mpls".to_string()
+            })
+        );
+        assert_eq!(candidates[4].rejected, Some(Rejection::Looping));
+        assert_eq!(candidates[5].rejected, Some(Rejection::TooShort));
+    }
+}
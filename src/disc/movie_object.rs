@@ -0,0 +1,135 @@
+//! Parses `MovieObject.bdmv`, which holds the navigation commands of the HDMV
+//! movie objects, and finds the playlists a movie object plays. All
+//! multi-byte fields are big-endian.
+
+use super::{be_u16, be_u32};
+use std::collections::HashSet;
+
+const COMMAND_LEN: usize = 12;
+
+// instruction groups, sub-groups and options of the navigation commands
+const GROUP_BRANCH: u8 = 0;
+const BRANCH_JUMP: u8 = 1;
+const BRANCH_PLAY: u8 = 2;
+const JUMP_OBJECT: u8 = 0;
+const CALL_OBJECT: u8 = 2;
+const PLAY_PL: u8 = 0;
+const PLAY_PL_AT_PI: u8 = 1;
+const PLAY_PL_AT_MARK: u8 = 2;
+
+/// The movie objects are followed this many jumps deep.
+const MAX_JUMPS: usize = 8;
+
+/// A navigation command of a movie object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Plays the playlist with the given number, e.g. 800 for `00800.mpls`.
+    PlayPlaylist(u16),
+    /// Jumps to or calls the movie object with the given index.
+    JumpObject(u16),
+    /// Anything else, including commands whose operand is a register, and
+    /// thus only known at runtime.
+    Other,
+}
+
+/// Parses `MovieObject.bdmv`, and returns the commands of every movie object.
+pub fn parse(data: &[u8]) -> anyhow::Result<Vec<Vec<Command>>> {
+    if data.get(..4) != Some(&b"MOBJ"[..]) {
+        anyhow::bail!("not a MovieObject.bdmv file");
+    }
+    let num_objects = be_u16(data, 48)?;
+
+    let mut offset = 50;
+    let mut objects = Vec::with_capacity(num_objects as usize);
+    for _ in 0..num_objects {
+        let num_commands = be_u16(data, offset + 2)? as usize;
+        offset += 4;
+        let commands = (0..num_commands)
+            .map(|i| parse_command(data, offset + i * COMMAND_LEN))
+            .collect::<anyhow::Result<_>>()?;
+        offset += num_commands * COMMAND_LEN;
+        objects.push(commands);
+    }
+    Ok(objects)
+}
+
+fn parse_command(data: &[u8], offset: usize) -> anyhow::Result<Command> {
+    let destination = be_u32(data, offset + 4)?;
+    let (group, sub_group) = ((data[offset] >> 3) & 0x03, data[offset] & 0x07);
+    let immediate = data[offset + 1] & 0x80 != 0;
+    let option = data[offset + 1] & 0x0f;
+    if group != GROUP_BRANCH || !immediate {
+        return Ok(Command::Other);
+    }
+
+    Ok(match (sub_group, option) {
+        (BRANCH_PLAY, PLAY_PL) | (BRANCH_PLAY, PLAY_PL_AT_PI) | (BRANCH_PLAY, PLAY_PL_AT_MARK) => {
+            Command::PlayPlaylist(destination as u16)
+        }
+        (BRANCH_JUMP, JUMP_OBJECT) | (BRANCH_JUMP, CALL_OBJECT) => {
+            Command::JumpObject(destination as u16)
+        }
+        _ => Command::Other,
+    })
+}
+
+/// Returns the numbers of the playlists the given movie object plays, in the
+/// order of its commands, following jumps to other movie objects. Only
+/// playlists that are given as immediate values are found.
+pub fn playlists(objects: &[Vec<Command>], start: u16) -> Vec<u16> {
+    let mut playlists = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(start, 0)];
+    while let Some((object, depth)) = pending.pop() {
+        if depth > MAX_JUMPS || !visited.insert(object) {
+            continue;
+        }
+        let commands = match objects.get(object as usize) {
+            Some(c) => c,
+            None => continue,
+        };
+        // jumps are followed after the object's own playlists
+        let mut jumps = Vec::new();
+        for command in commands {
+            match *command {
+                Command::PlayPlaylist(p) if !playlists.contains(&p) => playlists.push(p),
+                Command::JumpObject(o) => jumps.push((o, depth + 1)),
+                _ => {}
+            }
+        }
+        pending.extend(jumps.into_iter().rev());
+    }
+    playlists
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(insn: [u8; 4], destination: u32) -> Vec<u8> {
+        let mut c = insn.to_vec();
+        c.extend(&destination.to_be_bytes());
+        c.extend(&[0; 4]);
+        c
+    }
+
+    #[test]
+    fn playlists_are_found_through_jumps() {
+        let mut data = b"MOBJ0200".to_vec();
+        data.resize(48, 0);
+        data.extend(&2u16.to_be_bytes());
+        // object 0: set a register, then jump to object 1
+        data.extend(&[0x80, 0, 0, 2]);
+        data.extend(command([0x50, 0x00, 0x00, 0x01], 0x8000_0001));
+        data.extend(command([0x21, 0x80, 0x00, 0x00], 1));
+        // object 1: play a playlist given in a register, then 00800.mpls
+        data.extend(&[0x80, 0, 0, 2]);
+        data.extend(command([0x22, 0x00, 0x00, 0x00], 0x8000_0001));
+        data.extend(command([0x22, 0x80, 0x00, 0x00], 800));
+
+        let objects = parse(&data).unwrap();
+        assert_eq!(objects[0][1], Command::JumpObject(1));
+        assert_eq!(playlists(&objects, 0), [800]);
+        assert!(playlists(&objects, 2).is_empty());
+    }
+}
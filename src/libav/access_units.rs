@@ -0,0 +1,460 @@
+//! Counts the video access units of a transport stream natively, from the
+//! start codes in the PES payloads of its video streams, instead of relying on
+//! the packets FFmpeg's parsers produce. The counter is fed the bytes of the
+//! file as FFmpeg reads them, so segments are still read only once.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+const TS_PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PID_PAT: u16 = 0;
+
+// the stream types of the PMT
+const STREAM_TYPE_MPEG1_VIDEO: u8 = 0x01;
+const STREAM_TYPE_MPEG2_VIDEO: u8 = 0x02;
+const STREAM_TYPE_AVC: u8 = 0x1b;
+const STREAM_TYPE_MVC: u8 = 0x20;
+const STREAM_TYPE_HEVC: u8 = 0x24;
+const STREAM_TYPE_VC1: u8 = 0xea;
+
+/// The number of bytes after a start code that are needed to classify it:
+/// the NAL unit header and the first byte of the slice header, which comes
+/// after a three byte extension for MVC.
+const START_CODE_DATA: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VideoCoding {
+    Mpeg2,
+    Avc,
+    /// The dependent view of stereoscopic AVC, which Blu-ray carries in a
+    /// PID of its own.
+    Mvc,
+    Hevc,
+    Vc1,
+}
+
+impl VideoCoding {
+    fn from_stream_type(stream_type: u8) -> Option<VideoCoding> {
+        match stream_type {
+            STREAM_TYPE_MPEG1_VIDEO | STREAM_TYPE_MPEG2_VIDEO => Some(VideoCoding::Mpeg2),
+            STREAM_TYPE_AVC => Some(VideoCoding::Avc),
+            STREAM_TYPE_MVC => Some(VideoCoding::Mvc),
+            STREAM_TYPE_HEVC => Some(VideoCoding::Hevc),
+            STREAM_TYPE_VC1 => Some(VideoCoding::Vc1),
+            _ => None,
+        }
+    }
+}
+
+/// The number of access units of the primary video stream of a segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessUnitCount {
+    pub frames: u32,
+    /// The frames of the MVC dependent view, which should match `frames`.
+    pub dependent_frames: Option<u32>,
+}
+
+/// Access unit counts of one elementary stream.
+#[derive(Debug)]
+struct VideoStream {
+    pid: u16,
+    coding: VideoCoding,
+    // the bytes at the end of the previous payload that may hold the start of
+    // a start code
+    carry: Vec<u8>,
+    delimiters: u32,
+    first_slices: u32,
+    // MPEG-2 pictures, and how many fields the ones with a picture coding
+    // extension have
+    pictures: u32,
+    fields: u32,
+}
+
+impl VideoStream {
+    fn new(pid: u16, coding: VideoCoding) -> VideoStream {
+        VideoStream {
+            pid,
+            coding,
+            carry: Vec::with_capacity(TS_PACKET_SIZE + START_CODE_DATA + 2),
+            delimiters: 0,
+            first_slices: 0,
+            pictures: 0,
+            fields: 0,
+        }
+    }
+
+    fn push(&mut self, payload: &[u8]) {
+        let mut data = std::mem::take(&mut self.carry);
+        data.extend_from_slice(payload);
+
+        // start codes whose data isn't complete yet are looked at again with
+        // the next payload
+        let complete = data.len().saturating_sub(START_CODE_DATA + 2);
+        let mut i = 0;
+        while i < complete {
+            if data[i + 2] > 1 {
+                i += 3;
+            } else if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+                self.start_code(&data[i + 3..i + 3 + START_CODE_DATA]);
+                i += 3;
+            } else {
+                i += 1;
+            }
+        }
+
+        data.drain(..i.min(data.len()));
+        self.carry = data;
+    }
+
+    fn start_code(&mut self, b: &[u8]) {
+        match self.coding {
+            VideoCoding::Avc => match b[0] & 0x1f {
+                9 => self.delimiters += 1,
+                // slices whose first_mb_in_slice is 0
+                1 | 5 if b[1] & 0x80 != 0 => self.first_slices += 1,
+                _ => {}
+            },
+            VideoCoding::Mvc => match b[0] & 0x1f {
+                // the dependent view delimiter
+                24 => self.delimiters += 1,
+                20 if b[4] & 0x80 != 0 => self.first_slices += 1,
+                _ => {}
+            },
+            VideoCoding::Hevc => {
+                let nal_type = (b[0] >> 1) & 0x3f;
+                let layer = ((b[0] & 0x01) << 5) | (b[1] >> 3);
+                match nal_type {
+                    _ if layer != 0 => {}
+                    35 => self.delimiters += 1,
+                    // slices with first_slice_segment_in_pic_flag set
+                    0..=9 | 16..=21 if b[2] & 0x80 != 0 => self.first_slices += 1,
+                    _ => {}
+                }
+            }
+            VideoCoding::Mpeg2 => match b[0] {
+                0x00 => self.pictures += 1,
+                // the picture coding extension, with the picture structure
+                0xb5 if b[1] >> 4 == 8 => {
+                    self.fields += if b[3] & 0x03 == 0x03 { 2 } else { 1 };
+                }
+                _ => {}
+            },
+            // only the first field of field-coded frames has a frame start
+            // code
+            VideoCoding::Vc1 => {
+                if b[0] == 0x0d {
+                    self.delimiters += 1;
+                }
+            }
+        }
+    }
+
+    fn frames(&self) -> u32 {
+        match self.coding {
+            VideoCoding::Mpeg2 if self.fields > 0 => self.fields / 2,
+            VideoCoding::Mpeg2 => self.pictures,
+            // access unit delimiters are optional, except on Blu-ray
+            _ if self.delimiters > 0 => self.delimiters,
+            _ => self.first_slices,
+        }
+    }
+}
+
+/// Counts access units in the bytes of a transport stream with either 188 or
+/// 192 byte packets. Bytes must be passed in order, from the start of the
+/// file.
+#[derive(Debug, Default)]
+pub struct AccessUnitCounter {
+    // the number of bytes that have been passed
+    position: u64,
+    // 0 if the file isn't a transport stream
+    packet_size: Option<usize>,
+    partial: Vec<u8>,
+    pmt_pids: Vec<u16>,
+    streams: Vec<VideoStream>,
+    // whether the whole file has been passed
+    complete: bool,
+}
+
+impl AccessUnitCounter {
+    /// Passes the given bytes, which start at `offset` in the file. Bytes
+    /// that have been passed before are ignored, and so are bytes after a gap.
+    pub fn push(&mut self, offset: u64, data: &[u8]) {
+        if offset > self.position || offset + data.len() as u64 <= self.position {
+            return;
+        }
+        let data = &data[(self.position - offset) as usize..];
+        self.position += data.len() as u64;
+        if self.packet_size == Some(0) {
+            return;
+        }
+
+        self.partial.extend_from_slice(data);
+        let packet_size = match self.packet_size {
+            Some(size) => size,
+            None if self.partial.len() < 3 * 192 + 4 => return,
+            None => {
+                let size = packet_size(&self.partial).unwrap_or_default();
+                self.packet_size = Some(size);
+                if size == 0 {
+                    self.partial = Vec::new();
+                    return;
+                }
+                size
+            }
+        };
+
+        let partial = std::mem::take(&mut self.partial);
+        let mut packets = partial.chunks_exact(packet_size);
+        for packet in &mut packets {
+            self.push_packet(&packet[packet_size - TS_PACKET_SIZE..]);
+        }
+        self.partial = packets.remainder().to_vec();
+    }
+
+    /// Marks the file as passed completely. Counts are only known after that.
+    pub fn finish(&mut self) {
+        self.complete = true;
+    }
+
+    /// The number of access units of the primary video stream, which is the
+    /// one with the lowest PID, or `None` if the file wasn't passed
+    /// completely or isn't a transport stream.
+    pub fn count(&self) -> Option<AccessUnitCount> {
+        if !self.complete || self.packet_size == Some(0) {
+            return None;
+        }
+        let primary = self
+            .streams
+            .iter()
+            .filter(|s| s.coding != VideoCoding::Mvc)
+            .min_by_key(|s| s.pid)?;
+        Some(AccessUnitCount {
+            frames: primary.frames(),
+            dependent_frames: self
+                .streams
+                .iter()
+                .find(|s| s.coding == VideoCoding::Mvc)
+                .map(|s| s.frames()),
+        })
+    }
+
+    fn push_packet(&mut self, packet: &[u8]) {
+        if packet[0] != SYNC_BYTE {
+            return;
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let adaptation = packet[3] & 0x30;
+        if adaptation & 0x10 == 0 {
+            return;
+        }
+        let start = if adaptation & 0x20 != 0 {
+            5 + packet[4] as usize
+        } else {
+            4
+        };
+        let payload = match packet.get(start..) {
+            Some(p) if !p.is_empty() => p,
+            _ => return,
+        };
+
+        if pid == PID_PAT || self.pmt_pids.contains(&pid) {
+            if unit_start {
+                self.parse_section(pid, payload);
+            }
+            return;
+        }
+        let stream = match self.streams.iter_mut().find(|s| s.pid == pid) {
+            Some(s) => s,
+            None => return,
+        };
+        if !unit_start {
+            stream.push(payload);
+            return;
+        }
+        // skip the PES header
+        if payload.len() < 9 || payload[..3] != [0, 0, 1] {
+            return;
+        }
+        if let Some(es) = payload.get(9 + payload[8] as usize..) {
+            stream.push(es);
+        }
+    }
+
+    // parses a PAT or PMT, which Blu-rays keep within a single packet
+    fn parse_section(&mut self, pid: u16, payload: &[u8]) {
+        let section = match payload.get(1 + payload[0] as usize..) {
+            Some(s) if s.len() >= 12 => s,
+            _ => return,
+        };
+        let length =
+            (u16::from_be_bytes([section[1] & 0x0f, section[2]]) as usize + 3).min(section.len());
+        // the CRC is at the end
+        let end = length.saturating_sub(4);
+
+        if pid == PID_PAT {
+            for program in section[8..end.max(8)].chunks_exact(4) {
+                let program_number = u16::from_be_bytes([program[0], program[1]]);
+                let pmt_pid = u16::from_be_bytes([program[2] & 0x1f, program[3]]);
+                if program_number != 0 && !self.pmt_pids.contains(&pmt_pid) {
+                    self.pmt_pids.push(pmt_pid);
+                }
+            }
+            return;
+        }
+
+        let program_info = u16::from_be_bytes([section[10] & 0x0f, section[11]]) as usize;
+        let mut offset = 12 + program_info;
+        while offset + 5 <= end {
+            let stream_type = section[offset];
+            let pid = u16::from_be_bytes([section[offset + 1] & 0x1f, section[offset + 2]]);
+            let info = u16::from_be_bytes([section[offset + 3] & 0x0f, section[offset + 4]]);
+            let coding = VideoCoding::from_stream_type(stream_type);
+            if let Some(coding) = coding.filter(|_| self.streams.iter().all(|s| s.pid != pid)) {
+                self.streams.push(VideoStream::new(pid, coding));
+            }
+            offset += 5 + info as usize;
+        }
+    }
+}
+
+// tells 188 byte packets from Blu-ray's 192 byte source packets, which start
+// with a 4 byte arrival time stamp
+fn packet_size(data: &[u8]) -> Option<usize> {
+    let synced = |size: usize, offset: usize| {
+        (0..3).all(|i| data.get(offset + i * size) == Some(&SYNC_BYTE))
+    };
+    if synced(192, 4) {
+        Some(192)
+    } else if synced(TS_PACKET_SIZE, 0) {
+        Some(TS_PACKET_SIZE)
+    } else {
+        None
+    }
+}
+
+/// Passes everything read from the inner reader to an `AccessUnitCounter`.
+pub struct CountingReader<R: Read + Seek> {
+    inner: R,
+    position: u64,
+    counter: Arc<Mutex<AccessUnitCounter>>,
+}
+
+impl<R: Read + Seek> CountingReader<R> {
+    pub fn new(inner: R, counter: Arc<Mutex<AccessUnitCounter>>) -> CountingReader<R> {
+        CountingReader {
+            inner,
+            position: 0,
+            counter,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut counter = self.counter.lock().unwrap();
+        if n == 0 && !buf.is_empty() && counter.position == self.position {
+            counter.finish();
+        } else {
+            counter.push(self.position, &buf[..n]);
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // wraps the given PES payload into 192 byte source packets
+    fn source_packets(pid: u16, es: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5, 0, 0, 0, 0, 0];
+        pes.extend_from_slice(es);
+        let mut data = Vec::new();
+        for (i, chunk) in pes.chunks(TS_PACKET_SIZE - 4).enumerate() {
+            let mut packet = vec![0; 4];
+            let unit_start = if i == 0 { 0x40 } else { 0 };
+            packet.extend(&[SYNC_BYTE, unit_start | (pid >> 8) as u8, pid as u8]);
+            if chunk.len() == TS_PACKET_SIZE - 4 {
+                packet.push(0x10);
+            } else {
+                // stuff the packet with an adaptation field
+                let stuffing = TS_PACKET_SIZE - 4 - chunk.len() - 1;
+                packet.extend(&[0x30, stuffing as u8]);
+                packet.resize(packet.len() + stuffing, 0xff);
+            }
+            packet.extend_from_slice(chunk);
+            data.extend(packet);
+        }
+        data
+    }
+
+    fn section(pid: u16, table: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0];
+        payload.extend_from_slice(table);
+        payload.resize(TS_PACKET_SIZE - 4, 0xff);
+        let mut packet = vec![
+            0,
+            0,
+            0,
+            0,
+            SYNC_BYTE,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x10,
+        ];
+        packet.extend(payload);
+        packet
+    }
+
+    #[test]
+    fn hevc_access_units_are_counted_across_packets() {
+        let mut data = section(
+            0,
+            &[0, 0xb0, 13, 0, 1, 0xc1, 0, 0, 0, 1, 0xe1, 0, 0, 0, 0, 0],
+        );
+        data.extend(section(
+            0x100,
+            &[
+                2, 0xb0, 18, 0, 1, 0xc1, 0, 0, 0xf0, 0, 0xf0, 0, 0x24, 0xf0, 0x11, 0xf0, 0, 0, 0,
+                0, 0,
+            ],
+        ));
+        // three pictures, each an AUD and a slice, with the second AUD split
+        // across two TS packets
+        let mut es = Vec::new();
+        for i in 0..3 {
+            es.extend(&[0, 0, 1, 0x46, 0x01, 0x50]);
+            es.extend(&[0, 0, 1, 0x02, 0x01, 0x80, 0, 0]);
+            es.resize(168 * (i + 1), 0xaa);
+        }
+        data.extend(source_packets(0x1011, &es));
+
+        let counter = Arc::new(Mutex::new(AccessUnitCounter::default()));
+        let mut reader = CountingReader::new(io::Cursor::new(data), counter.clone());
+        let mut buf = [0u8; 100];
+        while reader.read(&mut buf).unwrap() > 0 {}
+
+        let count = counter.lock().unwrap().count();
+        assert_eq!(
+            count,
+            Some(AccessUnitCount {
+                frames: 3,
+                dependent_frames: None,
+            })
+        );
+    }
+}
@@ -7,6 +7,8 @@ pub enum AVError {
     FFMpegErr(i32),
     DemuxErr(DemuxErr),
     OtherErr(OtherErr),
+    /// The run was stopped with a `CancellationToken`.
+    Cancelled,
 }
 
 #[derive(Debug)]
@@ -15,11 +17,17 @@ pub enum DemuxErr {
     NoTrueHdStreamFound,
     NoTrueHdFramesEncountered,
     SelectedTrueHdStreamNotFound(i32),
+    AudioStreamNotFound(i32),
+    SegmentScanFailed(usize),
 }
 
 #[derive(Debug)]
 pub enum OtherErr {
     FilePathIsNotUtf8(PathBuf),
+    InvalidCutList(String),
+    UnsupportedCutListVersion(u32),
+    InvalidCheckpoint(String),
+    UnsupportedCheckpointVersion(u32),
 }
 
 impl From<DemuxErr> for AVError {
@@ -60,15 +68,30 @@ impl Display for AVError {
                 DemuxErr::SelectedTrueHdStreamNotFound(i) => {
                     write!(f, "TrueHD stream with index {} not found.", i)
                 }
+                DemuxErr::AudioStreamNotFound(i) => {
+                    write!(f, "Audio stream with id {:#X} not found.", i)
+                }
+                DemuxErr::SegmentScanFailed(i) => {
+                    write!(f, "Failed to read segment {} in the background.", i)
+                }
             },
             AVError::OtherErr(e) => {
                 let msg = match e {
                     OtherErr::FilePathIsNotUtf8(path) => {
                         format!("File path is not valid UTF-8: {}", path.to_string_lossy())
                     }
+                    OtherErr::InvalidCutList(e) => format!("Invalid cut list: {}", e),
+                    OtherErr::UnsupportedCutListVersion(v) => {
+                        format!("Unsupported cut list version: {}", v)
+                    }
+                    OtherErr::InvalidCheckpoint(e) => format!("Invalid checkpoint: {}", e),
+                    OtherErr::UnsupportedCheckpointVersion(v) => {
+                        format!("Unsupported checkpoint version: {}", v)
+                    }
                 };
                 write!(f, "{}", msg)
             }
+            AVError::Cancelled => write!(f, "Demuxing was cancelled."),
         }
     }
 }
@@ -1,9 +1,43 @@
 use super::{AVCodecContext, AVError, AVPacket};
 use ffmpeg4_ffi::sys as ff;
-use std::path::Path;
+use log::debug;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    os::raw::{c_int, c_void},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+};
+
+/// The size of the buffer FFmpeg reads into from a custom reader.
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+// `AVERROR(EIO)`, which is the same on every platform FFmpeg supports
+const AVERROR_EIO: c_int = -5;
+// `AVERROR_EOF`, which is `-MKTAG('E', 'O', 'F', ' ')`
+const AVERROR_EOF: c_int = -0x2046_4f45;
+
+// the `whence` values of `seek`, as defined by C's stdio
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+const SEEK_END: c_int = 2;
+
+/// A reader FFmpeg can read a container from.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
 
 pub struct AVFormatContext<'a> {
     ctx: &'a mut ff::AVFormatContext,
+    // the custom AVIOContext the container is read through, if it wasn't
+    // opened from a path
+    io: Option<CustomIo>,
+}
+
+struct CustomIo {
+    avio: *mut ff::AVIOContext,
+    // passed to the callbacks as their `opaque` pointer
+    reader: *mut Box<dyn ReadSeek>,
 }
 
 #[derive(PartialEq)]
@@ -47,8 +81,16 @@ pub struct AVCodecParameters {
 
 impl AVFormatContext<'_> {
     pub fn open<P: AsRef<Path>>(input_path: P) -> Result<Self, AVError> {
-        let input_path_cstr =
-            std::ffi::CString::new(input_path.as_ref().to_str().unwrap()).unwrap();
+        // FFmpeg only takes UTF-8 paths, so anything else is read through
+        // a custom AVIOContext
+        let input_path_cstr = match input_path
+            .as_ref()
+            .to_str()
+            .and_then(|s| std::ffi::CString::new(s).ok())
+        {
+            Some(s) => s,
+            None => return AVFormatContext::from_reader(File::open(input_path)?),
+        };
 
         unsafe {
             let mut ctx_ptr: *mut _ = std::ptr::null_mut();
@@ -62,8 +104,65 @@ impl AVFormatContext<'_> {
                 // correctly initialized
                 Ok(AVFormatContext {
                     ctx: ctx_ptr.as_mut().unwrap(),
+                    io: None,
+                })
+            } else {
+                Err(AVError::FFMpegErr(open_result))
+            }
+        }
+    }
+
+    /// Opens a container that is read from the given reader, e.g. a file
+    /// inside a disc image, or a buffer in memory.
+    pub fn from_reader<R: Read + Seek + 'static>(reader: R) -> Result<Self, AVError> {
+        let reader: Box<Box<dyn ReadSeek>> = Box::new(Box::new(reader));
+        let reader = Box::into_raw(reader);
+
+        unsafe {
+            let buffer = ff::av_malloc(IO_BUFFER_SIZE) as *mut u8;
+            let avio = if buffer.is_null() {
+                std::ptr::null_mut()
+            } else {
+                ff::avio_alloc_context(
+                    buffer,
+                    IO_BUFFER_SIZE as c_int,
+                    0,
+                    reader as *mut c_void,
+                    Some(read_packet),
+                    None,
+                    Some(seek),
+                )
+            };
+            if avio.is_null() {
+                ff::av_free(buffer as *mut c_void);
+                drop(Box::from_raw(reader));
+                return Err(io::Error::other("failed to allocate an AVIOContext").into());
+            }
+            let io = CustomIo { avio, reader };
+
+            let mut ctx_ptr = ff::avformat_alloc_context();
+            if ctx_ptr.is_null() {
+                io.free();
+                return Err(io::Error::other("failed to allocate an AVFormatContext").into());
+            }
+            (*ctx_ptr).pb = avio;
+            (*ctx_ptr).flags |= ff::AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            // on failure, the format context is freed, but the custom
+            // AVIOContext isn't
+            let open_result = ff::avformat_open_input(
+                &mut ctx_ptr,
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            );
+            if open_result == 0 && !ctx_ptr.is_null() {
+                Ok(AVFormatContext {
+                    ctx: ctx_ptr.as_mut().unwrap(),
+                    io: Some(io),
                 })
             } else {
+                io.free();
                 Err(AVError::FFMpegErr(open_result))
             }
         }
@@ -103,6 +202,25 @@ impl AVFormatContext<'_> {
             err => Err(AVError::FFMpegErr(err)),
         }
     }
+
+    /// Continues reading at the given byte position of the file.
+    pub fn seek_to_byte(&mut self, position: u64) -> Result<(), AVError> {
+        let flags = ff::AVSEEK_FLAG_BYTE as c_int;
+        match unsafe { ff::av_seek_frame(self.ctx, -1, position as i64, flags) } {
+            n if n >= 0 => Ok(()),
+            err => Err(AVError::FFMpegErr(err)),
+        }
+    }
+
+    /// Continues reading at the last key frame of the given stream before the
+    /// given timestamp, in the stream's time base.
+    pub fn seek_to_timestamp(&mut self, stream: &AVStream, timestamp: i64) -> Result<(), AVError> {
+        let flags = ff::AVSEEK_FLAG_BACKWARD as c_int;
+        match unsafe { ff::av_seek_frame(self.ctx, stream.stream.index, timestamp, flags) } {
+            n if n >= 0 => Ok(()),
+            err => Err(AVError::FFMpegErr(err)),
+        }
+    }
 }
 
 impl Drop for AVFormatContext<'_> {
@@ -111,5 +229,90 @@ impl Drop for AVFormatContext<'_> {
             let mut ctx: *mut ff::AVFormatContext = &mut *self.ctx;
             ff::avformat_close_input(&mut ctx);
         }
+        // the format context doesn't free a custom AVIOContext
+        if let Some(io) = self.io.take() {
+            unsafe { io.free() };
+        }
+    }
+}
+
+impl CustomIo {
+    unsafe fn free(self) {
+        // FFmpeg may have replaced the buffer we allocated
+        let mut avio = self.avio;
+        ff::av_freep(&mut (*avio).buffer as *mut *mut u8 as *mut c_void);
+        ff::avio_context_free(&mut avio);
+        drop(Box::from_raw(self.reader));
+    }
+}
+
+// reads up to `buf_size` bytes for FFmpeg from the reader behind `opaque`
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let reader = &mut *(opaque as *mut Box<dyn ReadSeek>);
+    let buf = std::slice::from_raw_parts_mut(buf, buf_size.max(0) as usize);
+    // a panic must not unwind into FFmpeg
+    let result = catch_unwind(AssertUnwindSafe(|| loop {
+        match reader.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }));
+    match result {
+        Ok(Ok(0)) => AVERROR_EOF,
+        Ok(Ok(n)) => n as c_int,
+        Ok(Err(e)) => {
+            debug!("Custom AVIOContext failed to read: {}", e);
+            AVERROR_EIO
+        }
+        Err(_) => AVERROR_EIO,
+    }
+}
+
+// seeks the reader behind `opaque`, or returns its size for `AVSEEK_SIZE`
+unsafe extern "C" fn seek(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = &mut *(opaque as *mut Box<dyn ReadSeek>);
+    let whence = whence & !(ff::AVSEEK_FORCE as c_int);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        if whence == ff::AVSEEK_SIZE as c_int {
+            let position = reader.stream_position()?;
+            let size = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(position))?;
+            return Ok(size);
+        }
+        let pos = match whence {
+            SEEK_SET => SeekFrom::Start(offset.max(0) as u64),
+            SEEK_CUR => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        reader.seek(pos)
+    }));
+    match result {
+        Ok(Ok(position)) => position as i64,
+        Ok(Err(e)) => {
+            debug!("Custom AVIOContext failed to seek: {}", e);
+            AVERROR_EIO as i64
+        }
+        Err(_) => AVERROR_EIO as i64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn callbacks_read_and_seek_the_reader() {
+        let mut reader: Box<dyn ReadSeek> = Box::new(Cursor::new(vec![1u8, 2, 3, 4, 5]));
+        let opaque = &mut reader as *mut Box<dyn ReadSeek> as *mut c_void;
+        let mut buf = [0u8; 4];
+        unsafe {
+            assert_eq!(seek(opaque, 2, SEEK_SET), 2);
+            assert_eq!(seek(opaque, 0, ff::AVSEEK_SIZE as c_int), 5);
+            assert_eq!(read_packet(opaque, buf.as_mut_ptr(), 4), 3);
+            assert_eq!(&buf[..3], &[3, 4, 5]);
+            assert_eq!(read_packet(opaque, buf.as_mut_ptr(), 4), AVERROR_EOF);
+        }
     }
 }
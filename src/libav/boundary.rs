@@ -0,0 +1,719 @@
+use super::{cut_map::CutReason, dsp, truehd::ThdMetadata, ThdDecodePacket, ThdOverrun};
+use log::{debug, info};
+use std::{
+    fmt::{Debug, Display},
+    sync::Arc,
+};
+
+/// The maximum number of frames on either side of a segment boundary that are
+/// searched for duplicate audio.
+pub const MAX_OVERLAP_FRAMES: usize = 8;
+
+/// How much better than the aligned lag another lag must correlate to be
+/// preferred over it.
+const LAG_TOLERANCE: f32 = 0.001;
+
+/// The thresholds a boundary decision is based on.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundaryThresholds {
+    /// Frames whose samples all stay below this amplitude are considered
+    /// silent. Samples are always on a 24-bit scale.
+    pub silence: i32,
+    /// Frames whose covariance exceeds this value contain the same audio.
+    pub covariance: f32,
+    /// The overrun, in samples, at or above which a frame is removed to keep
+    /// the audio in sync with the video.
+    pub overrun: i32,
+}
+
+impl Default for BoundaryThresholds {
+    /// The thresholds for 24-bit, 48 kHz audio.
+    fn default() -> Self {
+        BoundaryThresholds {
+            silence: 100,
+            covariance: 0.95,
+            overrun: 20,
+        }
+    }
+}
+
+impl BoundaryThresholds {
+    /// Derives the thresholds for a stream. The silence threshold is kept at
+    /// four steps of the source's least significant bit or more, so that the
+    /// dither of a 16-bit source isn't mistaken for audio. A frame is removed
+    /// once the overrun reaches half a frame.
+    pub fn for_stream(metadata: &ThdMetadata) -> BoundaryThresholds {
+        let default = BoundaryThresholds::default();
+        let bit_depth = metadata.bit_depth.clamp(1, 24);
+        BoundaryThresholds {
+            silence: default.silence.max(4 << (24 - bit_depth)),
+            covariance: default.covariance,
+            overrun: (metadata.frame_size as i32 / 2).max(1),
+        }
+    }
+}
+
+impl Display for BoundaryThresholds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "silence < {}, covariance > {}, overrun >= {} samples",
+            self.silence, self.covariance, self.overrun
+        )
+    }
+}
+
+/// Thresholds that are set explicitly, instead of being derived from the
+/// stream.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ThresholdOptions {
+    pub silence: Option<i32>,
+    pub covariance: Option<f32>,
+    pub overrun: Option<i32>,
+}
+
+impl ThresholdOptions {
+    pub fn resolve(&self, metadata: &ThdMetadata) -> BoundaryThresholds {
+        let derived = BoundaryThresholds::for_stream(metadata);
+        BoundaryThresholds {
+            silence: self.silence.unwrap_or(derived.silence),
+            covariance: self.covariance.unwrap_or(derived.covariance),
+            overrun: self.overrun.unwrap_or(derived.overrun),
+        }
+    }
+}
+
+/// The outcome of checking a single segment boundary.
+#[derive(Debug, Clone)]
+pub struct BoundaryDecision {
+    /// The number of frames to cut off the end of the previous segment.
+    pub frames: u32,
+    /// Covariance of the frames at the boundary, if the strategy computed one.
+    pub covariance: Option<f32>,
+    /// Whether both frames at the boundary were considered silent.
+    pub silence: bool,
+    /// The number of samples at the start of the next segment that repeat
+    /// the end of the previous one, if the strategy detected an overlap. This
+    /// needn't be a multiple of the frame size.
+    pub overlap: Option<i32>,
+    pub reason: CutReason,
+}
+
+impl BoundaryDecision {
+    fn keep(reason: CutReason) -> BoundaryDecision {
+        BoundaryDecision {
+            frames: 0,
+            covariance: None,
+            silence: false,
+            overlap: None,
+            reason,
+        }
+    }
+}
+
+/// Decides what to do at a segment boundary.
+///
+/// `tail` are the last decoded TrueHD frames of the previous segment (at most
+/// one group of frames, starting at a major sync), `head` the first decoded
+/// frames of the current segment, and `overrun` how far the audio is ahead of
+/// the video before any frame is removed at this boundary. Strategies that
+/// don't need some of the `thresholds` are free to ignore them.
+pub trait BoundaryStrategy: Debug + Send + Sync {
+    /// The name the strategy is selected by.
+    fn name(&self) -> &'static str;
+
+    fn decide(
+        &self,
+        tail: &[ThdDecodePacket],
+        head: &[ThdDecodePacket],
+        overrun: &ThdOverrun,
+        thresholds: &BoundaryThresholds,
+    ) -> BoundaryDecision;
+}
+
+/// The names of all built-in strategies.
+pub const STRATEGY_NAMES: [&str; 3] = ["heuristic", "exact", "overrun"];
+
+/// Returns the built-in strategy with the given name.
+pub fn strategy_by_name(name: &str) -> Option<Arc<dyn BoundaryStrategy>> {
+    match name {
+        "heuristic" => Some(Arc::new(HeuristicStrategy)),
+        "exact" => Some(Arc::new(ExactMatchStrategy)),
+        "overrun" => Some(Arc::new(OverrunStrategy)),
+        _ => None,
+    }
+}
+
+/// How the audio of a single channel of two frames compares.
+struct ChannelComparison {
+    covariance: f32,
+    /// How much the channel counts towards the similarity of the frames,
+    /// which is the average RMS level of both frames' samples.
+    weight: f32,
+    tail_silent: bool,
+    head_silent: bool,
+}
+
+/// How the audio of two frames compares, channel by channel.
+struct FrameComparison {
+    channels: Vec<ChannelComparison>,
+    /// The weighted average of all channel covariances.
+    covariance: f32,
+    tail_silent: bool,
+    head_silent: bool,
+}
+
+impl FrameComparison {
+    /// Whether both frames are silent.
+    fn silence(&self) -> bool {
+        self.tail_silent && self.head_silent
+    }
+
+    /// Whether the frames are either both silent, or contain the same audio.
+    /// A silent frame never matches one that isn't, because the covariance
+    /// of a silent frame isn't meaningful.
+    fn matches(&self, covariance_threshold: f32) -> bool {
+        self.silence()
+            || (!self.tail_silent && !self.head_silent && self.covariance > covariance_threshold)
+    }
+
+    fn log_channels(&self) {
+        for (i, c) in self.channels.iter().enumerate() {
+            debug!(
+                "ch {}: covariance {:.7}, weight {:.1}{}",
+                i,
+                c.covariance,
+                c.weight,
+                match (c.tail_silent, c.head_silent) {
+                    (true, true) => " (silent)",
+                    (true, false) => " (silent tail)",
+                    (false, true) => " (silent head)",
+                    (false, false) => "",
+                }
+            );
+        }
+    }
+}
+
+fn rms(samples: &[i32]) -> f32 {
+    if samples.is_empty() {
+        return 0f32;
+    }
+    let sum: f64 = samples.iter().map(|&s| s as f64 * s as f64).sum();
+    (sum / samples.len() as f64).sqrt() as f32
+}
+
+fn is_silent(samples: &[i32], threshold: i32) -> bool {
+    samples.iter().map(|n| n.abs()).max().unwrap_or(0) < threshold
+}
+
+fn compare_channel(tail: &[i32], head: &[i32], silence_threshold: i32) -> ChannelComparison {
+    let (tail_silent, head_silent) = (
+        is_silent(tail, silence_threshold),
+        is_silent(head, silence_threshold),
+    );
+    ChannelComparison {
+        // the covariance of a silent channel isn't meaningful, and one that is
+        // only silent on one side obviously doesn't contain the same audio
+        covariance: if tail_silent || head_silent {
+            0f32
+        } else {
+            dsp::covariance(head, tail)
+        },
+        weight: (rms(tail) + rms(head)) / 2f32,
+        tail_silent,
+        head_silent,
+    }
+}
+
+/// Compares two frames, given as the samples of each of their channels.
+/// Channels that are silent in both frames are left out of the covariance.
+fn compare_frames(tail: &[Vec<i32>], head: &[Vec<i32>], silence_threshold: i32) -> FrameComparison {
+    if tail.len() != head.len() {
+        debug!(
+            "Frames have different channel counts ({} and {}).",
+            tail.len(),
+            head.len()
+        );
+    }
+    let channels: Vec<ChannelComparison> = tail
+        .iter()
+        .zip(head)
+        .map(|(t, h)| compare_channel(t, h, silence_threshold))
+        .collect();
+
+    let (weighted_sum, weights) = channels
+        .iter()
+        .filter(|c| !(c.tail_silent && c.head_silent))
+        .fold((0f32, 0f32), |(sum, weights), c| {
+            (sum + c.covariance * c.weight, weights + c.weight)
+        });
+    let covariance = if weights > 0f32 {
+        weighted_sum / weights
+    } else {
+        0f32
+    };
+
+    FrameComparison {
+        covariance,
+        tail_silent: channels.iter().all(|c| c.tail_silent),
+        head_silent: channels.iter().all(|c| c.head_silent),
+        channels,
+    }
+}
+
+/// How well a frame matches the start of the next segment at the lag where
+/// all channels together correlate best.
+struct LagComparison {
+    /// At lag `l`, sample `i` of the frame matched sample `i + l` of the head.
+    lag: i32,
+    /// The weighted average of all channel correlations at `lag`.
+    score: f32,
+    /// The correlation and weight of every channel at `lag`, or `None` for
+    /// channels that are silent on both sides.
+    channels: Vec<Option<(f32, f32)>>,
+}
+
+impl LagComparison {
+    fn log_channels(&self) {
+        for (i, c) in self.channels.iter().enumerate() {
+            match c {
+                Some((score, weight)) => debug!(
+                    "ch {}: correlation {:.7} at lag {}, weight {:.1}",
+                    i, score, self.lag, weight
+                ),
+                None => debug!("ch {}: silent", i),
+            }
+        }
+    }
+}
+
+/// The correlation of a channel at every lag, along with the channel's weight.
+type WeightedCurve = (Vec<(i32, f32)>, f32);
+
+/// Cross-correlates the last frame of the previous segment with the start of
+/// the next segment, channel by channel, over lags of up to `max_lag`
+/// samples in either direction. Channels are weighted like in
+/// `compare_frames`, and a channel that is silent on only one side counts as
+/// not correlating at all.
+fn correlate_frames(
+    tail: &[Vec<i32>],
+    head: &[Vec<i32>],
+    max_lag: usize,
+    silence_threshold: i32,
+) -> Option<LagComparison> {
+    let curves: Vec<Option<WeightedCurve>> = tail
+        .iter()
+        .zip(head)
+        .map(|(t, h)| {
+            let (tail_silent, head_silent) = (
+                is_silent(t, silence_threshold),
+                is_silent(h, silence_threshold),
+            );
+            if tail_silent && head_silent {
+                return None;
+            }
+            let mut curve = dsp::cross_correlation(t, h, max_lag, t.len() / 2);
+            if tail_silent || head_silent {
+                curve.iter_mut().for_each(|(_, score)| *score = 0f32);
+            }
+            Some((curve, (rms(t) + rms(h)) / 2f32))
+        })
+        .collect();
+
+    let lags: Vec<i32> = curves
+        .iter()
+        .flatten()
+        .next()?
+        .0
+        .iter()
+        .map(|(lag, _)| *lag)
+        .collect();
+    let weights: f32 = curves.iter().flatten().map(|(_, w)| w).sum();
+    if weights <= 0f32 {
+        return None;
+    }
+    let scores: Vec<f32> = (0..lags.len())
+        .map(|i| {
+            curves
+                .iter()
+                .flatten()
+                .map(|(curve, w)| curve.get(i).map_or(0f32, |(_, s)| s * w))
+                .sum::<f32>()
+                / weights
+        })
+        .collect();
+
+    let (mut best, mut best_score) = (0, f32::MIN);
+    for (i, &score) in scores.iter().enumerate() {
+        if score > best_score {
+            best = i;
+            best_score = score;
+        }
+    }
+    // periodic audio correlates almost equally well at several lags, in which
+    // case the aligned lag is the most plausible one
+    if let Some(aligned) = lags.iter().position(|&l| l == 0) {
+        if best_score - scores[aligned] < LAG_TOLERANCE {
+            best = aligned;
+        }
+    }
+
+    Some(LagComparison {
+        lag: lags[best],
+        score: scores[best],
+        channels: curves
+            .iter()
+            .map(|c| {
+                c.as_ref()
+                    .map(|(curve, w)| (curve.get(best).map_or(0f32, |(_, s)| *s), *w))
+            })
+            .collect(),
+    })
+}
+
+/// Searches for an overlap of more than one frame, where the last frames of
+/// `tail` contain the same audio as the first frames of `head`. Frames are
+/// given as the samples of each of their channels. Silent frame pairs are
+/// accepted within an overlap, but at least one pair must actually contain
+/// audio. Returns the number of overlapping frames along with the comparison
+/// of each frame pair.
+fn find_overlap(
+    tail: &[Vec<Vec<i32>>],
+    head: &[Vec<Vec<i32>>],
+    thresholds: &BoundaryThresholds,
+) -> Option<(usize, Vec<FrameComparison>)> {
+    let max_overlap = tail.len().min(head.len()).min(MAX_OVERLAP_FRAMES);
+    for n in (2..=max_overlap).rev() {
+        let comparisons: Vec<FrameComparison> = tail[tail.len() - n..]
+            .iter()
+            .zip(head)
+            .map(|(t, h)| compare_frames(t, h, thresholds.silence))
+            .collect();
+        let overlaps = comparisons.iter().all(|c| c.matches(thresholds.covariance));
+        if overlaps && comparisons.iter().any(|c| !c.silence()) {
+            return Some((n, comparisons));
+        }
+    }
+    None
+}
+
+/// Compares the audio at the boundary channel by channel, and removes frames
+/// that contain the same audio as the start of the next segment. If both
+/// frames at the boundary are silent, a frame is removed only if that reduces
+/// the overrun.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicStrategy;
+
+impl BoundaryStrategy for HeuristicStrategy {
+    fn name(&self) -> &'static str {
+        "heuristic"
+    }
+
+    fn decide(
+        &self,
+        tail: &[ThdDecodePacket],
+        head: &[ThdDecodePacket],
+        overrun: &ThdOverrun,
+        thresholds: &BoundaryThresholds,
+    ) -> BoundaryDecision {
+        let tail_samples: Vec<Vec<Vec<i32>>> =
+            tail.iter().map(|p| p.original.channel_samples()).collect();
+        let head_samples: Vec<Vec<Vec<i32>>> =
+            head.iter().map(|p| p.original.channel_samples()).collect();
+
+        if let Some((frames, comparisons)) = find_overlap(&tail_samples, &head_samples, thresholds)
+        {
+            for (i, comparison) in comparisons.iter().enumerate() {
+                debug!(
+                    "Overlapping frame {} covariance is {:.7}",
+                    i, comparison.covariance
+                );
+                comparison.log_channels();
+            }
+            let covariance = comparisons
+                .iter()
+                .filter(|c| !c.silence())
+                .map(|c| c.covariance)
+                .fold(1f32, f32::min);
+            info!("Deleted {} frames with duplicate audio content.", frames);
+            let frame_size = tail
+                .last()
+                .map_or(0, |t| t.original.metadata.frame_size as i32);
+            return BoundaryDecision {
+                frames: frames as u32,
+                covariance: Some(covariance),
+                silence: false,
+                overlap: Some(frames as i32 * frame_size),
+                reason: CutReason::DuplicateAudio,
+            };
+        }
+
+        // no multi-frame overlap, so only the very last frame of the previous
+        // segment is compared with the start of the current one, allowing
+        // for the audio to be offset by up to one frame in either direction
+        let (tail_frame, head_frame) = match (tail_samples.last(), head_samples.first()) {
+            (Some(t), Some(h)) => (t, h),
+            _ => return BoundaryDecision::keep(CutReason::NoDuplicate),
+        };
+        let frame_size = tail_frame.first().map_or(0, |c| c.len());
+        let head_signal: Vec<Vec<i32>> = (0..head_frame.len())
+            .map(|ch| {
+                head_samples
+                    .iter()
+                    .take(2)
+                    .flat_map(|frame| frame.get(ch).into_iter().flatten().copied())
+                    .collect()
+            })
+            .collect();
+
+        let comparison = compare_frames(tail_frame, head_frame, thresholds.silence);
+        let silence = comparison.silence();
+        if silence {
+            // We're dealing with a silent section here, which means the covariance
+            // value isn't going to be very significant. So we'll fall back to
+            // minimizing desync based on the overrun accumulator.
+            debug!(
+                "Both frames at segment boundary appear to only contain silent audio. Falling back to overrun correction."
+            );
+
+            let (frames, reason) = if overrun.samples() >= thresholds.overrun {
+                info!("Deleted silent frame to correct for audio sync drift.");
+                (1, CutReason::SilenceOverrun)
+            } else {
+                info!("Segment boundary is OK, no adjustment necessary.");
+                (0, CutReason::Silence)
+            };
+            return BoundaryDecision {
+                frames,
+                covariance: Some(comparison.covariance),
+                silence,
+                overlap: None,
+                reason,
+            };
+        }
+
+        let correlation =
+            match correlate_frames(tail_frame, &head_signal, frame_size, thresholds.silence) {
+                Some(c) => c,
+                None => return BoundaryDecision::keep(CutReason::NoDuplicate),
+            };
+        debug!(
+            "Best frame correlation is {:.7} at a lag of {} samples",
+            correlation.score, correlation.lag
+        );
+        correlation.log_channels();
+
+        if correlation.score <= thresholds.covariance {
+            debug!("No duplicate audio content at segment boundary.");
+            info!("Segment boundary is OK, no adjustment necessary.");
+            return BoundaryDecision {
+                frames: 0,
+                covariance: Some(correlation.score),
+                silence,
+                overlap: None,
+                reason: CutReason::NoDuplicate,
+            };
+        }
+
+        // only whole frames can be removed, so round to the nearest one
+        let frame_size = frame_size as i32;
+        let overlap = frame_size + correlation.lag;
+        let frames = ((overlap + frame_size / 2) / frame_size).max(0) as u32;
+        if overlap != frames as i32 * frame_size {
+            info!(
+                "Detected a partial overlap of {} samples at segment boundary.",
+                overlap
+            );
+        }
+        let reason = if frames > 0 {
+            info!("Deleted {} frame(s) with duplicate audio content.", frames);
+            CutReason::DuplicateAudio
+        } else {
+            info!("Overlap is too short to remove a frame, no adjustment necessary.");
+            CutReason::NoDuplicate
+        };
+
+        BoundaryDecision {
+            frames,
+            covariance: Some(correlation.score),
+            silence,
+            overlap: Some(overlap),
+            reason,
+        }
+    }
+}
+
+/// Removes frames only if they decode to exactly the same samples as the
+/// start of the next segment. Frames of digital silence never count as a
+/// match.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExactMatchStrategy;
+
+impl BoundaryStrategy for ExactMatchStrategy {
+    fn name(&self) -> &'static str {
+        "exact"
+    }
+
+    fn decide(
+        &self,
+        tail: &[ThdDecodePacket],
+        head: &[ThdDecodePacket],
+        _overrun: &ThdOverrun,
+        _thresholds: &BoundaryThresholds,
+    ) -> BoundaryDecision {
+        let samples = |p: &ThdDecodePacket| -> Vec<i32> {
+            p.original.samples.iter().map(|s| s.value).collect()
+        };
+        let frame_size = tail
+            .last()
+            .map_or(0, |t| t.original.metadata.frame_size as i32);
+        let tail: Vec<Vec<i32>> = tail.iter().map(samples).collect();
+        let head: Vec<Vec<i32>> = head.iter().map(samples).collect();
+
+        let max_overlap = tail.len().min(head.len()).min(MAX_OVERLAP_FRAMES);
+        let overlap = (1..=max_overlap).rev().find(|&n| {
+            let tail = &tail[tail.len() - n..];
+            tail == &head[..n] && tail.iter().flatten().any(|&s| s != 0)
+        });
+
+        match overlap {
+            Some(frames) => {
+                info!("Deleted {} frame(s) with identical audio content.", frames);
+                BoundaryDecision {
+                    frames: frames as u32,
+                    covariance: Some(1f32),
+                    silence: false,
+                    overlap: Some(frames as i32 * frame_size),
+                    reason: CutReason::DuplicateAudio,
+                }
+            }
+            None => {
+                info!("Segment boundary is OK, no identical frames found.");
+                BoundaryDecision::keep(CutReason::NoDuplicate)
+            }
+        }
+    }
+}
+
+/// Ignores the audio content, and removes frames only to keep the overrun as
+/// close to zero as possible.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OverrunStrategy;
+
+impl BoundaryStrategy for OverrunStrategy {
+    fn name(&self) -> &'static str {
+        "overrun"
+    }
+
+    fn decide(
+        &self,
+        tail: &[ThdDecodePacket],
+        _head: &[ThdDecodePacket],
+        overrun: &ThdOverrun,
+        thresholds: &BoundaryThresholds,
+    ) -> BoundaryDecision {
+        let frame_size = match tail.last() {
+            Some(t) => t.original.metadata.frame_size as i32,
+            None => return BoundaryDecision::keep(CutReason::NoOverrun),
+        };
+
+        // round to the nearest number of frames
+        let frames = (overrun.samples() + frame_size - thresholds.overrun) / frame_size;
+        if frames > 0 {
+            info!(
+                "Deleted {} frame(s) to correct for audio sync drift.",
+                frames
+            );
+            BoundaryDecision {
+                frames: frames as u32,
+                covariance: None,
+                silence: false,
+                overlap: None,
+                reason: CutReason::Overrun,
+            }
+        } else {
+            info!("Segment boundary is OK, no adjustment necessary.");
+            BoundaryDecision::keep(CutReason::NoOverrun)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_frames, correlate_frames, find_overlap, BoundaryThresholds};
+    use crate::libav::truehd::ThdMetadata;
+
+    fn channel(seed: i32) -> Vec<i32> {
+        (0..40)
+            .map(|i| ((i * 7 + seed * 13) % 29 - 14) * 500)
+            .collect()
+    }
+
+    fn frame(seed: i32) -> Vec<Vec<i32>> {
+        vec![channel(seed), channel(seed + 1)]
+    }
+
+    fn silent_frame() -> Vec<Vec<i32>> {
+        vec![vec![0; 40], vec![0; 40]]
+    }
+
+    #[test]
+    fn find_overlap_finds_multiple_duplicate_frames() {
+        let tail = vec![frame(1), frame(2), frame(3), frame(4)];
+        let head = vec![frame(3), frame(4), frame(5)];
+        assert_eq!(
+            Some(2),
+            find_overlap(&tail, &head, &BoundaryThresholds::default()).map(|(n, _)| n)
+        );
+    }
+
+    #[test]
+    fn find_overlap_ignores_silence() {
+        let tail = vec![frame(1), silent_frame(), silent_frame()];
+        let head = vec![silent_frame(), silent_frame(), frame(2)];
+        assert!(find_overlap(&tail, &head, &BoundaryThresholds::default()).is_none());
+    }
+
+    #[test]
+    fn compare_frames_matches_out_of_phase_channels() {
+        // these frames cancel out completely in a mono downmix
+        let inverted: Vec<i32> = channel(1).iter().map(|s| -s).collect();
+        let tail = vec![channel(1), inverted.clone()];
+        let head = vec![channel(1), inverted];
+        assert!(compare_frames(&tail, &head, 100).matches(0.95));
+
+        let different = vec![channel(1), channel(5)];
+        assert!(!compare_frames(&tail, &different, 100).matches(0.95));
+    }
+
+    #[test]
+    fn correlate_frames_detects_partial_overlap() {
+        let signal: Vec<i32> = (0..200).map(|i| ((i * 37) % 101 - 50) * 1000).collect();
+        let tail = vec![signal[40..80].to_vec()];
+        // the head repeats the last 25 samples of the tail
+        let head = vec![signal[55..135].to_vec()];
+
+        let correlation = correlate_frames(&tail, &head, 40, 100).unwrap();
+        assert_eq!(-15, correlation.lag);
+        assert!(correlation.score > 0.999);
+    }
+
+    #[test]
+    fn thresholds_scale_with_bit_depth_and_sample_rate() {
+        let metadata = |bit_depth, sample_rate| ThdMetadata {
+            channels: 6,
+            sample_rate,
+            frame_size: (sample_rate / 1200) as u8,
+            bit_depth,
+        };
+        assert_eq!(
+            BoundaryThresholds::default(),
+            BoundaryThresholds::for_stream(&metadata(24, 48000))
+        );
+
+        let thresholds = BoundaryThresholds::for_stream(&metadata(16, 96000));
+        assert_eq!(1024, thresholds.silence);
+        assert_eq!(40, thresholds.overrun);
+    }
+}
@@ -0,0 +1,195 @@
+//! An on-disk cache of what the demuxer learns about each segment: frame
+//! counts and the decoded head and tail of every TrueHD stream. Clips that are
+//! shared between playlists, or demuxed again, don't need to be decoded again.
+
+use super::{
+    checkpoint::PacketCheckpoint, truehd::ThdMetadata, ThdDecodePacket, ThdFrameHeader,
+    VideoMetadata,
+};
+use crate::Segment;
+use crc::crc64;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read},
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+
+/// The version of the cache entries. Bump this whenever the format changes,
+/// or whenever the demuxer would come to different results, so that old
+/// entries are ignored.
+pub const CACHE_VERSION: u32 = 1;
+
+/// The number of bytes at the start of a file that are hashed to identify it.
+const HEAD_HASH_LEN: u64 = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct AnalysisCache {
+    dir: PathBuf,
+}
+
+/// Identifies the contents of a segment file by its size, modification time
+/// and a hash of its first megabyte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileKey(String);
+
+/// What the demuxer learned about one TrueHD stream of a segment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamAnalysis {
+    pub version: u32,
+    pub stream_id: i32,
+    pub num_video_frames: u32,
+    pub video_metadata: VideoMetadata,
+    pub num_frames: u32,
+    pub thd_metadata: ThdMetadata,
+    /// The first few decoded frames, if they were needed for a boundary check.
+    pub head: Option<Vec<PacketCheckpoint>>,
+    /// The decoded last group of frames, unless the boundary decision after
+    /// the segment was replayed from a cut list.
+    pub last_group_of_frames: Option<Vec<PacketCheckpoint>>,
+    pub last_group_headers: Vec<ThdFrameHeader>,
+}
+
+impl AnalysisCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> AnalysisCache {
+        AnalysisCache { dir: dir.into() }
+    }
+
+    /// Returns the key of the given segment's file, or `None` if it can't be
+    /// read.
+    pub fn key(&self, segment: &Segment) -> Option<FileKey> {
+        match file_key(segment) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                debug!("Can't use the cache for {}: {}", segment.path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn load(&self, key: &FileKey, stream_id: i32) -> Option<StreamAnalysis> {
+        let path = self.entry_path(key, stream_id);
+        let file = File::open(&path).ok()?;
+        match serde_json::from_reader::<_, StreamAnalysis>(BufReader::new(file)) {
+            Ok(analysis) if analysis.version == CACHE_VERSION => {
+                debug!("Using cached analysis {}.", path.display());
+                Some(analysis)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Ignoring invalid cache entry {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Stores the analysis. Failures are logged, but otherwise ignored, since
+    /// the cache is only an optimization.
+    pub fn store(&self, key: &FileKey, analysis: &StreamAnalysis) {
+        let path = self.entry_path(key, analysis.stream_id);
+        let result = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| File::create(&path))
+            .and_then(|file| {
+                serde_json::to_writer(BufWriter::new(file), analysis).map_err(|e| e.into())
+            });
+        if let Err(e) = result {
+            debug!("Failed to write cache entry {}: {}", path.display(), e);
+        }
+    }
+
+    fn entry_path(&self, key: &FileKey, stream_id: i32) -> PathBuf {
+        let name = format!("{}-{:x}", key.0, stream_id);
+        self.dir.join(format!(
+            "{:016x}.json",
+            crc64::checksum_ecma(name.as_bytes())
+        ))
+    }
+}
+
+fn file_key(segment: &Segment) -> std::io::Result<FileKey> {
+    let (len, modified) = segment.metadata()?;
+    let mtime = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut head = Vec::with_capacity(HEAD_HASH_LEN as usize);
+    segment
+        .reader()?
+        .take(HEAD_HASH_LEN)
+        .read_to_end(&mut head)?;
+
+    Ok(FileKey(format!(
+        "{:x}-{:x}.{:x}-{:016x}",
+        len,
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        crc64::checksum_ecma(&head)
+    )))
+}
+
+/// Converts decoded frames for storing them in the cache.
+pub fn packets_to_cache(packets: &[ThdDecodePacket]) -> Vec<PacketCheckpoint> {
+    packets.iter().map(PacketCheckpoint::from).collect()
+}
+
+/// Converts decoded frames back from the cache.
+pub fn packets_from_cache(packets: &[PacketCheckpoint]) -> Vec<ThdDecodePacket> {
+    packets.iter().cloned().map(ThdDecodePacket::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libav::{Framerate, ScanType};
+
+    #[test]
+    fn entries_are_keyed_by_file_and_stream() {
+        let dir = std::env::temp_dir().join(format!("mlp-cache-test-{}", std::process::id()));
+        let clip = dir.join("00056.m2ts");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&clip, b"not really a transport stream").unwrap();
+
+        let cache = AnalysisCache::new(dir.join("cache"));
+        let segment = Segment {
+            path: clip.clone(),
+            image: None,
+            video_frames: None,
+            video_metadata: None,
+        };
+        let key = cache.key(&segment).unwrap();
+        let analysis = StreamAnalysis {
+            version: CACHE_VERSION,
+            stream_id: 0x1100,
+            num_video_frames: 3587,
+            video_metadata: VideoMetadata {
+                framerate: Framerate {
+                    numerator: 24000,
+                    denominator: 1001,
+                },
+                scan: ScanType::Progressive,
+            },
+            num_frames: 179_530,
+            thd_metadata: ThdMetadata {
+                channels: 8,
+                sample_rate: 48000,
+                frame_size: 40,
+                bit_depth: 24,
+            },
+            head: None,
+            last_group_of_frames: Some(Vec::new()),
+            last_group_headers: Vec::new(),
+        };
+        cache.store(&key, &analysis);
+
+        let loaded = cache.load(&key, 0x1100).unwrap();
+        assert_eq!(loaded.num_frames, 179_530);
+        assert!(cache.load(&key, 0x1101).is_none());
+
+        std::fs::write(&clip, b"a different transport stream").unwrap();
+        let changed = cache.key(&segment).unwrap();
+        assert_ne!(changed, key);
+        assert!(cache.load(&changed, 0x1100).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use super::{
+    cut_map::WrittenPacket,
+    demux::{SecondaryDemuxStats, SegmentDemuxStats},
+    truehd::ThdMetadata,
+    AVError, BoundaryCut, DecodedThdFrame, OtherErr, ThdDecodePacket, ThdFrameHeader, ThdSample,
+    ThdSegment, VideoMetadata,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// The version of the checkpoint file format. Bump this whenever the format
+/// changes in a way older versions can't read.
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// The state of a demux run after a completed segment. A run that is resumed
+/// from a checkpoint writes exactly the same output as one that never stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub version: u32,
+    /// The clip names of all segments of the run.
+    pub clips: Vec<String>,
+    /// The index of the first segment that hasn't been written yet.
+    pub next_segment: usize,
+    pub streams: Vec<StreamCheckpoint>,
+    pub secondaries: Vec<SecondaryCheckpoint>,
+    /// Which of the boundary overrides have been applied so far.
+    pub overrides_used: Vec<bool>,
+}
+
+/// The state of a TrueHD stream whose boundaries are checked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamCheckpoint {
+    pub stream_id: i32,
+    /// The length of the output written so far, in bytes.
+    pub output_len: u64,
+    pub segments: Vec<SegmentDemuxStats>,
+    pub boundaries: Vec<BoundaryCut>,
+    /// The end of the last completed segment, which the next boundary is
+    /// checked against.
+    pub previous_segment: Option<SegmentCheckpoint>,
+}
+
+/// The state of a secondary stream that follows a cut map.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecondaryCheckpoint {
+    /// The length of the output written so far, in bytes.
+    pub output_len: u64,
+    pub stats: SecondaryDemuxStats,
+    pub tail: Vec<WrittenPacket>,
+}
+
+/// A `ThdSegment`, including the decoded audio of its last group of frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentCheckpoint {
+    pub last_group_of_frames: Vec<PacketCheckpoint>,
+    pub last_group_headers: Vec<ThdFrameHeader>,
+    pub num_frames: u32,
+    pub num_video_frames: u32,
+    pub thd_metadata: ThdMetadata,
+    pub video_metadata: VideoMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PacketCheckpoint {
+    pub original: FrameCheckpoint,
+    pub mono: FrameCheckpoint,
+}
+
+/// A decoded frame, with its samples interleaved in channel order.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FrameCheckpoint {
+    pub metadata: ThdMetadata,
+    pub samples: Vec<i32>,
+}
+
+// the samples would flood the debug log
+impl std::fmt::Debug for FrameCheckpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameCheckpoint")
+            .field("metadata", &self.metadata)
+            .field("samples", &self.samples.len())
+            .finish()
+    }
+}
+
+impl Checkpoint {
+    pub fn read<R: Read>(reader: R) -> Result<Checkpoint, AVError> {
+        let checkpoint: Checkpoint = serde_json::from_reader(reader)
+            .map_err(|e| OtherErr::InvalidCheckpoint(e.to_string()))?;
+        if checkpoint.version != CHECKPOINT_VERSION {
+            return Err(OtherErr::UnsupportedCheckpointVersion(checkpoint.version).into());
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), AVError> {
+        serde_json::to_writer(writer, self)
+            .map_err(|e| OtherErr::InvalidCheckpoint(e.to_string()).into())
+    }
+
+    /// Writes the checkpoint to the given file. The previous checkpoint is only
+    /// replaced once the new one has been written completely.
+    pub fn save(&self, path: &Path) -> Result<(), AVError> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            self.write(&mut writer)?;
+            writer.flush()?;
+        }
+        std::fs::rename(PathBuf::from(tmp_path), path)?;
+        Ok(())
+    }
+
+    /// Checks that the checkpoint was made for the given segments and streams.
+    pub fn validate(
+        &self,
+        clips: &[String],
+        stream_ids: &[i32],
+        secondary_ids: &[i32],
+    ) -> Result<(), AVError> {
+        if self.clips != clips {
+            return Err(OtherErr::InvalidCheckpoint(String::from(
+                "the checkpoint was made for different segments",
+            ))
+            .into());
+        }
+        if self.next_segment > clips.len() {
+            return Err(OtherErr::InvalidCheckpoint(format!(
+                "segment {} doesn't exist",
+                self.next_segment
+            ))
+            .into());
+        }
+        let checkpoint_ids: Vec<i32> = self.streams.iter().map(|s| s.stream_id).collect();
+        let checkpoint_secondary_ids: Vec<i32> =
+            self.secondaries.iter().map(|s| s.stats.stream_id).collect();
+        if checkpoint_ids != stream_ids || checkpoint_secondary_ids != secondary_ids {
+            return Err(OtherErr::InvalidCheckpoint(String::from(
+                "the checkpoint was made for different streams",
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl From<&ThdSegment> for SegmentCheckpoint {
+    fn from(segment: &ThdSegment) -> Self {
+        SegmentCheckpoint {
+            last_group_of_frames: segment
+                .last_group_of_frames
+                .iter()
+                .map(PacketCheckpoint::from)
+                .collect(),
+            last_group_headers: segment.last_group_headers.clone(),
+            num_frames: segment.num_frames,
+            num_video_frames: segment.num_video_frames,
+            thd_metadata: segment.thd_metadata,
+            video_metadata: segment.video_metadata,
+        }
+    }
+}
+
+impl From<SegmentCheckpoint> for ThdSegment {
+    fn from(segment: SegmentCheckpoint) -> Self {
+        ThdSegment {
+            last_group_of_frames: segment
+                .last_group_of_frames
+                .into_iter()
+                .map(ThdDecodePacket::from)
+                .collect(),
+            last_group_headers: segment.last_group_headers,
+            num_frames: segment.num_frames,
+            num_video_frames: segment.num_video_frames,
+            thd_metadata: segment.thd_metadata,
+            video_metadata: segment.video_metadata,
+        }
+    }
+}
+
+impl From<&ThdDecodePacket> for PacketCheckpoint {
+    fn from(packet: &ThdDecodePacket) -> Self {
+        PacketCheckpoint {
+            original: FrameCheckpoint::from(&packet.original),
+            mono: FrameCheckpoint::from(&packet.mono),
+        }
+    }
+}
+
+impl From<PacketCheckpoint> for ThdDecodePacket {
+    fn from(packet: PacketCheckpoint) -> Self {
+        ThdDecodePacket {
+            original: packet.original.into(),
+            mono: packet.mono.into(),
+        }
+    }
+}
+
+impl From<&DecodedThdFrame> for FrameCheckpoint {
+    fn from(frame: &DecodedThdFrame) -> Self {
+        FrameCheckpoint {
+            metadata: frame.metadata,
+            samples: frame.samples.iter().map(|s| s.value).collect(),
+        }
+    }
+}
+
+impl From<FrameCheckpoint> for DecodedThdFrame {
+    fn from(frame: FrameCheckpoint) -> Self {
+        let channels = frame.metadata.channels.max(1) as usize;
+        DecodedThdFrame {
+            samples: frame
+                .samples
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| ThdSample::new(value, (i % channels) as u8))
+                .collect(),
+            metadata: frame.metadata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let bytes: Vec<u8> = (0..6i32)
+            .flat_map(|i| (i * 256 - 512).to_ne_bytes().to_vec())
+            .collect();
+        let frame = DecodedThdFrame::new(&bytes, 4, 2, 48000);
+        let restored: DecodedThdFrame = FrameCheckpoint::from(&frame).into();
+        assert_eq!(restored.channel_samples(), frame.channel_samples());
+        assert_eq!(restored.metadata.channels, 2);
+    }
+}
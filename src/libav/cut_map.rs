@@ -0,0 +1,303 @@
+use super::{AVError, OtherErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+};
+
+/// The version of the cut list file format. Bump this whenever the format
+/// changes in a way older versions can't read.
+pub const CUT_LIST_VERSION: u32 = 1;
+
+/// Why a segment boundary was adjusted the way it was.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CutReason {
+    /// The frames at the boundary contain the same audio.
+    DuplicateAudio,
+    /// The frames at the boundary contain different audio.
+    NoDuplicate,
+    /// Both frames are silent, and a frame was deleted to correct the overrun.
+    SilenceOverrun,
+    /// Both frames are silent, and the overrun didn't need correcting.
+    Silence,
+    /// Frames were deleted to correct the overrun, regardless of the audio.
+    Overrun,
+    /// The overrun didn't need correcting, and the audio wasn't checked.
+    NoOverrun,
+    /// The decision was forced by a user override.
+    Override,
+    /// The previous segment has no decoded audio to compare with, so nothing
+    /// was deleted.
+    NoAudio,
+}
+
+impl Display for CutReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CutReason::DuplicateAudio => "duplicate audio",
+            CutReason::NoDuplicate => "no duplicate audio",
+            CutReason::SilenceOverrun => "silence, corrected overrun",
+            CutReason::Silence => "silence",
+            CutReason::Overrun => "corrected overrun",
+            CutReason::NoOverrun => "no overrun",
+            CutReason::Override => "override",
+            CutReason::NoAudio => "no audio to compare",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The adjustment that was made at a single segment boundary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoundaryCut {
+    /// Index of the segment that follows the boundary.
+    pub segment: usize,
+    /// File name of the segment that follows the boundary.
+    pub clip: String,
+    /// Number of frames that were removed from the end of the previous segment.
+    pub frames: u32,
+    /// Duration of the removed frames, in seconds.
+    pub duration: f64,
+    /// Covariance of the frames at the boundary, if it was computed.
+    pub covariance: Option<f32>,
+    /// Whether both frames at the boundary were considered silent.
+    pub silence: bool,
+    /// The uncorrected overrun at the time the decision was made, in samples.
+    pub overrun: i32,
+    /// The number of samples the next segment repeats of the previous one, if
+    /// an overlap was detected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlap: Option<i32>,
+    pub reason: CutReason,
+}
+
+/// A list of all segment boundaries of a demux run, and how much audio was
+/// removed at each of them. The cut map of the primary TrueHD stream is what
+/// every secondary audio stream is cut by, so that all streams drift
+/// identically relative to the video.
+#[derive(Debug, Clone, Default)]
+pub struct CutMap {
+    pub boundaries: Vec<BoundaryCut>,
+}
+
+impl CutMap {
+    pub fn push(&mut self, cut: BoundaryCut) {
+        self.boundaries.push(cut);
+    }
+
+    /// Total duration removed across all boundaries, in seconds.
+    pub fn total_duration(&self) -> f64 {
+        self.boundaries.iter().map(|b| b.duration).sum()
+    }
+
+    /// Total duration removed at all boundaries up to and including the one
+    /// before the given segment, in seconds.
+    pub fn duration_until(&self, segment: usize) -> f64 {
+        self.boundaries
+            .iter()
+            .filter(|b| b.segment <= segment)
+            .map(|b| b.duration)
+            .sum()
+    }
+}
+
+/// Identifies a segment boundary, either by its index or by the name of the
+/// clip that follows it. Boundary `n` is the one between segments `n - 1` and
+/// `n`, so the first boundary has index 1. A clip name matches every boundary
+/// before that clip, in case the playlist plays it more than once.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoundaryRef {
+    Index(usize),
+    Clip(String),
+}
+
+/// What to do at a segment boundary, regardless of what the boundary check
+/// would have decided.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OverrideAction {
+    /// Keep all frames.
+    Keep,
+    /// Delete the given number of frames from the end of the previous segment.
+    Delete(u32),
+}
+
+impl OverrideAction {
+    pub fn frames(&self) -> u32 {
+        match self {
+            OverrideAction::Keep => 0,
+            OverrideAction::Delete(n) => *n,
+        }
+    }
+}
+
+/// Forces the decision at one or more segment boundaries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundaryOverride {
+    pub boundary: BoundaryRef,
+    pub action: OverrideAction,
+}
+
+impl BoundaryOverride {
+    pub fn matches(&self, segment: usize, clip: &str) -> bool {
+        match self.boundary {
+            BoundaryRef::Index(i) => i == segment,
+            BoundaryRef::Clip(ref name) => name == clip,
+        }
+    }
+}
+
+impl Display for BoundaryRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundaryRef::Index(i) => write!(f, "boundary {}", i),
+            BoundaryRef::Clip(name) => write!(f, "boundary before clip {}", name),
+        }
+    }
+}
+
+/// A versioned file that records every boundary decision of a demux run, so
+/// that the run can be reproduced exactly, without decoding any audio.
+/// Boundaries can also be edited by hand before replaying a cut list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CutList {
+    pub version: u32,
+    /// Id of the TrueHD stream the decisions were made for.
+    pub stream_id: Option<i32>,
+    pub boundaries: Vec<BoundaryCut>,
+}
+
+impl CutList {
+    pub fn new(stream_id: Option<i32>, cut_map: &CutMap) -> CutList {
+        CutList {
+            version: CUT_LIST_VERSION,
+            stream_id,
+            boundaries: cut_map.boundaries.clone(),
+        }
+    }
+
+    pub fn read<R: Read>(reader: R) -> Result<CutList, AVError> {
+        let cut_list: CutList =
+            serde_json::from_reader(reader).map_err(|e| OtherErr::InvalidCutList(e.to_string()))?;
+        if cut_list.version != CUT_LIST_VERSION {
+            return Err(OtherErr::UnsupportedCutListVersion(cut_list.version).into());
+        }
+        Ok(cut_list)
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), AVError> {
+        serde_json::to_writer_pretty(writer, self)
+            .map_err(|e| OtherErr::InvalidCutList(e.to_string()).into())
+    }
+
+    /// Returns the recorded decision for the boundary before the given segment.
+    pub fn boundary(&self, segment: usize) -> Option<&BoundaryCut> {
+        self.boundaries.iter().find(|b| b.segment == segment)
+    }
+}
+
+impl Display for BoundaryCut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Boundary before segment {} ({}): {} frame{} removed ({:.7} seconds)",
+            self.segment,
+            self.clip,
+            self.frames,
+            if self.frames == 1 { "" } else { "s" },
+            self.duration
+        )?;
+        if let Some(overlap) = self.overlap {
+            write!(f, ", {} samples overlapping", overlap)?;
+        }
+        Ok(())
+    }
+}
+
+/// The length and duration of a single packet that was written to the output
+/// of a secondary stream.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct WrittenPacket {
+    pub length: usize,
+    pub duration: f64,
+    /// Whether the packet may be removed. TrueHD frames that carry a major
+    /// sync must never be removed.
+    pub removable: bool,
+}
+
+/// Picks the packets to remove from the end of the previously written segment
+/// of a secondary stream, so that the total duration removed from it matches
+/// the duration removed from the primary stream as closely as possible.
+///
+/// `debt` is the duration the secondary stream is behind the primary stream's
+/// cut map, in seconds. Returns the number of packets to remove, counted from
+/// the end of `tail`.
+pub fn packets_to_remove(tail: &[WrittenPacket], debt: f64) -> usize {
+    let mut debt = debt;
+    let mut n = 0;
+    for packet in tail.iter().rev() {
+        if !packet.removable || debt < packet.duration / 2.0 {
+            break;
+        }
+        debt -= packet.duration;
+        n += 1;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{packets_to_remove, BoundaryCut, CutList, CutMap, CutReason, WrittenPacket};
+
+    fn ac3_packets(n: usize) -> Vec<WrittenPacket> {
+        (0..n)
+            .map(|_| WrittenPacket {
+                length: 1792,
+                duration: 1536.0 / 48000.0,
+                removable: true,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packets_to_remove_rounds_to_nearest_packet() {
+        let tail = ac3_packets(8);
+        // one TrueHD frame is much shorter than half an AC-3 frame
+        assert_eq!(0, packets_to_remove(&tail, 40.0 / 48000.0));
+        // 20 TrueHD frames are 800 samples, a little over half an AC-3 frame
+        assert_eq!(1, packets_to_remove(&tail, 800.0 / 48000.0));
+        assert_eq!(2, packets_to_remove(&tail, 3000.0 / 48000.0));
+    }
+
+    #[test]
+    fn packets_to_remove_stops_at_major_sync() {
+        let mut tail = ac3_packets(4);
+        tail[2].removable = false;
+        assert_eq!(1, packets_to_remove(&tail, 1.0));
+    }
+
+    #[test]
+    fn kept_boundaries_round_trip() {
+        let mut cut_map = CutMap::default();
+        cut_map.push(BoundaryCut {
+            segment: 1,
+            clip: String::from("00056"),
+            frames: 0,
+            duration: 0.0,
+            covariance: None,
+            silence: false,
+            overrun: 120,
+            overlap: None,
+            reason: CutReason::NoAudio,
+        });
+
+        let mut bytes = Vec::new();
+        CutList::new(Some(0x1100), &cut_map)
+            .write(&mut bytes)
+            .unwrap();
+        let cut_list = CutList::read(&bytes[..]).unwrap();
+        let cut = cut_list.boundary(1).unwrap();
+        assert_eq!(cut.frames, 0);
+        assert_eq!(cut.reason, CutReason::NoAudio);
+    }
+}
@@ -1,34 +1,242 @@
 use super::{
-    dsp, truehd, AVCodecType, AVError, AVFormatContext, AVFrame, AVPacket, AVStream,
-    DecodedThdFrame, DemuxErr, Framerate, MediaDuration, ThdDecodePacket, ThdFrameHeader,
-    ThdOverrun, ThdSegment, VideoMetadata,
+    access_units::AccessUnitCounter,
+    boundary::{BoundaryStrategy, HeuristicStrategy, ThresholdOptions, MAX_OVERLAP_FRAMES},
+    cache::{
+        packets_from_cache, packets_to_cache, AnalysisCache, FileKey, StreamAnalysis, CACHE_VERSION,
+    },
+    checkpoint::{Checkpoint, SecondaryCheckpoint, StreamCheckpoint, CHECKPOINT_VERSION},
+    cut_map::{
+        packets_to_remove, BoundaryOverride, CutList, CutReason, OverrideAction, WrittenPacket,
+    },
+    events::{DemuxEvent, DemuxObserver, EventSink},
+    scan::{ScanStream, SegmentScan, SegmentScanner},
+    truehd, AVCodecType, AVError, AVFormatContext, AVFrame, AVPacket, AVStream, BoundaryCut,
+    CancellationToken, CutMap, DecodedThdFrame, DemuxErr, Framerate, MediaDuration, OtherErr,
+    ScanType, ThdDecodePacket, ThdFrameHeader, ThdOverrun, ThdSegment, VideoMetadata,
 };
 use crate::Segment;
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{debug, info, trace, warn};
+use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
-    io::{Seek, SeekFrom, Write}, path::Path,
+    io::{Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
 };
 use truehd::ThdMetadata;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SegmentDemuxStats {
     pub video_frames: u32,
     pub video_metadata: VideoMetadata,
     pub thd_frames: u32,
     pub thd_frames_original: u32,
     pub thd_metadata: ThdMetadata,
+    #[serde(default)]
+    pub video_frame_counts: VideoFrameCounts,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// The number of video frames of a segment, according to every source that
+/// was available. The sources should agree, but FFmpeg sometimes misses the
+/// first HEVC frame of a stream, and timestamps can have gaps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct VideoFrameCounts {
+    /// The number of access units in the video stream, counted natively from
+    /// the transport stream.
+    pub access_units: Option<u32>,
+    /// The number of access units of the MVC dependent view, if any.
+    pub dependent_view: Option<u32>,
+    /// The number of video packets that were read.
+    pub packets: Option<u32>,
+    /// The span of the presentation timestamps, divided by the frame
+    /// duration.
+    pub timestamps: Option<u32>,
+    /// What an earlier run counted, if the segment wasn't read.
+    pub cached: Option<u32>,
+    /// What the playlist or clip info says.
+    pub expected: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
 pub struct DemuxOptions {
     pub thd_stream_id: Option<i32>,
+    /// Replays the boundary decisions of a previous run instead of decoding
+    /// the audio at each segment boundary.
+    pub cut_list: Option<CutList>,
+    /// Forces the decision at specific segment boundaries. These take
+    /// precedence over both the boundary check and the cut list.
+    pub overrides: Vec<BoundaryOverride>,
+    /// Decides what to do at every segment boundary that isn't overridden or
+    /// replayed from a cut list.
+    pub strategy: Arc<dyn BoundaryStrategy>,
+    /// Thresholds for the boundary strategy. Those that aren't set are
+    /// derived from each stream's bit depth and sample rate.
+    pub thresholds: ThresholdOptions,
+    /// Receives progress, boundary decisions and warnings while demuxing.
+    pub observer: Option<Arc<dyn DemuxObserver>>,
+    /// Stops the run when cancelled. The writers are left as they are at that
+    /// point, so any partially written output should be discarded.
+    pub cancellation: CancellationToken,
+    /// Writes a checkpoint to this file after every completed segment. The
+    /// file is left in place when the run is done.
+    pub checkpoint: Option<PathBuf>,
+    /// Continues a previous run from the given checkpoint. The writers must
+    /// contain that run's output up to the lengths the checkpoint records.
+    pub resume: Option<Checkpoint>,
+    /// The number of segments that are read and decoded in parallel. With
+    /// more than one, the audio of every segment that is read ahead is kept in
+    /// memory until it's written.
+    pub jobs: usize,
+    /// Reuses what earlier runs learned about each segment, and stores what
+    /// this run learns. When no audio is written, segments that are cached
+    /// completely aren't read at all.
+    pub cache: Option<AnalysisCache>,
 }
 
 impl Default for DemuxOptions {
     fn default() -> Self {
         DemuxOptions {
             thd_stream_id: None,
+            cut_list: None,
+            overrides: Vec::new(),
+            strategy: Arc::new(HeuristicStrategy),
+            thresholds: ThresholdOptions::default(),
+            observer: None,
+            cancellation: CancellationToken::default(),
+            checkpoint: None,
+            resume: None,
+            jobs: 1,
+            cache: None,
+        }
+    }
+}
+
+impl VideoFrameCounts {
+    /// The number of frames the segment was found to have, preferring the
+    /// access units over the timestamps, and those over the number of packets.
+    pub fn counted(&self) -> Option<u32> {
+        self.access_units
+            .or(self.timestamps)
+            .or(self.packets)
+            .or(self.cached)
+    }
+
+    /// Makes the counts comparable to the given video metadata, when the
+    /// segment was counted with the `probed` metadata. Timestamps are
+    /// converted to the other frame rate, and for interlaced video, counts of
+    /// about twice the timestamps' or the expected count are taken to be
+    /// counts of separately coded fields.
+    pub fn normalized(self, probed: &VideoMetadata, video: &VideoMetadata) -> VideoFrameCounts {
+        let timestamps = self.timestamps.map(|n| video.frames(probed.duration(n)));
+        let reference = timestamps.or(self.expected);
+        let frames = |count: Option<u32>| match (count, reference) {
+            (Some(n), Some(r)) if video.scan == ScanType::Interlaced && n > r + 2 => {
+                if (n as i64 - 2 * r as i64).abs() <= 2 {
+                    Some(n.div_ceil(2))
+                } else {
+                    Some(n)
+                }
+            }
+            _ => count,
+        };
+        VideoFrameCounts {
+            access_units: frames(self.access_units),
+            dependent_view: frames(self.dependent_view),
+            packets: frames(self.packets),
+            timestamps,
+            ..self
+        }
+    }
+
+    /// Adds the access units counted while the segment was read.
+    pub(super) fn with_access_units(self, counter: &Mutex<AccessUnitCounter>) -> VideoFrameCounts {
+        let count = counter.lock().unwrap().count();
+        VideoFrameCounts {
+            access_units: count.map(|c| c.frames),
+            dependent_view: count.and_then(|c| c.dependent_frames),
+            ..self
+        }
+    }
+
+    /// Whether any two of the available sources disagree.
+    pub fn disagree(&self) -> bool {
+        let counts = [
+            self.access_units,
+            self.dependent_view,
+            self.packets,
+            self.timestamps,
+            self.cached,
+            self.expected,
+        ];
+        let mut counts = counts.iter().flatten();
+        match counts.next() {
+            Some(first) => counts.any(|n| n != first),
+            None => false,
+        }
+    }
+}
+
+impl Display for VideoFrameCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sources = [
+            ("access units", self.access_units),
+            ("dependent view", self.dependent_view),
+            ("packets", self.packets),
+            ("timestamps", self.timestamps),
+            ("cache", self.cached),
+            ("playlist", self.expected),
+        ];
+        let counts: Vec<String> = sources
+            .iter()
+            .filter_map(|(name, n)| n.map(|n| format!("{} {}", n, name)))
+            .collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+/// Counts the video frames of a segment while it's read.
+#[derive(Debug, Default)]
+pub(super) struct VideoFrameCounter {
+    packets: u32,
+    // the earliest and latest presentation timestamp, which aren't the first
+    // and last packet's if frames are reordered, and the latter's duration
+    pts_range: Option<(i64, i64, i64)>,
+}
+
+impl VideoFrameCounter {
+    pub fn push(&mut self, packet: &AVPacket) {
+        self.packets += 1;
+        let (pts, duration) = (packet.pkt.pts, packet.pkt.duration);
+        if pts != NO_PTS {
+            self.pts_range = Some(match self.pts_range {
+                Some((first, last, _)) if pts > last => (first, pts, duration),
+                Some((first, last, last_duration)) => (first.min(pts), last, last_duration),
+                None => (pts, pts, duration),
+            });
+        }
+    }
+
+    pub fn counts(&self, video_stream: &AVStream) -> VideoFrameCounts {
+        let time_base = video_stream.stream.time_base;
+        let video_metadata = get_video_metadata(video_stream);
+        let timestamps = self
+            .pts_range
+            .filter(|_| video_metadata.framerate.numerator > 0 && time_base.den > 0)
+            .map(|(first, last, last_duration)| {
+                let span = (last - first) as f64 * time_base.num as f64 / time_base.den as f64;
+                // the last packet is shown for its duration, which is a single
+                // field if fields are coded separately
+                let last_duration = if last_duration > 0 {
+                    last_duration as f64 * time_base.num as f64 / time_base.den as f64
+                } else {
+                    video_metadata.duration(1)
+                };
+                video_metadata.frames(span + last_duration)
+            });
+        VideoFrameCounts {
+            packets: Some(self.packets),
+            timestamps,
+            ..VideoFrameCounts::default()
         }
     }
 }
@@ -49,6 +257,7 @@ impl SegmentDemuxStats {
 
 pub struct DemuxStats {
     pub segments: Vec<SegmentDemuxStats>,
+    pub cut_map: CutMap,
 }
 
 impl DemuxStats {
@@ -85,55 +294,7 @@ impl DemuxStats {
     }
 }
 
-// returns the number of frames to cut off the end
-fn adjust_gap(tail: &ThdDecodePacket, head: &ThdDecodePacket, overrun: &ThdOverrun) -> u32 {
-    let head_mono = &head.mono;
-    let tail_mono = &tail.mono;
-
-    let head_samples: Vec<i32> = head_mono.samples.iter().map(|f| f.value).collect();
-    let tail_samples: Vec<i32> = tail_mono.samples.iter().map(|f| f.value).collect();
-
-    let silence_threshold = 100i32;
-    let head_max = head_samples.iter().map(|n| n.abs()).max().unwrap_or(0);
-    let tail_max = tail_samples.iter().map(|n| n.abs()).max().unwrap_or(0);
-
-    let covariance = dsp::covariance(&head_samples, &tail_samples);
-    debug!("Frame covariance is {:.7}", covariance);
-
-    let adjust = if head_max < silence_threshold && tail_max < silence_threshold {
-        // We're dealing with a silent section here, which means the covariance
-        // value isn't going to be very significant. So we'll fall back to
-        // minimizing desync based on the overrun accumulator.
-        debug!(
-            "Both frames at segment boundary appear to only contain silent audio. Falling back to overrun correction. (head max: {}, tail max: {})", 
-            head_max,
-            tail_max
-        );
-
-        if overrun.samples() >= 20 {
-            info!("Deleted silent frame to correct for audio sync drift.");
-            1
-        } else {
-            0
-        }
-    } else {
-        if covariance > 0.95 {
-            info!("Deleted frame with duplicate audio content.");
-            1
-        } else {
-            debug!("No duplicate audio content at segment boundary.");
-            0
-        }
-    };
-
-    if adjust == 0 {
-        info!("Segment boundary is OK, no adjustment necessary.");
-    }
-
-    adjust
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ThdStreamInfo {
     pub index: i32,
     pub id: i32,
@@ -157,8 +318,8 @@ impl Display for ThdStreamInfo {
     }
 }
 
-pub fn thd_streams<'a, P: AsRef<Path>>(path: P) -> Result<Vec<ThdStreamInfo>, AVError> {
-    let mut avctx = AVFormatContext::open(&path)?;
+pub fn thd_streams(segment: &Segment) -> Result<Vec<ThdStreamInfo>, AVError> {
+    let mut avctx = segment.open()?;
     let streams = avctx.streams()?;
     let thd_streams: Vec<ThdStreamInfo> = streams
         .iter()
@@ -180,234 +341,1642 @@ pub fn thd_streams<'a, P: AsRef<Path>>(path: P) -> Result<Vec<ThdStreamInfo>, AV
     Ok(thd_streams)
 }
 
+/// Information about an audio stream of any codec, which can be cut along
+/// with a primary TrueHD stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioStreamInfo {
+    pub index: i32,
+    pub id: i32,
+    pub codec: AudioCodec,
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AudioCodec {
+    TrueHd,
+    Ac3,
+    Eac3,
+    Dts,
+    Other,
+}
+
+impl AudioCodec {
+    fn from_codec_id(id: ffmpeg4_ffi::sys::AVCodecID) -> AudioCodec {
+        match id {
+            ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_TRUEHD => AudioCodec::TrueHd,
+            ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_AC3 => AudioCodec::Ac3,
+            ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_EAC3 => AudioCodec::Eac3,
+            ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_DTS => AudioCodec::Dts,
+            _ => AudioCodec::Other,
+        }
+    }
+
+    /// The file extension that's commonly used for the raw elementary stream.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioCodec::TrueHd => "thd",
+            AudioCodec::Ac3 => "ac3",
+            AudioCodec::Eac3 => "eac3",
+            AudioCodec::Dts => "dts",
+            AudioCodec::Other => "bin",
+        }
+    }
+}
+
+impl Display for AudioStreamInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Audio stream: index: {}, id: {:#X}, language: {}, codec: {:?}",
+            self.index,
+            self.id,
+            self.language
+                .as_ref()
+                .map(|s| s.as_ref())
+                .unwrap_or("unknown"),
+            self.codec,
+        )
+    }
+}
+
+pub fn audio_streams(segment: &Segment) -> Result<Vec<AudioStreamInfo>, AVError> {
+    let mut avctx = segment.open()?;
+    let streams = avctx.streams()?;
+    Ok(streams
+        .iter()
+        .filter(|s| s.codec_type() == AVCodecType::Audio)
+        .map(|s| AudioStreamInfo {
+            index: s.stream.index,
+            id: s.stream.id,
+            codec: AudioCodec::from_codec_id(s.codec.id),
+            language: None,
+        })
+        .collect())
+}
+
 pub fn demux_thd<W: Write + Seek>(
     segments: &[Segment],
     options: &DemuxOptions,
-    mut out_writer: W,
+    out_writer: W,
 ) -> Result<DemuxStats, AVError> {
-    let mut stats: DemuxStats = DemuxStats {
-        segments: Vec::with_capacity(segments.len()),
+    let thd_stream_id = match options.thd_stream_id {
+        Some(id) => id,
+        None => thd_streams(segments.first().ok_or(DemuxErr::NoTrueHdStreamFound)?)?
+            .first()
+            .map(|s| s.id)
+            .ok_or(DemuxErr::NoTrueHdStreamFound)?,
     };
-    let mut previous_segment: Option<ThdSegment> = None;
+
+    let mut stats = demux_thd_streams(segments, options, vec![(thd_stream_id, out_writer)])?;
+    Ok(stats.remove(0))
+}
+
+/// Demuxes several TrueHD streams at once, reading every segment only once.
+/// `outputs` pairs the id of each TrueHD stream with the writer its data goes
+/// to. Segment boundaries are checked for each stream independently.
+pub fn demux_thd_streams<W: Write + Seek>(
+    segments: &[Segment],
+    options: &DemuxOptions,
+    outputs: Vec<(i32, W)>,
+) -> Result<Vec<DemuxStats>, AVError> {
+    let (stats, _) = demux_streams(segments, options, outputs, Vec::new(), false)?;
+    Ok(stats)
+}
+
+/// Runs the full demux logic for the given TrueHD streams, including every
+/// boundary decision, but doesn't write any audio.
+pub fn analyze_thd_streams(
+    segments: &[Segment],
+    options: &DemuxOptions,
+    stream_ids: &[i32],
+) -> Result<Vec<DemuxStats>, AVError> {
+    let outputs = stream_ids
+        .iter()
+        .map(|&id| (id, DiscardWriter::default()))
+        .collect();
+    let (stats, _) = demux_streams(segments, options, outputs, Vec::new(), true)?;
+    Ok(stats)
+}
+
+/// A writer that discards all data, but keeps track of its position, so that
+/// frames can be deleted by seeking back just like in a real output file.
+#[derive(Debug, Default)]
+struct DiscardWriter {
+    position: u64,
+    len: u64,
+}
+
+impl Write for DiscardWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DiscardWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => checked_offset(self.len, n),
+            SeekFrom::Current(n) => checked_offset(self.position, n),
+        };
+        match position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+/// Demuxes a primary TrueHD stream along with any number of secondary audio
+/// streams (TrueHD, AC-3 or DTS). Boundary decisions are only made for the
+/// primary stream. The resulting cut map is then applied to every secondary
+/// stream, so that all of them drift identically relative to the video.
+pub fn demux_with_cut_map<W: Write + Seek>(
+    segments: &[Segment],
+    options: &DemuxOptions,
+    primary: (i32, W),
+    secondaries: Vec<(i32, W)>,
+) -> Result<(DemuxStats, Vec<SecondaryDemuxStats>), AVError> {
+    let (mut stats, secondary_stats) =
+        demux_streams(segments, options, vec![primary], secondaries, false)?;
+    Ok((stats.remove(0), secondary_stats))
+}
+
+/// Runs a demux. With `analyze_only`, the writers discard their data, so
+/// segments that are cached completely don't need to be read.
+fn demux_streams<W: Write + Seek>(
+    segments: &[Segment],
+    options: &DemuxOptions,
+    outputs: Vec<(i32, W)>,
+    secondaries: Vec<(i32, W)>,
+    analyze_only: bool,
+) -> Result<(Vec<DemuxStats>, Vec<SecondaryDemuxStats>), AVError> {
+    // the decisions of a cut list are replayed for the stream they were made
+    // for, or for the first stream if the cut list doesn't say
+    let events = EventSink::new(options.observer.clone());
+    let replay_stream_id = options
+        .cut_list
+        .as_ref()
+        .and_then(|c| c.stream_id.or_else(|| outputs.first().map(|o| o.0)));
+    let mut demuxers: Vec<StreamDemuxer<W>> = outputs
+        .into_iter()
+        .map(|(stream_id, writer)| StreamDemuxer {
+            stream_id,
+            writer,
+            stats: DemuxStats {
+                segments: Vec::with_capacity(segments.len()),
+                cut_map: CutMap::default(),
+            },
+            previous_segment: None,
+            skip_segment: false,
+            cached_tail: None,
+            events: events.clone(),
+            replay: if replay_stream_id == Some(stream_id) {
+                options.cut_list.clone()
+            } else {
+                None
+            },
+        })
+        .collect();
+    let mut followers: Vec<SecondaryDemuxer<W>> = secondaries
+        .into_iter()
+        .map(|(stream_id, writer)| SecondaryDemuxer {
+            writer,
+            stats: SecondaryDemuxStats {
+                stream_id,
+                frames: 0,
+                frames_removed: 0,
+                duration_removed: 0f64,
+            },
+            tail: Vec::with_capacity(MAX_SECONDARY_TAIL),
+        })
+        .collect();
 
     debug!("Using demux options: {:?}", options);
+    info!("Using the {} boundary strategy.", options.strategy.name());
+
+    let mut overrides_used = vec![false; options.overrides.len()];
+    let mut first_segment = 0;
+    if let Some(ref checkpoint) = options.resume {
+        let clips: Vec<String> = segments.iter().map(|s| s.clip_name()).collect();
+        let stream_ids: Vec<i32> = demuxers.iter().map(|d| d.stream_id).collect();
+        let secondary_ids: Vec<i32> = followers.iter().map(|f| f.stats.stream_id).collect();
+        checkpoint.validate(&clips, &stream_ids, &secondary_ids)?;
+
+        for (demuxer, state) in demuxers.iter_mut().zip(checkpoint.streams.iter()) {
+            demuxer.restore(state.clone())?;
+        }
+        for (follower, state) in followers.iter_mut().zip(checkpoint.secondaries.iter()) {
+            follower.restore(state.clone())?;
+        }
+        if checkpoint.overrides_used.len() == overrides_used.len() {
+            overrides_used = checkpoint.overrides_used.clone();
+        }
+        first_segment = checkpoint.next_segment;
+        info!("Resuming at file {}/{}.", first_segment + 1, segments.len());
+    }
+
+    // look up what earlier runs learned about the remaining segments
+    let stream_ids: Vec<i32> = demuxers.iter().map(|d| d.stream_id).collect();
+    let cache_keys: Vec<Option<FileKey>> = segments
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match options.cache {
+            Some(ref cache) if i >= first_segment => cache.key(s),
+            _ => None,
+        })
+        .collect();
+    let mut cached: Vec<Vec<Option<StreamAnalysis>>> = cache_keys
+        .iter()
+        .map(|key| {
+            stream_ids
+                .iter()
+                .map(|&id| match (options.cache.as_ref(), key) {
+                    (Some(cache), Some(key)) => cache.load(key, id),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    // segments that don't need to be read at all, because nothing is written
+    // and every stream's frame counts and tail are known
+    let fully_cached: Vec<bool> = cached
+        .iter()
+        .map(|entries| {
+            analyze_only
+                && followers.is_empty()
+                && !demuxers.is_empty()
+                && demuxers.iter().zip(entries).all(|(d, e)| {
+                    e.as_ref()
+                        .is_some_and(|e| d.replay.is_some() || e.last_group_of_frames.is_some())
+                })
+        })
+        .collect();
+    // segments that are analyzed from their tail, because their number of
+    // video frames is known and nothing is written
+    let tail_only: Vec<bool> = segments
+        .iter()
+        .zip(fully_cached.iter())
+        .map(|(s, &c)| analyze_only && followers.is_empty() && !c && s.video_frames.is_some())
+        .collect();
+    let skipped: Vec<bool> = fully_cached
+        .iter()
+        .zip(tail_only.iter())
+        .map(|(&c, &t)| c || t)
+        .collect();
+
+    // read segments ahead of the demuxer, if requested
+    let mut scanner = if options.jobs > 1 {
+        debug!("Reading up to {} segments in parallel.", options.jobs);
+        Some(SegmentScanner::start(
+            segments.to_vec(),
+            skipped.clone(),
+            first_segment,
+            demuxers
+                .iter()
+                .map(|d| ScanStream {
+                    stream_id: d.stream_id,
+                    decode: d.replay.is_none(),
+                })
+                .collect(),
+            followers.iter().map(|f| f.stats.stream_id).collect(),
+            options.jobs,
+            options.cancellation.clone(),
+        ))
+    } else {
+        None
+    };
 
     let file_count = segments.len();
-    for (i, segment) in segments.iter().enumerate() {
+    let mut progress = ProgressTracker::new(segments, events.clone());
+    for (i, segment) in segments.iter().enumerate().skip(first_segment) {
+        options.cancellation.check()?;
+        progress.start_segment(i);
         info!(
             "Processing file {}/{} ('{}') ...",
             i + 1,
             file_count,
             segment.path.display()
         );
+        events.emit(DemuxEvent::SegmentStarted {
+            segment: i,
+            segment_count: file_count,
+            clip: segment.clip_name(),
+            path: segment.path.clone(),
+        });
+        let entries = std::mem::take(&mut cached[i]);
+        if fully_cached[i] {
+            debug!("Using the cached analysis instead of reading the segment.");
+        }
+        let mut scan = match scanner {
+            Some(ref mut scanner) if skipped[i] => {
+                scanner.skip(i);
+                None
+            }
+            Some(ref mut scanner) => Some(scanner.next(i)?),
+            None => None,
+        };
+
+        let mut heads: Vec<Vec<ThdDecodePacket>> = demuxers.iter().map(|_| Vec::new()).collect();
 
         // check overrun and apply sync, if necessary
-        if let Some(ref mut prev) = previous_segment {
+        if i > 0 {
             info!("Checking segment file gap.");
 
-            // match audio data
-            // `tail` is the last TrueHD frame of the previous segment
-            // `head` is the first TrueHD frame of the current segment
-            let (tail, tail_header) = { prev.last_group_of_frames.last().unwrap() };
-            let head = {
-                // open the current segment and decode only the first TrueHD frame
-                let mut avctx = AVFormatContext::open(&segment.path)?;
-                let streams = avctx.streams()?;
-                let decoded_head_frame = streams
+            // decode only the first few TrueHD frames of every stream we're
+            // demuxing, unless we're replaying the stream's decisions from a
+            // cut list, or the frames are cached. If the segment has been
+            // read ahead, those frames have already been decoded.
+            if let Some(ref mut scan) = scan {
+                heads = scan
+                    .streams
+                    .iter_mut()
+                    .map(|s| {
+                        s.as_mut()
+                            .map_or_else(Vec::new, |s| std::mem::take(&mut s.head))
+                    })
+                    .collect();
+            } else {
+                for (head, entry) in heads.iter_mut().zip(entries.iter()) {
+                    if let Some(cached_head) = entry.as_ref().and_then(|e| e.head.as_ref()) {
+                        *head = packets_from_cache(cached_head);
+                    }
+                }
+                let missing: Vec<bool> = demuxers
                     .iter()
-                    .find(|&s| s.codec.id == ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_TRUEHD)
-                    .and_then(|thd_stream| decode_head_frame(&mut avctx, thd_stream).ok()?);
-                match decoded_head_frame {
-                    Some(decoded_frame) => decoded_frame,
+                    .zip(heads.iter())
+                    .map(|(d, head)| d.replay.is_none() && head.is_empty())
+                    .collect();
+                if missing.iter().any(|&m| m) {
+                    let mut avctx = segment.open()?;
+                    let streams = avctx.streams()?;
+                    let head_streams: Vec<Option<&AVStream>> = demuxers
+                        .iter()
+                        .zip(missing.iter())
+                        .map(|(d, &m)| {
+                            if m {
+                                find_thd_stream(&streams, d.stream_id)
+                            } else {
+                                None
+                            }
+                        })
+                        .collect();
+                    let decoded =
+                        decode_head_frames(&mut avctx, &head_streams, MAX_OVERLAP_FRAMES)?;
+                    for ((head, decoded), m) in heads.iter_mut().zip(decoded).zip(missing) {
+                        if m {
+                            *head = decoded;
+                        }
+                    }
+                }
+            }
+
+            let clip = segment.clip_name();
+            let boundary_override = options
+                .overrides
+                .iter()
+                .enumerate()
+                .find(|(_, o)| o.matches(i, &clip));
+            if let Some((j, o)) = boundary_override {
+                info!("Using override for {}: {:?}", o.boundary, o.action);
+                overrides_used[j] = true;
+            }
+            let override_action = boundary_override.map(|(_, o)| o.action);
+
+            for (demuxer, head) in demuxers.iter_mut().zip(heads.iter()) {
+                demuxer.skip_segment = false;
+                if demuxer.replay.is_some() {
+                    demuxer.replay_gap(i, segment, override_action)?;
+                    continue;
+                }
+
+                if head.is_empty() {
+                    events.warn(format!(
+                        "No TrueHD frames of stream {:#X} found in {}. This segment will be skipped for that stream.",
+                        demuxer.stream_id,
+                        segment.path.display()
+                    ));
+                    demuxer.skip_segment = true;
+                } else {
+                    demuxer.check_gap(i, segment, head, options, override_action)?;
+                }
+            }
+
+            // secondary streams follow the cut map of the primary stream
+            if let Some(primary) = demuxers.first() {
+                let target = primary.stats.cut_map.duration_until(i);
+                for follower in followers.iter_mut() {
+                    follower.apply_cut_map(target)?;
+                }
+            }
+        }
+
+        let mut tail_analyzed = false;
+        let (mut video_frame_counts, mut thd_segments) = match scan {
+            None if fully_cached[i] => {
+                replay_cached_segment(&entries, &mut demuxers, &mut progress)?
+            }
+            None if tail_only[i] => {
+                match analyze_segment_tail(segment, &mut demuxers, &mut progress)? {
+                    Some(analysis) => {
+                        tail_analyzed = true;
+                        analysis
+                    }
                     None => {
-                        warn!(
-                            "No TrueHD frames found in {}. This segment will be skipped.",
-                            segment.path.display()
-                        );
-                        continue;
+                        debug!("Reading the whole segment instead of its tail.");
+                        copy_segment(
+                            segment,
+                            &mut demuxers,
+                            &mut followers,
+                            &mut progress,
+                            &options.cancellation,
+                        )?
                     }
                 }
+            }
+            Some(scan) => {
+                write_scanned_segment(scan, &mut demuxers, &mut followers, &mut progress)?
+            }
+            None => {
+                // cached tails don't need to be decoded again
+                for (demuxer, entry) in demuxers.iter_mut().zip(entries.iter()) {
+                    demuxer.cached_tail = entry
+                        .as_ref()
+                        .and_then(|e| e.last_group_of_frames.as_ref())
+                        .map(|tail| packets_from_cache(tail));
+                }
+                copy_segment(
+                    segment,
+                    &mut demuxers,
+                    &mut followers,
+                    &mut progress,
+                    &options.cancellation,
+                )?
+            }
+        };
+        video_frame_counts.expected = segment.video_frames.map(|n| n as u32);
+        // what the playlist or clip info says about the video takes precedence
+        // over what FFmpeg found
+        if let Some(probed) = thd_segments.first().map(|s| s.video_metadata) {
+            let video_metadata = segment.video_metadata.unwrap_or(probed);
+            if video_metadata != probed {
+                debug!("Using {:?} instead of {:?}.", video_metadata, probed);
+            }
+            video_frame_counts = video_frame_counts.normalized(&probed, &video_metadata);
+            for thd_segment in thd_segments.iter_mut() {
+                thd_segment.video_metadata = video_metadata;
+            }
+        }
+        let num_video_frames = checked_video_frames(i, segment, &video_frame_counts, &events);
+        for thd_segment in thd_segments.iter_mut() {
+            thd_segment.num_video_frames = num_video_frames;
+        }
+
+        // the video frames of a tail-analyzed segment weren't counted, so
+        // there's nothing to cache that the clip info doesn't already say
+        let cache_key = cache_keys[i].as_ref().filter(|_| !tail_analyzed);
+        if let (Some(cache), Some(key)) = (options.cache.as_ref(), cache_key) {
+            update_cache(
+                cache,
+                key,
+                &demuxers,
+                &heads,
+                &entries,
+                video_frame_counts.counted().unwrap_or(num_video_frames),
+                &thd_segments,
+            );
+        }
+
+        let video_frames = thd_segments.first().map_or(0, |s| s.num_video_frames);
+        let thd_frames = thd_segments.iter().map(|s| s.num_frames).collect();
+        let active = demuxers.iter_mut().filter(|d| !d.skip_segment);
+        for (demuxer, thd_segment) in active.zip(thd_segments) {
+            demuxer.push_segment(thd_segment, video_frame_counts);
+        }
+        events.emit(DemuxEvent::SegmentFinished {
+            segment: i,
+            clip: segment.clip_name(),
+            video_frames,
+            thd_frames,
+        });
+
+        if let Some(ref path) = options.checkpoint {
+            let checkpoint = Checkpoint {
+                version: CHECKPOINT_VERSION,
+                clips: segments.iter().map(|s| s.clip_name()).collect(),
+                next_segment: i + 1,
+                streams: demuxers
+                    .iter_mut()
+                    .map(|d| d.checkpoint())
+                    .collect::<Result<_, _>>()?,
+                secondaries: followers
+                    .iter_mut()
+                    .map(|f| f.checkpoint())
+                    .collect::<Result<_, _>>()?,
+                overrides_used: overrides_used.clone(),
             };
+            checkpoint.save(path)?;
+            debug!("Wrote checkpoint to {}.", path.display());
+        }
+    }
 
-            trace!("tail: {}", tail.original);
-            trace!("head: {}", head.original);
+    for (o, _) in options
+        .overrides
+        .iter()
+        .zip(overrides_used)
+        .filter(|(_, used)| !used)
+    {
+        events.warn(format!(
+            "Override for {} didn't match any segment boundary.",
+            o.boundary
+        ));
+    }
+
+    for demuxer in demuxers.iter() {
+        debug!(
+            "Overrun of stream {:#X} is now {} samples.",
+            demuxer.stream_id,
+            demuxer.stats.overrun().samples()
+        );
+    }
+    info!("Done!");
 
-            trace!("tail MONO: {}", tail.mono);
-            trace!("head MONO: {}", head.mono);
+    Ok((
+        demuxers.into_iter().map(|d| d.stats).collect(),
+        followers.into_iter().map(|f| f.stats).collect(),
+    ))
+}
+
+/// Per-stream state of a demux run.
+struct StreamDemuxer<W: Write + Seek> {
+    stream_id: i32,
+    writer: W,
+    stats: DemuxStats,
+    previous_segment: Option<ThdSegment>,
+    skip_segment: bool,
+    // the decoded tail of the current segment, if it's cached
+    cached_tail: Option<Vec<ThdDecodePacket>>,
+    // the cut list whose decisions are replayed for this stream
+    replay: Option<CutList>,
+    events: EventSink,
+}
+
+impl<W: Write + Seek> StreamDemuxer<W> {
+    fn check_gap(
+        &mut self,
+        segment_index: usize,
+        segment: &Segment,
+        head: &[ThdDecodePacket],
+        options: &DemuxOptions,
+        override_action: Option<OverrideAction>,
+    ) -> Result<(), AVError> {
+        let overrun = self.stats.overrun();
+        let (mut adjustment, thd_metadata) = {
+            let prev = match self.previous_segment {
+                Some(ref prev) => prev,
+                None => return Ok(()),
+            };
+
+            // match audio data
+            // `tail` are the last TrueHD frames of the previous segment
+            // `head` are the first TrueHD frames of the current segment
+            let tail = &prev.last_group_of_frames;
+            if tail.is_empty() {
+                if override_action.is_some() {
+                    return self.replay_gap(segment_index, segment, override_action);
+                }
+                // keep the boundary, but still record the decision so that an
+                // exported cut list has one for every boundary
+                self.push_cut(BoundaryCut {
+                    segment: segment_index,
+                    clip: segment.clip_name(),
+                    frames: 0,
+                    duration: 0f64,
+                    covariance: None,
+                    silence: false,
+                    overrun: overrun.samples(),
+                    overlap: None,
+                    reason: CutReason::NoAudio,
+                });
+                return Ok(());
+            }
+
+            if let (Some(tail), Some(head)) = (tail.last(), head.first()) {
+                trace!("tail: {}", tail.original);
+                trace!("head: {}", head.original);
+
+                trace!("tail MONO: {}", tail.mono);
+                trace!("head MONO: {}", head.mono);
+            }
 
-            let overrun = stats.overrun();
             debug!(
                 "Uncorrected overrun would be {} samples.",
                 overrun.samples()
             );
 
-            let n_delete = adjust_gap(&tail, &head, &overrun);
-            if n_delete > 0 {
-                // delete the most recently written frame by moving the file
-                // cursor back
-                let _ = &out_writer
-                    .seek(SeekFrom::Current(-(tail_header.length as i64)))
-                    .unwrap();
-                let mut prev_stats = stats.segments.last_mut().unwrap();
-                prev_stats.thd_frames -= 1;
+            let thresholds = options.thresholds.resolve(&prev.thd_metadata);
+            debug!("Using thresholds: {}", thresholds);
+
+            (
+                options.strategy.decide(tail, head, &overrun, &thresholds),
+                prev.thd_metadata,
+            )
+        };
+
+        if let Some(action) = override_action {
+            info!(
+                "Overriding boundary decision: deleting {} frame(s) instead of {}.",
+                action.frames(),
+                adjustment.frames
+            );
+            adjustment.frames = action.frames();
+            adjustment.reason = CutReason::Override;
+        }
+
+        let frames = self.delete_frames(adjustment.frames)?;
+        self.push_cut(BoundaryCut {
+            segment: segment_index,
+            clip: segment.clip_name(),
+            frames,
+            duration: thd_metadata.duration(frames),
+            covariance: adjustment.covariance,
+            silence: adjustment.silence,
+            overrun: overrun.samples(),
+            overlap: adjustment.overlap,
+            reason: adjustment.reason,
+        });
+
+        debug!("Overrun is now {} samples.", self.stats.overrun().samples());
+        Ok(())
+    }
+
+    // applies the decision the cut list recorded for this segment boundary,
+    // or the given override
+    fn replay_gap(
+        &mut self,
+        segment_index: usize,
+        segment: &Segment,
+        override_action: Option<OverrideAction>,
+    ) -> Result<(), AVError> {
+        let recorded = self.replay.as_ref().and_then(|c| c.boundary(segment_index));
+        let cut = match (recorded, override_action) {
+            (_, Some(action)) => BoundaryCut {
+                segment: segment_index,
+                clip: segment.clip_name(),
+                frames: action.frames(),
+                duration: 0f64,
+                covariance: recorded.and_then(|c| c.covariance),
+                silence: recorded.is_some_and(|c| c.silence),
+                overrun: self.stats.overrun().samples(),
+                overlap: recorded.and_then(|c| c.overlap),
+                reason: CutReason::Override,
+            },
+            (Some(cut), None) => cut.clone(),
+            (None, None) => {
+                return Err(OtherErr::InvalidCutList(format!(
+                    "no decision recorded for the boundary before segment {} ({})",
+                    segment_index,
+                    segment.clip_name()
+                ))
+                .into())
             }
+        };
+
+        let clip = segment.clip_name();
+        if cut.clip != clip {
+            self.events.warn(format!(
+                "Cut list expects clip {} before segment {}, but found {}.",
+                cut.clip, segment_index, clip
+            ));
+        }
+
+        if cut.reason != CutReason::Override {
+            info!("Replaying cut list decision. {}", cut);
         }
+        let thd_metadata = match self.previous_segment {
+            Some(ref prev) => prev.thd_metadata,
+            None => return Ok(()),
+        };
+        let frames = self.delete_frames(cut.frames)?;
+        self.push_cut(BoundaryCut {
+            frames,
+            duration: thd_metadata.duration(frames),
+            clip,
+            ..cut
+        });
 
-        debug!("Overrun is now {} samples.", stats.overrun().samples());
-        debug!("Copying TrueHD stream to output ...");
-        let mut avctx = AVFormatContext::open(&segment.path)?;
-        let streams = avctx.streams()?;
+        debug!("Overrun is now {} samples.", self.stats.overrun().samples());
+        Ok(())
+    }
 
-        let video_stream = streams
-            .iter()
-            .find(|&s| s.codec_type() == AVCodecType::Video)
-            .ok_or(DemuxErr::NoVideoStreamFound)?;
-        let thd_stream = streams
+    fn push_cut(&mut self, cut: BoundaryCut) {
+        self.events.emit(DemuxEvent::BoundaryDecided {
+            stream_id: self.stream_id,
+            cut: cut.clone(),
+        });
+        self.stats.cut_map.push(cut);
+    }
+
+    // deletes up to `n` of the most recently written frames by moving the
+    // file cursor back, and returns the number of deleted frames. Frames with
+    // a major sync are never deleted.
+    fn delete_frames(&mut self, n: u32) -> Result<u32, AVError> {
+        let prev = match self.previous_segment {
+            Some(ref prev) => prev,
+            None => return Ok(0),
+        };
+
+        let deletable: Vec<&ThdFrameHeader> = prev
+            .last_group_headers
             .iter()
-            .find(|&s| {
-                s.codec.id == ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_TRUEHD
-                    && options.thd_stream_id.map_or(true, |i| s.stream.id == i)
-            })
-            .ok_or(DemuxErr::NoTrueHdStreamFound)?;
+            .rev()
+            .take(n as usize)
+            .take_while(|h| !h.has_major_sync)
+            .collect();
+        if deletable.len() < n as usize {
+            self.events.warn(format!(
+                "Only {} of {} frames can be deleted without removing a major sync.",
+                deletable.len(),
+                n
+            ));
+        }
 
-        let segment = write_thd_segment(
-            &segment,
-            &mut avctx,
-            video_stream,
-            thd_stream,
-            &mut out_writer,
-        )?;
+        let deleted = deletable.len() as u32;
+        if deleted > 0 {
+            let length: usize = deletable.iter().map(|h| h.length).sum();
+            self.writer.seek(SeekFrom::Current(-(length as i64)))?;
+            let prev_stats = self.stats.segments.last_mut().unwrap();
+            prev_stats.thd_frames -= deleted;
+        }
+
+        Ok(deleted)
+    }
+
+    // flushes the output and returns the state needed to continue the run
+    // after the current segment
+    fn checkpoint(&mut self) -> Result<StreamCheckpoint, AVError> {
+        self.writer.flush()?;
+        Ok(StreamCheckpoint {
+            stream_id: self.stream_id,
+            output_len: self.writer.stream_position()?,
+            segments: self.stats.segments.clone(),
+            boundaries: self.stats.cut_map.boundaries.clone(),
+            previous_segment: self.previous_segment.as_ref().map(|s| s.into()),
+        })
+    }
+
+    fn restore(&mut self, state: StreamCheckpoint) -> Result<(), AVError> {
+        self.writer.seek(SeekFrom::Start(state.output_len))?;
+        self.stats.segments = state.segments;
+        self.stats.cut_map.boundaries = state.boundaries;
+        self.previous_segment = state.previous_segment.map(|s| s.into());
+        Ok(())
+    }
 
+    fn push_segment(&mut self, segment: ThdSegment, video_frame_counts: VideoFrameCounts) {
         let segment_overrun = ThdOverrun {
             acc: segment.overrun(),
         };
         debug!("Segment overrun is {} samples.", segment_overrun.samples());
-        stats.segments.push(SegmentDemuxStats {
+        self.stats.segments.push(SegmentDemuxStats {
             video_frames: segment.num_video_frames,
             thd_frames_original: segment.num_frames,
             thd_frames: segment.num_frames,
             thd_metadata: segment.thd_metadata,
             video_metadata: segment.video_metadata,
+            video_frame_counts,
         });
 
-        previous_segment = Some(segment);
+        self.previous_segment = Some(segment);
     }
+}
 
-    debug!("Overrun is now {} samples.", stats.overrun().samples());
-    info!("Done!");
+/// The number of packets we remember at the end of every segment of a
+/// secondary stream. Even for DTS, this covers more than a second of audio.
+const MAX_SECONDARY_TAIL: usize = 128;
+
+/// Statistics of a secondary audio stream that was cut according to the cut
+/// map of a primary TrueHD stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecondaryDemuxStats {
+    pub stream_id: i32,
+    pub frames: u32,
+    pub frames_removed: u32,
+    /// Duration of all removed frames, in seconds.
+    pub duration_removed: f64,
+}
+
+impl SecondaryDemuxStats {
+    /// How far this stream is off from the primary stream's cut map, in
+    /// seconds. Positive values mean less audio was removed than from the
+    /// primary stream.
+    pub fn drift(&self, cut_map: &CutMap) -> f64 {
+        cut_map.total_duration() - self.duration_removed
+    }
+}
 
-    return Ok(stats);
+/// Per-stream state of a secondary stream that follows a cut map.
+struct SecondaryDemuxer<W: Write + Seek> {
+    writer: W,
+    stats: SecondaryDemuxStats,
+    // the packets most recently written to the output
+    tail: Vec<WrittenPacket>,
 }
 
-fn write_thd_segment<W: Write + Seek>(
+impl<W: Write + Seek> SecondaryDemuxer<W> {
+    fn apply_cut_map(&mut self, target: f64) -> Result<(), AVError> {
+        let debt = target - self.stats.duration_removed;
+        let n_remove = packets_to_remove(&self.tail, debt);
+        if n_remove == 0 {
+            return Ok(());
+        }
+
+        let removed = self.tail.split_off(self.tail.len() - n_remove);
+        let length: usize = removed.iter().map(|p| p.length).sum();
+        let duration: f64 = removed.iter().map(|p| p.duration).sum();
+        self.writer.seek(SeekFrom::Current(-(length as i64)))?;
+
+        self.stats.frames -= n_remove as u32;
+        self.stats.frames_removed += n_remove as u32;
+        self.stats.duration_removed += duration;
+        debug!(
+            "Removed {} frame(s) ({:.7} seconds) from secondary stream {:#X}.",
+            n_remove, duration, self.stats.stream_id
+        );
+
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<SecondaryCheckpoint, AVError> {
+        self.writer.flush()?;
+        Ok(SecondaryCheckpoint {
+            output_len: self.writer.stream_position()?,
+            stats: self.stats.clone(),
+            tail: self.tail.clone(),
+        })
+    }
+
+    fn restore(&mut self, state: SecondaryCheckpoint) -> Result<(), AVError> {
+        self.writer.seek(SeekFrom::Start(state.output_len))?;
+        self.stats = state.stats;
+        self.tail = state.tail;
+        Ok(())
+    }
+
+    fn write_packet(&mut self, stream: &AVStream, packet: &AVPacket) -> Result<(), AVError> {
+        let pkt_slice = packet.as_slice();
+        self.writer.write_all(pkt_slice)?;
+
+        let removable = stream.codec.id != ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_TRUEHD
+            || ThdFrameHeader::from_bytes(pkt_slice).is_some_and(|h| !h.has_major_sync);
+        if self.tail.len() == MAX_SECONDARY_TAIL {
+            self.tail.remove(0);
+        }
+        self.tail.push(WrittenPacket {
+            length: pkt_slice.len(),
+            duration: packet_duration(stream, packet),
+            removable,
+        });
+        self.stats.frames += 1;
+
+        Ok(())
+    }
+
+    // writes the packets of a segment that was read ahead by the scanner
+    fn write_scanned(&mut self, data: &[u8], packets: &[WrittenPacket]) -> Result<(), AVError> {
+        self.writer.write_all(data)?;
+        for packet in packets {
+            if self.tail.len() == MAX_SECONDARY_TAIL {
+                self.tail.remove(0);
+            }
+            self.tail.push(*packet);
+        }
+        self.stats.frames += packets.len() as u32;
+
+        Ok(())
+    }
+}
+
+// returns the duration of the given audio packet, in seconds
+pub(super) fn packet_duration(stream: &AVStream, packet: &AVPacket) -> f64 {
+    let params = stream.codec_params;
+    if params.frame_size > 0 && params.sample_rate > 0 {
+        params.frame_size as f64 / params.sample_rate as f64
+    } else {
+        let time_base = stream.stream.time_base;
+        (packet.pkt.duration * time_base.num as i64) as f64 / time_base.den as f64
+    }
+}
+
+pub(super) fn find_thd_stream<'a, 'b>(
+    streams: &'a [AVStream<'b>],
+    stream_id: i32,
+) -> Option<&'a AVStream<'b>> {
+    streams.iter().find(|&s| {
+        s.codec.id == ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_TRUEHD && s.stream.id == stream_id
+    })
+}
+
+/// Reads one segment and copies it to the writers of every TrueHD stream that
+/// isn't skipped, and of every secondary stream. Returns the number of video
+/// frames that were counted, and one `ThdSegment` per TrueHD stream that isn't
+/// skipped.
+fn copy_segment<W: Write + Seek>(
     segment: &Segment,
-    format_context: &mut AVFormatContext,
-    video_stream: &AVStream,
-    thd_stream: &AVStream,
-    mut thd_writer: W,
-) -> Result<ThdSegment, AVError> {
-    let (video_metadata, thd_metadata) = (
-        get_video_metadata(video_stream),
-        get_thd_metadata(thd_stream),
+    demuxers: &mut [StreamDemuxer<W>],
+    followers: &mut [SecondaryDemuxer<W>],
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<(VideoFrameCounts, Vec<ThdSegment>), AVError> {
+    debug!("Copying TrueHD stream to output ...");
+    let (mut avctx, access_units) = segment.open_counting()?;
+    let streams = avctx.streams()?;
+
+    let video_stream = streams
+        .iter()
+        .find(|&s| s.codec_type() == AVCodecType::Video)
+        .ok_or(DemuxErr::NoVideoStreamFound)?;
+
+    let mut active: Vec<(&AVStream, &mut StreamDemuxer<W>)> = Vec::new();
+    for demuxer in demuxers.iter_mut().filter(|d| !d.skip_segment) {
+        let thd_stream =
+            find_thd_stream(&streams, demuxer.stream_id).ok_or(DemuxErr::NoTrueHdStreamFound)?;
+        active.push((thd_stream, demuxer));
+    }
+
+    let mut active_followers: Vec<(&AVStream, &mut SecondaryDemuxer<W>)> = Vec::new();
+    for follower in followers.iter_mut() {
+        let stream = streams
+            .iter()
+            .find(|s| {
+                s.codec_type() == AVCodecType::Audio && s.stream.id == follower.stats.stream_id
+            })
+            .ok_or(DemuxErr::AudioStreamNotFound(follower.stats.stream_id))?;
+        active_followers.push((stream, follower));
+    }
+
+    let (video_frame_counts, thd_segments) = write_thd_segment(
+        &mut avctx,
+        video_stream,
+        &mut active,
+        &mut active_followers,
+        progress,
+        cancellation,
+    )?;
+    Ok((
+        video_frame_counts.with_access_units(&access_units),
+        thd_segments,
+    ))
+}
+
+/// Writes a segment that was read ahead by the scanner. Does the same as
+/// `copy_segment`, without reading the segment again.
+fn write_scanned_segment<W: Write + Seek>(
+    scan: SegmentScan,
+    demuxers: &mut [StreamDemuxer<W>],
+    followers: &mut [SecondaryDemuxer<W>],
+    progress: &mut ProgressTracker,
+) -> Result<(VideoFrameCounts, Vec<ThdSegment>), AVError> {
+    let num_video_frames = scan.video_frame_counts.counted().unwrap_or_default();
+
+    let mut thd_segments = Vec::with_capacity(demuxers.len());
+    for (demuxer, stream) in demuxers.iter_mut().zip(scan.streams) {
+        if demuxer.skip_segment {
+            continue;
+        }
+        let stream = stream.ok_or(DemuxErr::NoTrueHdStreamFound)?;
+        demuxer.writer.write_all(&stream.data)?;
+        debug!(
+            "{} TrueHD frames of stream {:#X} have been written to the output.",
+            stream.num_frames, demuxer.stream_id
+        );
+        thd_segments.push(ThdSegment {
+            last_group_of_frames: stream.last_group_of_frames,
+            last_group_headers: stream.last_group_headers,
+            num_frames: stream.num_frames,
+            num_video_frames,
+            video_metadata: scan.video_metadata,
+            thd_metadata: stream.thd_metadata,
+        });
+    }
+
+    for (follower, secondary) in followers.iter_mut().zip(scan.secondaries) {
+        follower.write_scanned(&secondary.data, &secondary.packets)?;
+    }
+
+    progress.finish_segment(
+        thd_segments
+            .first()
+            .map_or(0f64, |s| s.thd_metadata.duration(s.num_frames)),
     );
+    Ok((scan.video_frame_counts, thd_segments))
+}
 
-    // set up progress bar
-    let start_time = thd_stream.stream.start_time;
-    let duration = thd_stream.stream.duration as u64;
-    let progress = ProgressBar::new(duration);
-    progress.set_draw_delta(duration / 100);
-    progress.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] [{wide_bar}] {eta}")
-            .progress_chars("#>-"),
+/// Passes over a segment using only its cached analysis. Nothing is written,
+/// but the writers are moved forward by the length of the last group of
+/// frames, which is as far as frames are ever deleted at the next boundary.
+fn replay_cached_segment<W: Write + Seek>(
+    entries: &[Option<StreamAnalysis>],
+    demuxers: &mut [StreamDemuxer<W>],
+    progress: &mut ProgressTracker,
+) -> Result<(VideoFrameCounts, Vec<ThdSegment>), AVError> {
+    let mut video_frame_counts = VideoFrameCounts::default();
+    let mut thd_segments = Vec::with_capacity(demuxers.len());
+    for (demuxer, entry) in demuxers.iter_mut().zip(entries) {
+        let entry = entry.as_ref().ok_or(DemuxErr::NoTrueHdStreamFound)?;
+        video_frame_counts.cached = Some(entry.num_video_frames);
+        if demuxer.skip_segment {
+            continue;
+        }
+
+        let length: usize = entry.last_group_headers.iter().map(|h| h.length).sum();
+        demuxer.writer.seek(SeekFrom::Current(length as i64))?;
+        thd_segments.push(ThdSegment {
+            last_group_of_frames: entry
+                .last_group_of_frames
+                .as_ref()
+                .map_or_else(Vec::new, |tail| packets_from_cache(tail)),
+            last_group_headers: entry.last_group_headers.clone(),
+            num_frames: entry.num_frames,
+            num_video_frames: entry.num_video_frames,
+            video_metadata: entry.video_metadata,
+            thd_metadata: entry.thd_metadata,
+        });
+    }
+
+    progress.finish_segment(
+        thd_segments
+            .first()
+            .map_or(0f64, |s| s.thd_metadata.duration(s.num_frames)),
     );
+    Ok((video_frame_counts, thd_segments))
+}
 
-    debug!("Video: {:?}, Audio: {:?}", video_metadata, thd_metadata);
+/// Stores the analysis of every stream that wasn't skipped in the current
+/// segment, unless the cache already knows everything this run learned.
+fn update_cache<W: Write + Seek>(
+    cache: &AnalysisCache,
+    key: &FileKey,
+    demuxers: &[StreamDemuxer<W>],
+    heads: &[Vec<ThdDecodePacket>],
+    entries: &[Option<StreamAnalysis>],
+    num_video_frames: u32,
+    thd_segments: &[ThdSegment],
+) {
+    let active = demuxers
+        .iter()
+        .zip(heads.iter().zip(entries))
+        .filter(|(d, _)| !d.skip_segment);
+    for ((demuxer, (head, entry)), thd_segment) in active.zip(thd_segments) {
+        let (cached_head, cached_tail) = match entry {
+            Some(e) => (e.head.as_ref(), e.last_group_of_frames.as_ref()),
+            None => (None, None),
+        };
+        let new_head = cached_head.is_none() && !head.is_empty();
+        let new_tail = cached_tail.is_none() && !thd_segment.last_group_of_frames.is_empty();
+        if entry.is_some() && !new_head && !new_tail {
+            continue;
+        }
 
-    let (mut num_frames, mut num_video_frames) = (0u32, 0u32);
+        cache.store(
+            key,
+            &StreamAnalysis {
+                version: CACHE_VERSION,
+                stream_id: demuxer.stream_id,
+                num_video_frames,
+                video_metadata: thd_segment.video_metadata,
+                num_frames: thd_segment.num_frames,
+                thd_metadata: thd_segment.thd_metadata,
+                head: if new_head {
+                    Some(packets_to_cache(head))
+                } else {
+                    cached_head.cloned()
+                },
+                last_group_of_frames: if new_tail {
+                    Some(packets_to_cache(&thd_segment.last_group_of_frames))
+                } else {
+                    cached_tail.cloned()
+                },
+                last_group_headers: thd_segment.last_group_headers.clone(),
+            },
+        );
+    }
+}
 
-    // keeps the packets of the most recent group of frames
-    // (all frames "belonging" to one major sync)
-    let mut packet_queue: Vec<AVPacket> = Vec::with_capacity(128);
+/// How far before the end of a segment its tail is read, in seconds. The
+/// window is doubled until it contains a major sync of every stream.
+const TAIL_SECONDS: f64 = 2.0;
+const MAX_TAIL_ATTEMPTS: u32 = 5;
+
+/// How far the TrueHD duration derived from timestamps may be off the expected
+/// video duration, in seconds, before the segment is read completely instead.
+const MAX_TAIL_DURATION_DIFF: f64 = 1.0;
+
+// `AV_NOPTS_VALUE`, which bindgen can't translate
+const NO_PTS: i64 = i64::MIN;
+
+/// Analyzes a segment by reading only its start and its tail. The number of
+/// TrueHD frames is derived from the timestamps of the first and the last
+/// frame, and the number of video frames is the one the playlist or clip info
+/// expects. Nothing is written, but the writers are moved forward by the
+/// length of the last group of frames, like `replay_cached_segment` does.
+/// Returns `None` if the segment has to be read completely instead.
+fn analyze_segment_tail<W: Write + Seek>(
+    segment: &Segment,
+    demuxers: &mut [StreamDemuxer<W>],
+    progress: &mut ProgressTracker,
+) -> Result<Option<(VideoFrameCounts, Vec<ThdSegment>)>, AVError> {
+    let num_video_frames = match segment.video_frames {
+        Some(n) => n as u32,
+        None => return Ok(None),
+    };
+    let mut avctx = segment.open()?;
+    let streams = avctx.streams()?;
+    let video_stream = streams
+        .iter()
+        .find(|&s| s.codec_type() == AVCodecType::Video)
+        .ok_or(DemuxErr::NoVideoStreamFound)?;
+    let video_metadata = segment
+        .video_metadata
+        .unwrap_or_else(|| get_video_metadata(video_stream));
+    let mut thd_streams = Vec::new();
+    for demuxer in demuxers.iter().filter(|d| !d.skip_segment) {
+        thd_streams.push(
+            find_thd_stream(&streams, demuxer.stream_id).ok_or(DemuxErr::NoTrueHdStreamFound)?,
+        );
+    }
+    if thd_streams.is_empty() {
+        return Ok(None);
+    }
 
-    // keeps track of the frame headers of the last group of frames we've written
-    let mut frame_queue: Vec<ThdFrameHeader> = Vec::with_capacity(128);
+    let starts = match first_timestamps(&mut avctx, &thd_streams)? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let mut seconds = TAIL_SECONDS;
+    let mut tails = None;
+    for _ in 0..MAX_TAIL_ATTEMPTS {
+        let seeked = match segment.tail_position(seconds) {
+            Some(position) => avctx.seek_to_byte(position).is_ok(),
+            None => tail_timestamp(thd_streams[0], seconds)
+                .is_some_and(|t| avctx.seek_to_timestamp(thd_streams[0], t).is_ok()),
+        };
+        if !seeked {
+            debug!("Failed to seek to the tail of the segment.");
+            return Ok(None);
+        }
+        tails = read_tails(&mut avctx, &thd_streams)?;
+        if tails.is_some() {
+            break;
+        }
+        seconds *= 2.0;
+    }
+    let tails = match tails {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+
+    let video_duration = video_metadata.duration(num_video_frames);
+    let mut thd_segments = Vec::with_capacity(thd_streams.len());
+    for ((stream, start), tail) in thd_streams.iter().zip(starts).zip(tails.iter()) {
+        let thd_metadata = get_thd_metadata(stream);
+        let num_frames = match tail.num_frames(stream, start, &thd_metadata) {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let thd_duration = thd_metadata.duration(num_frames);
+        if (thd_duration - video_duration).abs() > MAX_TAIL_DURATION_DIFF {
+            debug!(
+                "The timestamps of stream {:#X} span {} seconds, but the video is {} seconds long.",
+                stream.stream.id, thd_duration, video_duration
+            );
+            return Ok(None);
+        }
+        thd_segments.push((num_frames, thd_metadata));
+    }
+
+    debug!("Analyzed the segment from its tail.");
+    let active = demuxers.iter_mut().filter(|d| !d.skip_segment);
+    let mut result = Vec::with_capacity(thd_segments.len());
+    for (((demuxer, stream), tail), (num_frames, thd_metadata)) in
+        active.zip(thd_streams).zip(tails).zip(thd_segments)
+    {
+        let length: usize = tail.headers.iter().map(|h| h.length).sum();
+        demuxer.writer.seek(SeekFrom::Current(length as i64))?;
+        let decoded_frames = if demuxer.replay.is_none() {
+            truehd::decode(stream, tail.packets)?
+        } else {
+            Vec::new()
+        };
+        result.push(ThdSegment {
+            last_group_of_frames: decoded_frames,
+            last_group_headers: tail.headers,
+            num_frames,
+            num_video_frames,
+            video_metadata,
+            thd_metadata,
+        });
+    }
+
+    progress.finish_segment(
+        result
+            .first()
+            .map_or(0f64, |s| s.thd_metadata.duration(s.num_frames)),
+    );
+    Ok(Some((VideoFrameCounts::default(), result)))
+}
+
+/// Where the frames of a TrueHD stream start: the first timestamp, and the
+/// number of frames before it that didn't have one.
+type StreamStart = (i64, u32);
+
+// reads the start of the file until every stream had a frame with a timestamp
+fn first_timestamps(
+    format_context: &mut AVFormatContext,
+    streams: &[&AVStream],
+) -> Result<Option<Vec<StreamStart>>, AVError> {
+    let mut starts: Vec<Option<StreamStart>> = streams.iter().map(|_| None).collect();
+    let mut frames_without_pts = vec![0u32; streams.len()];
+    while starts.iter().any(|s| s.is_none()) {
+        let packet = match format_context.read_frame() {
+            Ok(p) => p,
+            Err(_) => return Ok(None),
+        };
+        let i = match streams.iter().position(|s| packet.of_stream(s)) {
+            Some(i) => i,
+            None => continue,
+        };
+        if starts[i].is_some() {
+            continue;
+        }
+        if packet.pkt.pts == NO_PTS {
+            frames_without_pts[i] += 1;
+        } else {
+            starts[i] = Some((packet.pkt.pts, frames_without_pts[i]));
+        }
+    }
+    Ok(starts.into_iter().collect())
+}
+
+/// The last group of frames of a TrueHD stream, read from the tail of a
+/// segment.
+struct StreamTail {
+    packets: Vec<AVPacket>,
+    headers: Vec<ThdFrameHeader>,
+    // the last timestamp, and the number of frames after it
+    last_pts: Option<(i64, u32)>,
+}
+
+impl StreamTail {
+    // the number of frames from the given start to the end of the tail
+    fn num_frames(
+        &self,
+        stream: &AVStream,
+        start: StreamStart,
+        metadata: &ThdMetadata,
+    ) -> Option<u32> {
+        let (first_pts, frames_before) = start;
+        let (last_pts, frames_after) = self.last_pts?;
+        let time_base = stream.stream.time_base;
+        let span = (last_pts - first_pts) as f64 * time_base.num as f64 / time_base.den as f64;
+        let frames = span * metadata.sample_rate as f64 / metadata.frame_size as f64;
+        if frames < 0f64 {
+            return None;
+        }
+        Some(frames.round() as u32 + frames_before + frames_after + 1)
+    }
+}
+
+// reads the file to its end, and returns the last group of frames of every
+// stream, or `None` if a stream had no major sync
+fn read_tails(
+    format_context: &mut AVFormatContext,
+    streams: &[&AVStream],
+) -> Result<Option<Vec<StreamTail>>, AVError> {
+    let mut tails: Vec<StreamTail> = streams
+        .iter()
+        .map(|_| StreamTail {
+            packets: Vec::with_capacity(128),
+            headers: Vec::with_capacity(128),
+            last_pts: None,
+        })
+        .collect();
+    let mut synced = vec![false; streams.len()];
+
+    while let Ok(packet) = format_context.read_frame() {
+        let i = match streams.iter().position(|s| packet.of_stream(s)) {
+            Some(i) => i,
+            None => continue,
+        };
+        let tail = &mut tails[i];
+        tail.last_pts = match (packet.pkt.pts, tail.last_pts) {
+            (NO_PTS, Some((pts, after))) => Some((pts, after + 1)),
+            (NO_PTS, None) => None,
+            (pts, _) => Some((pts, 0)),
+        };
+
+        let header = match ThdFrameHeader::from_bytes(packet.as_slice()) {
+            Some(h) => h,
+            None => continue,
+        };
+        if header.has_major_sync {
+            synced[i] = true;
+            tail.packets.truncate(0);
+            tail.headers.truncate(0);
+        }
+        if synced[i] {
+            tail.packets.push(packet);
+            tail.headers.push(header);
+        }
+    }
+
+    if synced.iter().all(|&s| s) {
+        Ok(Some(tails))
+    } else {
+        Ok(None)
+    }
+}
+
+// the timestamp the given number of seconds before the end of the stream,
+// based on the duration FFmpeg estimated while probing the file
+fn tail_timestamp(stream: &AVStream, seconds: f64) -> Option<i64> {
+    let (start, duration) = (stream.stream.start_time, stream.stream.duration);
+    if start == NO_PTS || duration <= 0 {
+        return None;
+    }
+    let time_base = stream.stream.time_base;
+    let offset = (seconds * time_base.den as f64 / time_base.num as f64) as i64;
+    Some(start + (duration - offset).max(0))
+}
+
+// ffmpeg sometimes has an issue with identifying the very first HEVC frame
+// of a stream, and timestamps can have gaps. So we cross-check the access
+// units in the transport stream, the number of packets, the span of the
+// timestamps, and what the MPLS or CLPI file says we _should_ have, and take
+// the expected count for calculating the overrun. The counted number is what
+// gets cached, since the same clip may be cut differently by other playlists.
+fn checked_video_frames(
+    segment_index: usize,
+    segment: &Segment,
+    counts: &VideoFrameCounts,
+    events: &EventSink,
+) -> u32 {
+    debug!("Video frames of segment {}: {}", segment_index + 1, counts);
+    if counts.disagree() {
+        events.warn(format!(
+            "The video frame counts of segment {} ('{}') disagree: {}. Using {} for calculating overrun.",
+            segment_index + 1,
+            segment.clip_name(),
+            counts,
+            if counts.expected.is_some() {
+                "the playlist's"
+            } else if counts.access_units.is_some() {
+                "the access units'"
+            } else {
+                "the timestamps'"
+            }
+        ));
+    }
+    counts
+        .expected
+        .or_else(|| counts.counted())
+        .unwrap_or_default()
+}
+
+/// Copies the given TrueHD streams and secondary audio streams of one segment
+/// to their writers, in a single pass over the file. Returns the number of
+/// video frames, and one `ThdSegment` per TrueHD stream, in the same order as
+/// `thd_outputs`.
+fn write_thd_segment<W: Write + Seek>(
+    format_context: &mut AVFormatContext,
+    video_stream: &AVStream,
+    thd_outputs: &mut [(&AVStream, &mut StreamDemuxer<W>)],
+    secondary_outputs: &mut [(&AVStream, &mut SecondaryDemuxer<W>)],
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<(VideoFrameCounts, Vec<ThdSegment>), AVError> {
+    let video_metadata = get_video_metadata(video_stream);
+
+    // progress within the segment is based on the first stream
+    let (start_time, time_base, duration) = thd_outputs
+        .first()
+        .map(|(s, _)| {
+            let time_base = s.stream.time_base;
+            let time_base = time_base.num as f64 / time_base.den as f64;
+            (
+                s.stream.start_time,
+                time_base,
+                s.stream.duration as f64 * time_base,
+            )
+        })
+        .unwrap_or((0, 0f64, 0f64));
+
+    let mut video_frames = VideoFrameCounter::default();
+    let mut states: Vec<SegmentWriteState> = thd_outputs
+        .iter()
+        .map(|(thd_stream, demuxer)| {
+            let thd_metadata = get_thd_metadata(thd_stream);
+            debug!("Video: {:?}, Audio: {:?}", video_metadata, thd_metadata);
+            SegmentWriteState {
+                thd_metadata,
+                decode_tail: demuxer.replay.is_none() && demuxer.cached_tail.is_none(),
+                num_frames: 0,
+                packet_queue: Vec::with_capacity(128),
+                frame_queue: Vec::with_capacity(128),
+            }
+        })
+        .collect();
 
     // keeps track of the progress, for UI purposes
-    let mut prev_pts: i64 = start_time;
+    let mut position = 0f64;
 
     while let Ok(packet) = format_context.read_frame() {
+        cancellation.check()?;
+        progress.update(packet.pkt.pos, position, duration);
+
         if packet.of_stream(video_stream) {
-            // increase video frame counter (which we need in order to calculate
-            // the precise video duration)
-            num_video_frames += 1;
-        } else if packet.of_stream(thd_stream) {
-            // update progress
-            // `set_position` doesn't seem to respect the
-            // draw_delta we set for performance reasons
-            let pts = packet.pkt.pts;
-            let progress_delta = (pts - prev_pts).max(0);
-            progress.inc(progress_delta as u64);
-            prev_pts = pts;
+            // count the video frames (which we need in order to calculate the
+            // precise video duration)
+            video_frames.push(&packet);
+            continue;
+        }
+
+        let output = thd_outputs
+            .iter_mut()
+            .zip(states.iter_mut())
+            .enumerate()
+            .find(|(_, ((s, _), _))| packet.of_stream(s));
+        if let Some((i, ((_, demuxer), state))) = output {
+            if i == 0 {
+                position = (packet.pkt.pts - start_time).max(0) as f64 * time_base;
+            }
 
             // copy the TrueHD frame to the output
             let pkt_slice = packet.as_slice();
-            let _ = &thd_writer.write_all(&pkt_slice)?;
+            demuxer.writer.write_all(pkt_slice)?;
 
             // push frame header to queue (we want to remember the last
             // n frame headers we saw)
-            let frame = ThdFrameHeader::from_bytes(&pkt_slice).unwrap();
+            let frame = ThdFrameHeader::from_bytes(pkt_slice).unwrap();
             if frame.has_major_sync {
-                packet_queue.truncate(0);
-                frame_queue.truncate(0);
+                state.packet_queue.truncate(0);
+                state.frame_queue.truncate(0);
             }
-            packet_queue.push(packet);
-            frame_queue.push(frame);
+            state.packet_queue.push(packet);
+            state.frame_queue.push(frame);
 
             // increase THD frame counter
-            num_frames += 1;
+            state.num_frames += 1;
+            continue;
+        }
+
+        let secondary = secondary_outputs
+            .iter_mut()
+            .find(|(s, _)| packet.of_stream(s));
+        if let Some((stream, follower)) = secondary {
+            follower.write_packet(stream, &packet)?;
         }
     }
 
-    progress.finish_and_clear();
+    progress.finish_segment(duration);
 
-    trace!(
-        "Last group of frames is {} frames long.",
-        packet_queue.len()
-    );
-    debug!(
-        "{} TrueHD frames have been written to the output.",
-        num_frames
-    );
-    debug!("Encountered {} video frames.", num_video_frames);
-
-    // ffmpeg sometimes has an issue with identifying the very first HEVC frame
-    // of a stream, which leads to a wrong frame count. So we cross-check that
-    // count against what the MPLS file says we _should_ have, and take the
-    // corrected count for calculating the overrun.
-    let corrected_video_frames = if let Some(n) = segment.video_frames {
-        if n as u32 != num_video_frames {
-            warn!("Counted {} frames, but expected {}. Using the expected number for calculating overrun.", 
-            num_video_frames, n);
-        }
-        n as u32
-    } else {
-        num_video_frames
-    };
+    let video_frame_counts = video_frames.counts(video_stream);
+    let num_video_frames = video_frame_counts.counted().unwrap_or_default();
+    let mut thd_segments = Vec::with_capacity(states.len());
+    for ((thd_stream, demuxer), state) in thd_outputs.iter_mut().zip(states) {
+        trace!(
+            "Last group of frames is {} frames long.",
+            state.packet_queue.len()
+        );
+        debug!(
+            "{} TrueHD frames of stream {:#X} have been written to the output.",
+            state.num_frames, thd_stream.stream.id
+        );
 
-    let decoded_frames = truehd::decode(thd_stream, packet_queue)?
-        .into_iter()
-        .zip(frame_queue.into_iter())
-        .map(|(d, h)| (d, h))
-        .collect();
+        // the tail doesn't need to be decoded if we already know what to do
+        // at the next segment boundary, or if it's cached
+        let decoded_frames = if state.decode_tail {
+            truehd::decode(thd_stream, state.packet_queue)?
+        } else {
+            demuxer.cached_tail.take().unwrap_or_default()
+        };
 
-    Ok(ThdSegment {
-        last_group_of_frames: decoded_frames,
-        num_frames,
-        num_video_frames: corrected_video_frames,
-        video_metadata,
-        thd_metadata,
-    })
+        thd_segments.push(ThdSegment {
+            last_group_of_frames: decoded_frames,
+            last_group_headers: state.frame_queue,
+            num_frames: state.num_frames,
+            num_video_frames,
+            video_metadata,
+            thd_metadata: state.thd_metadata,
+        });
+    }
+
+    Ok((video_frame_counts, thd_segments))
+}
+
+/// The number of progress events emitted over a whole demux run, at most.
+const PROGRESS_STEPS: u64 = 1000;
+
+/// Keeps track of the progress across all segments of a demux run.
+struct ProgressTracker {
+    events: EventSink,
+    // the index of the segment that is being read
+    segment: usize,
+    segment_sizes: Vec<u64>,
+    total_bytes: u64,
+    // the size of all segments that have been read completely
+    done_bytes: u64,
+    // the overall byte position of the last progress event
+    reported_bytes: u64,
+}
+
+impl ProgressTracker {
+    fn new(segments: &[Segment], events: EventSink) -> ProgressTracker {
+        // sizes are only needed for progress events
+        let segment_sizes: Vec<u64> = if events.is_observed() {
+            segments
+                .iter()
+                .map(|s| s.metadata().map_or(0, |(len, _)| len))
+                .collect()
+        } else {
+            vec![0; segments.len()]
+        };
+        ProgressTracker {
+            events,
+            segment: 0,
+            total_bytes: segment_sizes.iter().sum(),
+            segment_sizes,
+            done_bytes: 0,
+            reported_bytes: 0,
+        }
+    }
+
+    fn start_segment(&mut self, segment: usize) {
+        self.segment = segment;
+        self.done_bytes = self.segment_sizes.iter().take(segment).sum();
+    }
+
+    // `pos` is the byte position within the current segment, or -1 if unknown
+    fn update(&mut self, pos: i64, position: f64, duration: f64) {
+        if pos < 0 || !self.events.is_observed() {
+            return;
+        }
+        let bytes = self.done_bytes + pos as u64;
+        if bytes >= self.reported_bytes + (self.total_bytes / PROGRESS_STEPS).max(1) {
+            self.report(bytes, position, duration);
+        }
+    }
+
+    fn finish_segment(&mut self, duration: f64) {
+        let size = self.segment_sizes.get(self.segment).copied().unwrap_or(0);
+        if self.events.is_observed() {
+            self.report(self.done_bytes + size, duration, duration);
+        }
+    }
+
+    fn report(&mut self, bytes: u64, position: f64, duration: f64) {
+        self.reported_bytes = bytes;
+        self.events.emit(DemuxEvent::Progress {
+            segment: self.segment,
+            bytes,
+            total_bytes: self.total_bytes,
+            position,
+            duration,
+        });
+    }
+}
+
+/// Bookkeeping for one TrueHD stream while its segment is being written.
+struct SegmentWriteState {
+    thd_metadata: ThdMetadata,
+    decode_tail: bool,
+    num_frames: u32,
+    // keeps the packets of the most recent group of frames
+    // (all frames "belonging" to one major sync)
+    packet_queue: Vec<AVPacket>,
+    // keeps track of the frame headers of the last group of frames we've written
+    frame_queue: Vec<ThdFrameHeader>,
 }
 
 // returns the very last decoded TrueHD frame of the given file and stream
@@ -467,23 +2036,97 @@ pub fn decode_head_frame(
     return Ok(None);
 }
 
-fn get_video_metadata(video_stream: &AVStream) -> VideoMetadata {
+// returns up to `max_frames` of the first decoded TrueHD frames of each of
+// the given streams, reading the file only as far as necessary
+pub fn decode_head_frames(
+    format_context: &mut AVFormatContext,
+    streams: &[Option<&AVStream>],
+    max_frames: usize,
+) -> Result<Vec<Vec<ThdDecodePacket>>, AVError> {
+    let mut av_frame = AVFrame::new();
+
+    let mut codec_contexts = Vec::with_capacity(streams.len());
+    for stream in streams {
+        codec_contexts.push(match stream {
+            Some(s) => {
+                let mut a_ctx = s.get_codec_context()?;
+                a_ctx.open(s)?;
+                Some(a_ctx)
+            }
+            None => None,
+        });
+    }
+
+    let mut heads: Vec<Vec<ThdDecodePacket>> = streams
+        .iter()
+        .map(|_| Vec::with_capacity(max_frames))
+        .collect();
+    let mut remaining = streams.iter().filter(|s| s.is_some()).count();
+
+    while remaining > 0 {
+        let packet = match format_context.read_frame() {
+            Ok(p) => p,
+            Err(_) => break,
+        };
+
+        let position = streams
+            .iter()
+            .position(|s| s.is_some_and(|s| packet.of_stream(s)));
+        if let Some(i) = position {
+            if heads[i].len() == max_frames {
+                continue;
+            }
+
+            let a_ctx = codec_contexts[i].as_mut().unwrap();
+            a_ctx.decode_frame(&packet, &mut av_frame)?;
+            let decoded_frame = DecodedThdFrame::from(&av_frame);
+            let mono_frame = truehd::downmix_mono(&av_frame, a_ctx)?;
+            let decoded_mono_frame = DecodedThdFrame::from(&mono_frame);
+
+            heads[i].push(ThdDecodePacket {
+                original: decoded_frame,
+                mono: decoded_mono_frame,
+            });
+            if heads[i].len() == max_frames {
+                remaining -= 1;
+            }
+        }
+    }
+
+    Ok(heads)
+}
+
+pub(super) fn get_video_metadata(video_stream: &AVStream) -> VideoMetadata {
     let frame_rate = video_stream.stream.r_frame_rate;
-    VideoMetadata {
-        framerate: Framerate {
+    let scan = match video_stream.codec_params.field_order {
+        ffmpeg4_ffi::sys::AVFieldOrder_AV_FIELD_TT
+        | ffmpeg4_ffi::sys::AVFieldOrder_AV_FIELD_BB
+        | ffmpeg4_ffi::sys::AVFieldOrder_AV_FIELD_TB
+        | ffmpeg4_ffi::sys::AVFieldOrder_AV_FIELD_BT => ScanType::Interlaced,
+        _ => ScanType::Progressive,
+    };
+    VideoMetadata::new(
+        Framerate {
             numerator: frame_rate.num,
             denominator: frame_rate.den,
         },
-    }
+        scan,
+    )
 }
 
 pub fn get_thd_metadata(thd_stream: &AVStream) -> ThdMetadata {
     let sample_rate = thd_stream.codec_params.sample_rate as u32;
     let frame_size = (sample_rate / 1200) as u8;
     let channels = thd_stream.codec_params.channels as u8;
+    // fall back to 24 bits if the parser couldn't tell
+    let bit_depth = match thd_stream.codec_params.bits_per_raw_sample {
+        n @ 1..=24 => n as u8,
+        _ => 24,
+    };
     ThdMetadata {
         sample_rate,
         frame_size,
         channels,
+        bit_depth,
     }
 }
@@ -54,6 +54,56 @@ pub fn covariance(x: &[i32], y: &[i32]) -> f32 {
     covariance.min(1.0)
 }
 
+/// Pearson correlation of `x` and `y`, or 0 if either of them is constant.
+fn correlation(x: &[i32], y: &[i32]) -> f32 {
+    let n = x.len().min(y.len());
+    if n == 0 {
+        return 0f32;
+    }
+    let (x, y) = (&x[..n], &y[..n]);
+    let x_mean = x.iter().map(|&v| v as f64).sum::<f64>() / n as f64;
+    let y_mean = y.iter().map(|&v| v as f64).sum::<f64>() / n as f64;
+
+    let (mut xy, mut xx, mut yy) = (0f64, 0f64, 0f64);
+    for (&x_i, &y_i) in x.iter().zip(y) {
+        let (dx, dy) = (x_i as f64 - x_mean, y_i as f64 - y_mean);
+        xy += dx * dy;
+        xx += dx * dx;
+        yy += dy * dy;
+    }
+
+    if xx == 0f64 || yy == 0f64 {
+        return 0f32;
+    }
+    (xy / (xx * yy).sqrt()).min(1.0) as f32
+}
+
+/// Normalized cross-correlation of `x` and `y` at every lag from `-max_lag`
+/// to `max_lag`. At lag `l`, `x[i]` is compared with `y[i + l]`, and the score
+/// is the correlation of the overlapping samples only. Lags at which fewer
+/// than `min_overlap` samples overlap are left out.
+pub fn cross_correlation(
+    x: &[i32],
+    y: &[i32],
+    max_lag: usize,
+    min_overlap: usize,
+) -> Vec<(i32, f32)> {
+    let max_lag = max_lag as i32;
+    (-max_lag..=max_lag)
+        .filter_map(|lag| {
+            let start = 0.max(-lag) as usize;
+            let end = (x.len() as i32).min(y.len() as i32 - lag);
+            if end - (start as i32) < min_overlap.max(1) as i32 {
+                return None;
+            }
+            let end = end as usize;
+            let y_start = (start as i32 + lag) as usize;
+            let y_end = (end as i32 + lag) as usize;
+            Some((lag, correlation(&x[start..end], &y[y_start..y_end])))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -74,4 +124,17 @@ mod tests {
         let covariance = super::covariance(&left, &right);
         assert_eq!(format!("{:.6}", covariance), "0.999977");
     }
+
+    #[test]
+    fn cross_correlation_peaks_at_offset() {
+        let signal: Vec<i32> = (0..120).map(|i| ((i * 37) % 101 - 50) * 1000).collect();
+        let x = &signal[40..80];
+        // `y` starts 7 samples earlier than `x`, so `x[i]` is `y[i + 7]`
+        let y = &signal[33..113];
+
+        let curve = super::cross_correlation(x, y, 40, 20);
+        let (_, score) = curve.iter().find(|(lag, _)| *lag == 7).unwrap();
+        assert!(*score > 0.999);
+        assert!(curve.iter().all(|(_, s)| s <= score));
+    }
 }
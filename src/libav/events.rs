@@ -0,0 +1,102 @@
+//! Events that report what a demux run is doing, for front-ends that need more
+//! than the log output.
+
+use super::BoundaryCut;
+use log::warn;
+use serde::Serialize;
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum DemuxEvent {
+    /// A segment is about to be read.
+    SegmentStarted {
+        segment: usize,
+        segment_count: usize,
+        clip: String,
+        path: PathBuf,
+    },
+    /// Progress across all segments of the demux run.
+    Progress {
+        segment: usize,
+        /// The number of bytes read, over all segments.
+        bytes: u64,
+        /// The size of all segments, in bytes.
+        total_bytes: u64,
+        /// How far into the current segment we are, in seconds, based on the
+        /// timestamps of the first TrueHD stream.
+        position: f64,
+        /// The duration of the current segment, in seconds.
+        duration: f64,
+    },
+    /// A decision has been made at a segment boundary.
+    BoundaryDecided {
+        stream_id: i32,
+        #[serde(flatten)]
+        cut: BoundaryCut,
+    },
+    /// A segment has been read completely.
+    SegmentFinished {
+        segment: usize,
+        clip: String,
+        video_frames: u32,
+        /// The number of TrueHD frames written, in the order of the demuxed
+        /// streams.
+        thd_frames: Vec<u32>,
+    },
+    /// Something looks off, but the demux run continues.
+    Warning { message: String },
+}
+
+/// Receives the events of a demux run. Events are delivered on the thread that
+/// runs the demuxer, so implementations should return quickly.
+pub trait DemuxObserver: Debug + Send + Sync {
+    fn on_event(&self, event: &DemuxEvent);
+}
+
+/// Hands events to the observer of a demux run, if there is one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventSink {
+    observer: Option<Arc<dyn DemuxObserver>>,
+}
+
+impl EventSink {
+    pub fn new(observer: Option<Arc<dyn DemuxObserver>>) -> EventSink {
+        EventSink { observer }
+    }
+
+    pub fn is_observed(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    pub fn emit(&self, event: DemuxEvent) {
+        if let Some(ref observer) = self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Logs the warning and hands it to the observer.
+    pub fn warn(&self, message: String) {
+        warn!("{}", message);
+        self.emit(DemuxEvent::Warning { message });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_tagged() {
+        let event = DemuxEvent::SegmentFinished {
+            segment: 2,
+            clip: String::from("00056"),
+            video_frames: 3587,
+            thd_frames: vec![179_530],
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"segment-finished","segment":2,"clip":"00056","video_frames":3587,"thd_frames":[179530]}"#
+        );
+    }
+}
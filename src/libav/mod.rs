@@ -1,7 +1,10 @@
 use ::log::*;
 use ffmpeg4_ffi::sys as ff;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+pub mod access_units;
+
 pub mod av_codec_context;
 pub use av_codec_context::AVCodecContext;
 
@@ -27,11 +30,31 @@ pub use truehd::{
     DecodedThdFrame, ThdDecodePacket, ThdFrameHeader, ThdOverrun, ThdSample, ThdSegment,
 };
 
+pub mod cancel;
+pub use cancel::CancellationToken;
+
+pub mod boundary;
+pub use boundary::{BoundaryDecision, BoundaryStrategy};
+
+pub mod cache;
+pub use cache::AnalysisCache;
+
+pub mod checkpoint;
+pub use checkpoint::Checkpoint;
+
+pub mod cut_map;
+pub use cut_map::{BoundaryCut, BoundaryOverride, BoundaryRef, CutMap, OverrideAction};
+
 pub mod demux;
 pub use demux::DemuxStats;
 
+pub mod events;
+pub use events::{DemuxEvent, DemuxObserver};
+
 pub mod dsp;
 
+pub mod scan;
+
 impl<'a> From<&AVFrame<'a>> for DecodedThdFrame {
     fn from(frame: &AVFrame<'a>) -> Self {
         let bytes = frame.as_slice();
@@ -42,15 +65,66 @@ impl<'a> From<&AVFrame<'a>> for DecodedThdFrame {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Framerate {
     pub numerator: i32,
     pub denominator: i32,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Framerate {
+    fn halved(self) -> Framerate {
+        if self.numerator % 2 == 0 {
+            Framerate {
+                numerator: self.numerator / 2,
+                ..self
+            }
+        } else {
+            Framerate {
+                denominator: self.denominator * 2,
+                ..self
+            }
+        }
+    }
+}
+
+/// Whether the frames of a video stream are full pictures, or pairs of fields.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanType {
+    #[default]
+    Progressive,
+    Interlaced,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VideoMetadata {
+    /// Frames per second. For interlaced video, that's half the field rate.
     pub framerate: Framerate,
+    #[serde(default)]
+    pub scan: ScanType,
+}
+
+impl VideoMetadata {
+    /// Interlaced video on Blu-ray has 25 or 29.97 frames per second, but
+    /// FFmpeg and some playlists give the field rate instead, which is
+    /// corrected here.
+    pub fn new(rate: Framerate, scan: ScanType) -> VideoMetadata {
+        let is_field_rate = rate.numerator as i64 > 30 * rate.denominator as i64;
+        VideoMetadata {
+            framerate: if scan == ScanType::Interlaced && is_field_rate {
+                rate.halved()
+            } else {
+                rate
+            },
+            scan,
+        }
+    }
+
+    /// The number of frames that are shown in the given number of seconds.
+    pub fn frames(&self, seconds: f64) -> u32 {
+        (seconds * self.framerate.numerator as f64 / self.framerate.denominator as f64).round()
+            as u32
+    }
 }
 
 pub trait MediaDuration {
@@ -59,7 +133,7 @@ pub trait MediaDuration {
 
 impl MediaDuration for VideoMetadata {
     fn duration(&self, frames: u32) -> f64 {
-        (frames as i32 * self.framerate.denominator) as f64 / self.framerate.numerator as f64
+        frames as f64 * self.framerate.denominator as f64 / self.framerate.numerator as f64
     }
 }
 
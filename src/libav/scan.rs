@@ -0,0 +1,391 @@
+//! Reads segments ahead of the demuxer in worker threads. Every worker copies
+//! the audio of one segment into memory and decodes its head and tail, so that
+//! the demuxer only has to make the boundary decisions and write the data in
+//! order.
+
+use super::{
+    boundary::MAX_OVERLAP_FRAMES,
+    cut_map::WrittenPacket,
+    demux::{
+        find_thd_stream, get_thd_metadata, get_video_metadata, packet_duration, VideoFrameCounter,
+        VideoFrameCounts,
+    },
+    truehd::{self, ThdMetadata},
+    AVCodecContext, AVCodecType, AVError, AVFrame, AVPacket, AVStream, CancellationToken,
+    DecodedThdFrame, DemuxErr, ThdDecodePacket, ThdFrameHeader, VideoMetadata,
+};
+use crate::Segment;
+use std::{
+    collections::BTreeMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// how often a worker waiting for the demuxer checks whether the run was cancelled
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A TrueHD stream to read from every segment.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanStream {
+    pub stream_id: i32,
+    /// Whether the head and tail of the stream need to be decoded. They don't
+    /// if the stream's boundary decisions are replayed from a cut list.
+    pub decode: bool,
+}
+
+/// The audio of a single segment, read by a worker.
+pub struct SegmentScan {
+    pub video_frame_counts: VideoFrameCounts,
+    pub video_metadata: VideoMetadata,
+    /// One entry per TrueHD stream, or `None` if the segment doesn't contain
+    /// the stream.
+    pub streams: Vec<Option<StreamScan>>,
+    pub secondaries: Vec<SecondaryScan>,
+}
+
+pub struct StreamScan {
+    /// All TrueHD frames of the segment, back to back.
+    pub data: Vec<u8>,
+    pub num_frames: u32,
+    pub thd_metadata: ThdMetadata,
+    /// The first few decoded frames, for checking the boundary before this
+    /// segment.
+    pub head: Vec<ThdDecodePacket>,
+    pub last_group_of_frames: Vec<ThdDecodePacket>,
+    pub last_group_headers: Vec<ThdFrameHeader>,
+}
+
+pub struct SecondaryScan {
+    pub data: Vec<u8>,
+    pub packets: Vec<WrittenPacket>,
+}
+
+/// Keeps the workers from reading more than `jobs` segments ahead of the
+/// demuxer, which bounds the memory used for buffered audio.
+struct ScanWindow {
+    // the index of the segment the demuxer waits for
+    next: Mutex<usize>,
+    changed: Condvar,
+    stop: CancellationToken,
+}
+
+type ScanResult = (usize, Result<SegmentScan, AVError>);
+
+pub struct SegmentScanner {
+    results: Receiver<ScanResult>,
+    pending: BTreeMap<usize, Result<SegmentScan, AVError>>,
+    window: Arc<ScanWindow>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl SegmentScanner {
+    /// Starts `jobs` workers that read the given segments, starting at
+    /// `first_segment`. Segments for which `skip` is set aren't read; the
+    /// demuxer must pass over them with `skip` instead of `next`.
+    pub fn start(
+        segments: Vec<Segment>,
+        skip: Vec<bool>,
+        first_segment: usize,
+        streams: Vec<ScanStream>,
+        secondary_ids: Vec<i32>,
+        jobs: usize,
+        cancellation: CancellationToken,
+    ) -> SegmentScanner {
+        let (sender, results) = channel();
+        let window = Arc::new(ScanWindow {
+            next: Mutex::new(first_segment),
+            changed: Condvar::new(),
+            stop: CancellationToken::new(),
+        });
+        let next_index = Arc::new(AtomicUsize::new(first_segment));
+        let segments = Arc::new(segments);
+        let skip = Arc::new(skip);
+        let streams = Arc::new(streams);
+        let secondary_ids = Arc::new(secondary_ids);
+
+        let workers = (0..jobs)
+            .map(|_| {
+                let worker = ScanWorker {
+                    segments: segments.clone(),
+                    skip: skip.clone(),
+                    streams: streams.clone(),
+                    secondary_ids: secondary_ids.clone(),
+                    next_index: next_index.clone(),
+                    window: window.clone(),
+                    jobs,
+                    cancellation: cancellation.clone(),
+                    sender: sender.clone(),
+                };
+                std::thread::spawn(move || worker.run())
+            })
+            .collect();
+
+        SegmentScanner {
+            results,
+            pending: BTreeMap::new(),
+            window,
+            workers,
+        }
+    }
+
+    /// Waits for the scan of the given segment. Segments must be requested in
+    /// order.
+    pub fn next(&mut self, index: usize) -> Result<SegmentScan, AVError> {
+        loop {
+            if let Some(result) = self.pending.remove(&index) {
+                self.advance(index);
+                return result;
+            }
+            match self.results.recv() {
+                Ok((i, result)) => {
+                    self.pending.insert(i, result);
+                }
+                Err(_) => return Err(DemuxErr::SegmentScanFailed(index).into()),
+            }
+        }
+    }
+
+    /// Passes over a segment that was skipped when the scanner was started.
+    pub fn skip(&mut self, index: usize) {
+        self.advance(index);
+    }
+
+    // lets the workers read up to `jobs` segments past the given one
+    fn advance(&mut self, index: usize) {
+        *self.window.next.lock().unwrap() = index + 1;
+        self.window.changed.notify_all();
+    }
+}
+
+impl Drop for SegmentScanner {
+    fn drop(&mut self) {
+        self.window.stop.cancel();
+        self.window.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct ScanWorker {
+    segments: Arc<Vec<Segment>>,
+    skip: Arc<Vec<bool>>,
+    streams: Arc<Vec<ScanStream>>,
+    secondary_ids: Arc<Vec<i32>>,
+    next_index: Arc<AtomicUsize>,
+    window: Arc<ScanWindow>,
+    jobs: usize,
+    cancellation: CancellationToken,
+    sender: Sender<ScanResult>,
+}
+
+impl ScanWorker {
+    fn run(self) {
+        loop {
+            let index = self.next_index.fetch_add(1, Ordering::SeqCst);
+            if index >= self.segments.len() || !self.wait_for_window(index) {
+                break;
+            }
+            if self.skip[index] {
+                continue;
+            }
+            // a panicking worker must still report its segment, or the
+            // demuxer would wait for it forever
+            let result = catch_unwind(AssertUnwindSafe(|| self.scan(index)))
+                .unwrap_or_else(|_| Err(DemuxErr::SegmentScanFailed(index).into()));
+            if self.sender.send((index, result)).is_err() {
+                break;
+            }
+        }
+    }
+
+    // blocks until the segment is within `jobs` segments of the demuxer.
+    // Returns false if the scanner has been stopped or the run cancelled.
+    fn wait_for_window(&self, index: usize) -> bool {
+        let stopped = || self.window.stop.is_cancelled() || self.cancellation.is_cancelled();
+        let mut next = self.window.next.lock().unwrap();
+        while !stopped() && index >= *next + self.jobs {
+            // cancelling the run doesn't wake the workers, so check it regularly
+            next = self
+                .window
+                .changed
+                .wait_timeout(next, CANCELLATION_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        !stopped()
+    }
+
+    fn scan(&self, index: usize) -> Result<SegmentScan, AVError> {
+        let (mut avctx, access_units) = self.segments[index].open_counting()?;
+        let streams = avctx.streams()?;
+
+        let video_stream = streams
+            .iter()
+            .find(|&s| s.codec_type() == AVCodecType::Video)
+            .ok_or(DemuxErr::NoVideoStreamFound)?;
+        let thd_streams: Vec<Option<&AVStream>> = self
+            .streams
+            .iter()
+            .map(|s| find_thd_stream(&streams, s.stream_id))
+            .collect();
+        let secondary_streams = self
+            .secondary_ids
+            .iter()
+            .map(|&id| {
+                streams
+                    .iter()
+                    .find(|s| s.codec_type() == AVCodecType::Audio && s.stream.id == id)
+                    .ok_or(DemuxErr::AudioStreamNotFound(id))
+            })
+            .collect::<Result<Vec<&AVStream>, _>>()?;
+
+        let mut states: Vec<Option<StreamScanState>> = thd_streams
+            .iter()
+            .zip(self.streams.iter())
+            .map(|(stream, s)| match stream {
+                // the head of the first segment is never checked
+                Some(stream) => {
+                    StreamScanState::new(stream, s.decode, s.decode && index > 0).map(Some)
+                }
+                None => Ok(None),
+            })
+            .collect::<Result<_, AVError>>()?;
+        let mut secondaries: Vec<SecondaryScan> = secondary_streams
+            .iter()
+            .map(|_| SecondaryScan {
+                data: Vec::new(),
+                packets: Vec::new(),
+            })
+            .collect();
+
+        let mut av_frame = AVFrame::new();
+        let mut video_frames = VideoFrameCounter::default();
+        while let Ok(packet) = avctx.read_frame() {
+            self.cancellation.check()?;
+            self.window.stop.check()?;
+
+            if packet.of_stream(video_stream) {
+                video_frames.push(&packet);
+                continue;
+            }
+
+            let position = thd_streams
+                .iter()
+                .position(|s| s.is_some_and(|s| packet.of_stream(s)));
+            if let Some(state) = position.and_then(|i| states[i].as_mut()) {
+                state.push(packet, &mut av_frame)?;
+                continue;
+            }
+
+            let position = secondary_streams.iter().position(|s| packet.of_stream(s));
+            if let Some(i) = position {
+                let stream = secondary_streams[i];
+                let pkt_slice = packet.as_slice();
+                secondaries[i].data.extend_from_slice(pkt_slice);
+                secondaries[i].packets.push(WrittenPacket {
+                    length: pkt_slice.len(),
+                    duration: packet_duration(stream, &packet),
+                    removable: stream.codec.id != ffmpeg4_ffi::sys::AVCodecID_AV_CODEC_ID_TRUEHD
+                        || ThdFrameHeader::from_bytes(pkt_slice).is_some_and(|h| !h.has_major_sync),
+                });
+            }
+        }
+
+        let mut scans = Vec::with_capacity(states.len());
+        for (state, stream) in states.into_iter().zip(thd_streams) {
+            scans.push(match (state, stream) {
+                (Some(state), Some(stream)) => Some(state.finish(stream)?),
+                _ => None,
+            });
+        }
+
+        Ok(SegmentScan {
+            video_frame_counts: video_frames
+                .counts(video_stream)
+                .with_access_units(&access_units),
+            video_metadata: get_video_metadata(video_stream),
+            streams: scans,
+            secondaries,
+        })
+    }
+}
+
+/// Bookkeeping for one TrueHD stream while its segment is being scanned.
+struct StreamScanState<'a> {
+    scan: StreamScan,
+    decode_tail: bool,
+    // decodes the head of the segment, if it's needed
+    head_context: Option<AVCodecContext<'a>>,
+    // the packets of the most recent group of frames
+    packet_queue: Vec<AVPacket>,
+}
+
+impl<'a> StreamScanState<'a> {
+    fn new(
+        stream: &'a AVStream,
+        decode_tail: bool,
+        decode_head: bool,
+    ) -> Result<StreamScanState<'a>, AVError> {
+        let head_context = if decode_head {
+            let mut a_ctx = stream.get_codec_context()?;
+            a_ctx.open(stream)?;
+            Some(a_ctx)
+        } else {
+            None
+        };
+        Ok(StreamScanState {
+            scan: StreamScan {
+                data: Vec::new(),
+                num_frames: 0,
+                thd_metadata: get_thd_metadata(stream),
+                head: Vec::with_capacity(MAX_OVERLAP_FRAMES),
+                last_group_of_frames: Vec::new(),
+                last_group_headers: Vec::with_capacity(128),
+            },
+            decode_tail,
+            head_context,
+            packet_queue: Vec::with_capacity(128),
+        })
+    }
+
+    fn push(&mut self, packet: AVPacket, av_frame: &mut AVFrame) -> Result<(), AVError> {
+        let pkt_slice = packet.as_slice();
+        self.scan.data.extend_from_slice(pkt_slice);
+
+        if self.scan.head.len() < MAX_OVERLAP_FRAMES {
+            if let Some(ref mut a_ctx) = self.head_context {
+                a_ctx.decode_frame(&packet, av_frame)?;
+                let mono_frame = truehd::downmix_mono(av_frame, a_ctx)?;
+                self.scan.head.push(ThdDecodePacket {
+                    original: DecodedThdFrame::from(&*av_frame),
+                    mono: DecodedThdFrame::from(&mono_frame),
+                });
+            }
+        }
+
+        let frame = ThdFrameHeader::from_bytes(pkt_slice).unwrap();
+        if frame.has_major_sync {
+            self.packet_queue.truncate(0);
+            self.scan.last_group_headers.truncate(0);
+        }
+        self.packet_queue.push(packet);
+        self.scan.last_group_headers.push(frame);
+        self.scan.num_frames += 1;
+        Ok(())
+    }
+
+    fn finish(self, stream: &AVStream) -> Result<StreamScan, AVError> {
+        let mut scan = self.scan;
+        if self.decode_tail {
+            scan.last_group_of_frames = truehd::decode(stream, self.packet_queue)?;
+        }
+        Ok(scan)
+    }
+}
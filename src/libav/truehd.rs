@@ -2,6 +2,7 @@ use super::{
     AVCodecContext, AVError, AVFrame, AVPacket, AVStream, MediaDuration, SwrContext, SwrOptions,
     VideoMetadata,
 };
+use serde::{Deserialize, Serialize};
 use std::{
     convert::TryInto,
     fmt::Display,
@@ -66,6 +67,7 @@ pub fn downmix_mono<'a>(
 
 /// A very light-weight header that only contains a length and a flag of whether
 /// the encoded frame contains a major sync header.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct ThdFrameHeader {
     pub length: usize,
     pub has_major_sync: bool,
@@ -117,11 +119,14 @@ impl ThdSample {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct ThdMetadata {
     pub channels: u8,
     pub sample_rate: u32,
     pub frame_size: u8,
+    /// The bit depth of the source audio. Decoded samples are always on a
+    /// 24-bit scale, regardless of this.
+    pub bit_depth: u8,
 }
 
 #[derive(Debug)]
@@ -133,12 +138,7 @@ pub struct DecodedThdFrame {
 impl Display for DecodedThdFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Format: {}", self.metadata)?;
-        for i in 0..self.metadata.channels {
-            let samples: Vec<i32> = self
-                .samples
-                .iter()
-                .filter_map(|f| if f.channel == i { Some(f.value) } else { None })
-                .collect();
+        for (i, samples) in self.channel_samples().iter().enumerate() {
             writeln!(f, "ch {}, samples: {:?}", i, samples)?;
         }
         Ok(())
@@ -171,13 +171,32 @@ impl DecodedThdFrame {
                 channels,
                 sample_rate,
                 frame_size: (sample_rate / 1200) as u8,
+                // the decoder only tells us the sample format, not the
+                // source's bit depth
+                bit_depth: 24,
             },
         }
     }
+
+    /// Returns the samples of every channel, in channel order.
+    pub fn channel_samples(&self) -> Vec<Vec<i32>> {
+        (0..self.metadata.channels)
+            .map(|i| {
+                self.samples
+                    .iter()
+                    .filter_map(|f| if f.channel == i { Some(f.value) } else { None })
+                    .collect()
+            })
+            .collect()
+    }
 }
 
 pub struct ThdSegment {
-    pub last_group_of_frames: Vec<(ThdDecodePacket, ThdFrameHeader)>,
+    /// The decoded frames of the last group of frames (all frames "belonging"
+    /// to the last major sync). Empty if the segment wasn't decoded.
+    pub last_group_of_frames: Vec<ThdDecodePacket>,
+    /// The headers of the last group of frames.
+    pub last_group_headers: Vec<ThdFrameHeader>,
     pub num_frames: u32,
     pub num_video_frames: u32,
     pub thd_metadata: ThdMetadata,
@@ -1,17 +1,54 @@
 use anyhow::Context;
-use clap::{crate_version, App, Arg, ArgGroup, ArgSettings};
-use libav::{demux::ThdStreamInfo, truehd::ThdMetadata, MediaDuration};
+use clap::{crate_version, App, Arg, ArgGroup, ArgMatches, ArgSettings};
+use disc::{clip_info::ClipInfo, Disc, PlaylistCandidate, Rejection, Title};
+use libav::{
+    access_units::{AccessUnitCounter, CountingReader},
+    boundary::{self, BoundaryStrategy, ThresholdOptions},
+    cut_map::{BoundaryOverride, BoundaryRef, CutList, CutReason, OverrideAction},
+    demux::{AudioCodec, AudioStreamInfo, DemuxOptions, ThdStreamInfo},
+    truehd::ThdMetadata,
+    AVError, AVFormatContext, AnalysisCache, CancellationToken, Checkpoint, DemuxObserver,
+    Framerate, ScanType, VideoMetadata,
+};
 use log::*;
 use mpls::{Mpls, PlayItem};
 use num_format::{Locale, ToFormattedString};
+use report::{
+    AngleReport, DemuxReport, DemuxSummary, FrameCountReport, OutputFormat, PlaylistListReport,
+    PlaylistReport, SecondaryReport, StreamReport,
+};
 use simplelog::*;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::{
-    io::BufWriter,
+    io::{self, BufReader, BufWriter, Read},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
+use udf::UdfImage;
 
+mod disc;
 pub mod libav;
+mod progress;
+mod report;
+mod udf;
+
+/// Returns a token that the first Ctrl-C cancels, so demuxing can stop
+/// cleanly. The second Ctrl-C exits right away.
+fn cancel_on_ctrl_c() -> anyhow::Result<CancellationToken> {
+    let cancellation = CancellationToken::new();
+    {
+        let cancellation = cancellation.clone();
+        ctrlc::set_handler(move || {
+            if cancellation.is_cancelled() {
+                std::process::exit(130);
+            }
+            cancellation.cancel();
+        })
+        .context("Failed to set up the Ctrl-C handler.")?;
+    }
+    Ok(cancellation)
+}
 
 fn main() -> anyhow::Result<()> {
     let args = App::new("TrueHD Demuxer")
@@ -24,12 +61,7 @@ fn main() -> anyhow::Result<()> {
                 .subcommand(
                     App::new("playlist")
                         .about("Demux from a blu-ray playlist file.")
-                        .arg(
-                            Arg::with_name("playlist")
-                                .about("Sets the path to the playlist file (.mpls).")
-                                .value_name("PLAYLIST")
-                                .required(true),
-                        )
+                        .args(playlist_args())
                         .arg(
                             Arg::with_name("output")
                                 .long_about("Sets the output TrueHD file. If omitted, playlist info will be printed instead.")
@@ -39,72 +71,29 @@ fn main() -> anyhow::Result<()> {
                                 .takes_value(true)
                                 .required(false),
                         )
+                        .args(demux_args()),
+                )
+                .subcommand(
+                    App::new("disc")
+                        .about("Demux the main feature of a blu-ray disc.")
+                        .long_about("Demux the main feature of a blu-ray disc. Every playlist of the disc is read, and the best main feature candidate is demuxed, as listed by the list command.")
+                        .args(disc_args())
                         .arg(
-                            Arg::with_name("stream-idx")
-                            .about("Sets the index of the TrueHD stream to demux.")
-                            .long("stream")
-                            .required(false)
-                            .takes_value(true)
-                            .validator(|s| {
-                                s.parse::<i32>()
-                                    .map_err(|_| String::from("Must be a number."))
-                            }),
+                            Arg::with_name("output")
+                                .long_about("Sets the output TrueHD file. If omitted, the info of the selected playlist will be printed instead.")
+                                .short('o')
+                                .long("output")
+                                .value_name("OUTPUT")
+                                .takes_value(true)
+                                .required(false),
                         )
-                        .arg(
-                            Arg::with_name("angle")
-                                .about("Sets the playlist angle index, starting at 1.")
-                                .long("angle")
-                                .default_value("1")
-                                .value_name("ANGLE")
-                                .required(false)
-                                .validator(|s| {
-                                    s.parse::<i32>()
-                                        .map_err(|_| String::from("Must be a number."))
-                                }),
-                        ),
+                        .args(demux_args()),
                 )
                 .subcommand(
                     App::new("segments")
                         .about("Demux from blu-ray media files.")
-                        .arg(
-                            Arg::with_name("segment-list")
-                                .about("Sets the comma-separated list of TrueHD segments.")
-                                .short('l')
-                                .long("segment-list")
-                                .group("segment-list-group")
-                                .takes_value(true)
-                                .min_values(2)
-                                .value_delimiter(",")
-                                .value_name("SEGMENT-LIST"),
-                        )
-                        .arg(
-                            Arg::with_name("segment-files")
-                                .about("Sets the list of file names, separated by +.")
-                                .long("segment-files")
-                                .value_delimiter("+")
-                                .value_name("SEGMENT-LIST")
-                                .group("segment-list-group")
-                                .takes_value(true),
-                        )
-                        .arg(
-                            Arg::with_name("stream-dir")
-                                .about("Sets the directory that contains the m2ts source files.")
-                                .requires("segment-list-group")
-                                .value_name("DIRECTORY")
-                                .short('s')
-                                .long("stream-dir"),
-                        )
-                        .arg(
-                            Arg::with_name("stream-idx")
-                            .about("Sets the index of the TrueHD stream to demux.")
-                            .long("stream")
-                            .required(false)
-                            .takes_value(true)
-                            .validator(|s| {
-                                s.parse::<i32>()
-                                    .map_err(|_| String::from("Must be a number."))
-                            }),
-                        )
+                        .args(segment_args())
+                        .args(demux_args())
                         .arg(
                             Arg::with_name("output")
                                 .about("Sets the output TrueHD file.")
@@ -113,13 +102,33 @@ fn main() -> anyhow::Result<()> {
                                 .value_name("OUTPUT-FILE")
                                 .required(true),
                         )
-                        .group(
-                            ArgGroup::with_name("segment-list-group")
-                                .requires("stream-dir")
-                                .required(true),
-                        ),
+                        .group(segment_list_group()),
+                ),
+        )
+        .subcommand(
+            App::new("analyze")
+                .about("Analyze segment boundaries without writing any output.")
+                .subcommand(
+                    App::new("playlist")
+                        .about("Analyze a blu-ray playlist file.")
+                        .args(playlist_args())
+                        .args(analyze_args()),
+                )
+                .subcommand(
+                    App::new("segments")
+                        .about("Analyze blu-ray media files.")
+                        .args(segment_args())
+                        .args(analyze_args())
+                        .group(segment_list_group()),
                 ),
         )
+        .subcommand(
+            App::new("list")
+                .about("Lists the playlists of a blu-ray disc, main feature candidates first.")
+                .long_about("Lists the playlists of a blu-ray disc. Duplicates, playlists that are too short and playlists that play the same clip more than once are rejected. The others are ranked by TrueHD availability, duration and segment count, so that the main feature comes first.")
+                .arg(disc_arg())
+                .arg(min_duration_arg()),
+        )
         .subcommand(
             App::new("info")
                 .about("Prints information about the TrueHD stream.")
@@ -164,178 +173,228 @@ fn main() -> anyhow::Result<()> {
                 .global(true)
                 .long("enable-ffmpeg-log"),
         )
+        .arg(
+            Arg::with_name("format")
+                .about("Sets the output format.")
+                .long_about("Sets the output format. With \"json\", a single JSON document that describes the streams, segments and boundary decisions is written to stdout, and all log output goes to stderr.")
+                .global(true)
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .about("Sets how progress is reported.")
+                .long_about("Sets how progress is reported while demuxing. \"bar\" draws a progress bar across all segments, \"ndjson\" writes one JSON object per line to stderr for every event (segment started, progress, boundary decision, segment finished, warning), and \"none\" disables progress reporting.")
+                .global(true)
+                .long("progress")
+                .takes_value(true)
+                .possible_values(&progress::PROGRESS_NAMES)
+                .default_value("bar"),
+        )
         .after_help("This software uses libraries from the FFmpeg project under the LGPLv2.1.")
         .get_matches();
 
     let force = args.is_present("force");
     let verbosity_level = args.occurrences_of("verbosity").min(3);
     let log_ffmpeg = args.is_present("ffmpeg-log");
+    let format = OutputFormat::from_name(args.value_of("format").unwrap_or("text"));
+
+    let observer = progress::observer_by_name(args.value_of("progress").unwrap_or("bar"));
 
-    setup_logging(verbosity_level as i32, log_ffmpeg);
+    setup_logging(verbosity_level as i32, log_ffmpeg, format);
 
     match args.subcommand() {
-        ("demux", Some(sub)) => {
-            match sub.subcommand() {
-                ("playlist", Some(sub)) => {
-                    let mpls_path = sub.value_of("playlist").map(|p| PathBuf::from(p)).unwrap();
-
-                    // turn angle into a 0-based index internally
-                    let user_did_supply_angle = sub.occurrences_of("angle") > 0;
-                    let angle_arg = sub
-                        .value_of("angle")
-                        .map(|s| s.parse::<i32>().unwrap())
-                        .map(|n| n.max(1) - 1)
-                        .unwrap();
-
-                    let user_stream_idx = sub
-                        .value_of("stream-idx")
-                        .map(|s| s.parse::<i32>().unwrap());
-
-                    let mpls = {
-                        let file = File::open(&mpls_path)?;
-                        let mpls = Mpls::from(file).expect("failed to parse MPLS file.");
-                        mpls
-                    };
-                    let segments = {
-                        let angles = mpls.angles();
-                        if angles.len() > 1 && !user_did_supply_angle {
-                            warn!("This playlist contains more than one angle, but you did not select an angle with --angle. Using the default angle 1 ...");
-                        }
-                        let selected_angle = match angles.get(angle_arg as usize) {
-                            None => {
-                                error!("Angle {} doesn't exist in this playlist.", angle_arg + 1);
-                                return Ok(());
-                            }
-                            Some(a) => a,
-                        };
-
-                        debug!(
-                            "Playlist has {} {}.",
-                            angles.len(),
-                            if angles.len() > 1 { "angles" } else { "angle" }
-                        );
-                        debug!("Using angle {}.", angle_arg + 1);
-
-                        get_segments(&mpls, &selected_angle, &mpls_path)
-                    };
+        ("demux", Some(sub)) => match sub.subcommand() {
+            (name @ "playlist", Some(sub)) | (name @ "disc", Some(sub)) => {
+                let demux_args =
+                    DemuxArgs::from_matches(sub, observer.clone(), cancel_on_ctrl_c()?);
+                let playlist = if name == "disc" {
+                    disc_segments(sub)?
+                } else {
+                    playlist_segments(sub)?
+                };
+                let (mpls, segments) = match playlist {
+                    Some(p) => p,
+                    None => return Ok(()),
+                };
 
-                    let thd_streams = libav::demux::thd_streams(&segments[0].path)
-                        .map(|s| thd_streams_with_language(&s, &mpls.play_list.play_items[0]))
-                        .context("Failed at searching for TrueHD streams.")?;
-                    print_thd_stream_list(&thd_streams);
+                let thd_streams = libav::demux::thd_streams(&segments[0])
+                    .map(|s| {
+                        thd_streams_with_language(
+                            &s,
+                            &audio_languages(&mpls.play_list.play_items[0]),
+                        )
+                    })
+                    .context("Failed at searching for TrueHD streams.")?;
+                print_thd_stream_list(&thd_streams);
 
-                    if let Some(output_path) = sub.value_of("output").map(|p| PathBuf::from(p)) {
-                        let selected_stream = select_thd_stream(&thd_streams, user_stream_idx)?;
-                        let demux_opts = match selected_stream {
-                            Some(i) => libav::demux::DemuxOptions {
-                                thd_stream_id: Some(i),
+                if let Some(output_path) = sub.value_of("output").map(PathBuf::from) {
+                    let selected_streams =
+                        select_thd_streams(&thd_streams, demux_args.stream_selection.clone())?;
+                    let audio_streams = libav::demux::audio_streams(&segments[0])
+                        .map(|s| {
+                            audio_streams_with_language(
+                                &s,
+                                &audio_languages(&mpls.play_list.play_items[0]),
+                            )
+                        })
+                        .context("Failed at searching for audio streams.")?;
+                    let secondary_streams = select_secondary_streams(
+                        &audio_streams,
+                        selected_streams.first(),
+                        demux_args.secondary_selection.clone(),
+                    )?;
+                    demux_to_files(
+                        &segments,
+                        &selected_streams,
+                        &secondary_streams,
+                        &demux_args,
+                        &output_path,
+                        force,
+                        format,
+                    )?;
+                } else {
+                    print_playlist_info(&mpls);
+                    if format == OutputFormat::Json {
+                        report::write_json(
+                            "demux playlist",
+                            &PlaylistReport {
+                                streams: &thd_streams,
+                                angles: mpls
+                                    .angles()
+                                    .iter()
+                                    .map(|a| AngleReport {
+                                        index: a.index + 1,
+                                        segments: a
+                                            .segments()
+                                            .iter()
+                                            .map(|c| c.file_name.clone())
+                                            .collect(),
+                                    })
+                                    .collect(),
                             },
-                            None => {
-                                return Ok(());
-                            }
-                        };
-
-                        if let Some(file) =
-                            file_create_with_force_check(&output_path, force).transpose()?
-                        {
-                            let writer = BufWriter::new(file);
-                            let stats = libav::demux::demux_thd(&segments, &demux_opts, writer)
-                                .context("Failed demuxing TrueHD stream.")?;
-                            print_demux_stats(&stats);
-                        }
-                    } else {
-                        let mpls = {
-                            let f = File::open(&mpls_path).with_context(|| {
-                                format!("Failed to open MPLS file at {}", &mpls_path.display())
-                            })?;
-                            Mpls::from(f)?
-                        };
-                        print_playlist_info(&mpls);
+                        )?;
                     }
-
-                    Ok(())
                 }
-                ("segments", Some(sub)) => {
-                    let output_path = sub.value_of("output").map(|p| PathBuf::from(p)).unwrap();
-                    let source_dir_path = sub
-                        .value_of("stream-dir")
-                        .map(|p| PathBuf::from(p))
-                        .unwrap();
-
-                    let user_stream_idx = sub
-                        .value_of("stream-idx")
-                        .map(|s| s.parse::<i32>().unwrap());
-
-                    let segments: Vec<Segment> = {
-                        if let Some(values) = sub.values_of("segment-list") {
-                            values
-                                .map(|s| {
-                                    s.parse::<u16>()
-                                        .expect("segment list must only contain numbers.")
-                                })
-                                .map(|s| {
-                                    let mut p = source_dir_path.clone();
-                                    p.push(format!("{:0>5}.m2ts", s));
-                                    p
-                                })
-                                .map(|s| Segment {
-                                    path: s,
-                                    video_frames: None,
-                                })
-                                .collect()
-                        } else if let Some(values) = sub.values_of("segment-files") {
-                            values
-                                .map(|s| {
-                                    let mut p = source_dir_path.clone();
-                                    p.push(s);
-                                    p
-                                })
-                                .map(|s| Segment {
-                                    path: s,
-                                    video_frames: None,
-                                })
-                                .collect()
-                        } else {
-                            // can't happen, clap makes sure of that
-                            return Ok(());
-                        }
-                    };
 
-                    let thd_streams = libav::demux::thd_streams(&segments[0].path)
-                        .context("Failed at searching for TrueHD streams.")?;
-                    print_thd_stream_list(&thd_streams);
-                    let selected_stream = select_thd_stream(&thd_streams, user_stream_idx)?;
-                    let demux_opts = match selected_stream {
-                        Some(i) => libav::demux::DemuxOptions {
-                            thd_stream_id: Some(i),
-                        },
-                        None => {
-                            return Ok(());
-                        }
-                    };
+                Ok(())
+            }
+            ("segments", Some(sub)) => {
+                let output_path = sub.value_of("output").map(PathBuf::from).unwrap();
+                let demux_args =
+                    DemuxArgs::from_matches(sub, observer.clone(), cancel_on_ctrl_c()?);
 
-                    if let Some(file) =
-                        file_create_with_force_check(&output_path, force).transpose()?
-                    {
-                        let writer = BufWriter::new(file);
-                        let stats = libav::demux::demux_thd(&segments, &demux_opts, writer)
-                            .context("Failed demuxing TrueHD stream.")?;
-                        print_demux_stats(&stats);
-                    }
+                let segments = segment_list(sub);
+                let langs = clip_languages(&segments[0]);
+
+                let thd_streams = libav::demux::thd_streams(&segments[0])
+                    .map(|s| thd_streams_with_language(&s, &langs))
+                    .context("Failed at searching for TrueHD streams.")?;
+                print_thd_stream_list(&thd_streams);
+                let selected_streams =
+                    select_thd_streams(&thd_streams, demux_args.stream_selection.clone())?;
+                let audio_streams = libav::demux::audio_streams(&segments[0])
+                    .map(|s| audio_streams_with_language(&s, &langs))
+                    .context("Failed at searching for audio streams.")?;
+                let secondary_streams = select_secondary_streams(
+                    &audio_streams,
+                    selected_streams.first(),
+                    demux_args.secondary_selection.clone(),
+                )?;
+                demux_to_files(
+                    &segments,
+                    &selected_streams,
+                    &secondary_streams,
+                    &demux_args,
+                    &output_path,
+                    force,
+                    format,
+                )?;
 
-                    Ok(())
+                Ok(())
+            }
+            _ => Ok(()),
+        },
+        ("analyze", Some(sub)) => {
+            let (segments, thd_streams) = match sub.subcommand() {
+                ("playlist", Some(sub)) => {
+                    let (mpls, segments) = match playlist_segments(sub)? {
+                        Some(p) => p,
+                        None => return Ok(()),
+                    };
+                    let thd_streams = libav::demux::thd_streams(&segments[0])
+                        .map(|s| {
+                            thd_streams_with_language(
+                                &s,
+                                &audio_languages(&mpls.play_list.play_items[0]),
+                            )
+                        })
+                        .context("Failed at searching for TrueHD streams.")?;
+                    (segments, thd_streams)
+                }
+                ("segments", Some(sub)) => {
+                    let segments = segment_list(sub);
+                    let thd_streams = libav::demux::thd_streams(&segments[0])
+                        .map(|s| thd_streams_with_language(&s, &clip_languages(&segments[0])))
+                        .context("Failed at searching for TrueHD streams.")?;
+                    (segments, thd_streams)
                 }
-                _ => Ok(()),
+                _ => return Ok(()),
+            };
+            let demux_args = match sub.subcommand() {
+                (_, Some(sub)) => {
+                    DemuxArgs::from_matches(sub, observer.clone(), cancel_on_ctrl_c()?)
+                }
+                _ => return Ok(()),
+            };
+
+            print_thd_stream_list(&thd_streams);
+            let selected_streams =
+                select_thd_streams(&thd_streams, demux_args.stream_selection.clone())?;
+            analyze_segments(&segments, &selected_streams, &demux_args, format)
+        }
+        ("list", Some(sub)) => {
+            let disc = Disc::open(sub.value_of("disc").unwrap())?;
+            let (candidates, titles) = disc.candidates(min_duration(sub))?;
+            print_playlist_candidates(&candidates);
+            print_titles(&titles);
+            if format == OutputFormat::Json {
+                report::write_json(
+                    "list",
+                    &PlaylistListReport {
+                        main_feature: disc::main_feature(&candidates).map(|c| c.name.as_str()),
+                        playlists: &candidates,
+                        titles: &titles,
+                    },
+                )?;
             }
+
+            Ok(())
         }
         ("info", Some(sub)) => {
-            let path = sub.value_of("stream").map(|p| PathBuf::from(p)).unwrap();
+            let path = sub.value_of("stream").map(PathBuf::from).unwrap();
             let user_stream_idx = sub
                 .value_of("stream-idx")
                 .map(|s| s.parse::<i32>().unwrap());
 
-            if let Some((a, b, metadata)) = count_thd_frames(&path, user_stream_idx)? {
+            let thd_streams = libav::demux::thd_streams(&Segment {
+                path: path.clone(),
+                image: None,
+                video_frames: None,
+                video_metadata: None,
+            })?;
+            print_thd_stream_list(&thd_streams);
+            if let Some((a, b, metadata)) = count_thd_frames(&path, &thd_streams, user_stream_idx)?
+            {
                 print_frame_count_info((a, b), &metadata);
+                if format == OutputFormat::Json {
+                    report::write_json(
+                        "info",
+                        &FrameCountReport::new(&thd_streams, (a, b), metadata),
+                    )?;
+                }
             }
 
             Ok(())
@@ -344,15 +403,614 @@ fn main() -> anyhow::Result<()> {
     }
 }
 
+/// Arguments of the subcommands that read a playlist.
+fn playlist_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::with_name("playlist")
+            .about("Sets the path to the playlist file (.mpls).")
+            .long_about("Sets the path to the playlist file (.mpls). A playlist inside a blu-ray ISO image is given as <IMAGE>:<PATH>, e.g. disc.iso:/BDMV/PLAYLIST/00800.mpls.")
+            .value_name("PLAYLIST")
+            .required(true),
+        angle_arg(),
+    ]
+}
+
+/// Arguments of the subcommands that read a whole disc.
+fn disc_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        disc_arg(),
+        angle_arg(),
+        min_duration_arg(),
+        Arg::with_name("title")
+            .about("Selects the playlist of the given disc title instead of the main feature.")
+            .long_about("Selects the playlist of the given disc title, starting at 1, instead of the main feature. Titles are read from index.bdmv and MovieObject.bdmv. The playlists of BD-J titles, and of titles that choose them at runtime, can't be determined.")
+            .long("title")
+            .value_name("TITLE")
+            .takes_value(true)
+            .validator(|s| match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(String::from("Must be a positive number.")),
+            }),
+    ]
+}
+
+fn disc_arg<'a>() -> Arg<'a> {
+    Arg::with_name("disc")
+        .about("Sets the path to the blu-ray disc.")
+        .long_about("Sets the path to the blu-ray disc: its root directory, its BDMV directory, or an ISO image.")
+        .value_name("DISC")
+        .required(true)
+}
+
+fn angle_arg<'a>() -> Arg<'a> {
+    Arg::with_name("angle")
+        .about("Sets the playlist angle index, starting at 1.")
+        .long("angle")
+        .default_value("1")
+        .value_name("ANGLE")
+        .required(false)
+        .validator(|s| {
+            s.parse::<i32>()
+                .map_err(|_| String::from("Must be a number."))
+        })
+}
+
+fn min_duration_arg<'a>() -> Arg<'a> {
+    Arg::with_name("min-duration")
+        .about("Sets the minimum duration of a main feature candidate, in seconds.")
+        .long("min-duration")
+        .value_name("SECONDS")
+        .takes_value(true)
+        .default_value("600")
+        .validator(|s| match s.parse::<f64>() {
+            Ok(n) if n >= 0.0 => Ok(()),
+            _ => Err(String::from("Must be a non-negative number.")),
+        })
+}
+
+fn min_duration(sub: &ArgMatches) -> f64 {
+    sub.value_of("min-duration")
+        .map_or(disc::DEFAULT_MIN_DURATION, |s| s.parse().unwrap())
+}
+
+/// Arguments of the subcommands that read a list of segment files.
+fn segment_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::with_name("segment-list")
+            .about("Sets the comma-separated list of TrueHD segments.")
+            .short('l')
+            .long("segment-list")
+            .group("segment-list-group")
+            .takes_value(true)
+            .min_values(2)
+            .value_delimiter(",")
+            .value_name("SEGMENT-LIST"),
+        Arg::with_name("segment-files")
+            .about("Sets the list of file names, separated by +.")
+            .long("segment-files")
+            .value_delimiter("+")
+            .value_name("SEGMENT-LIST")
+            .group("segment-list-group")
+            .takes_value(true),
+        Arg::with_name("stream-dir")
+            .about("Sets the directory that contains the m2ts source files.")
+            .requires("segment-list-group")
+            .value_name("DIRECTORY")
+            .short('s')
+            .long("stream-dir"),
+    ]
+}
+
+fn segment_list_group<'a>() -> ArgGroup<'a> {
+    ArgGroup::with_name("segment-list-group")
+        .requires("stream-dir")
+        .required(true)
+}
+
+fn stream_arg<'a>() -> Arg<'a> {
+    Arg::with_name("stream-idx")
+        .about("Sets the index of the TrueHD stream to demux.")
+        .long_about("Sets the index of the TrueHD stream to demux. Can also be a comma-separated list of indices, or \"all\", in which case one output file per stream is written.")
+        .long("stream")
+        .required(false)
+        .takes_value(true)
+        .validator(|s| parse_stream_selection(s).map(|_| ()))
+}
+
+fn jobs_arg<'a>() -> Arg<'a> {
+    Arg::with_name("jobs")
+        .about("Sets the number of segments that are read in parallel.")
+        .long_about("Sets the number of segments that are read and decoded in parallel. The output is still written in order, and is identical to that of a single job. Every additional job keeps the audio of one segment in memory.")
+        .long("jobs")
+        .short('j')
+        .takes_value(true)
+        .default_value("1")
+        .validator(|s| match s.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(()),
+            _ => Err(String::from("Must be a positive number.")),
+        })
+}
+
+fn cache_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::with_name("cache-dir")
+            .about("Sets the directory of the analysis cache.")
+            .long_about("Sets the directory of the analysis cache, which keeps the frame counts and the decoded audio at the start and end of every segment. Clips that are shared between playlists, or demuxed again, are then only decoded once. Defaults to \"mlp\" in the user's cache directory.")
+            .long("cache-dir")
+            .value_name("DIR")
+            .takes_value(true)
+            .conflicts_with("no-cache"),
+        Arg::with_name("no-cache")
+            .about("Neither reads nor writes the analysis cache.")
+            .long("no-cache"),
+    ]
+}
+
+// the platform's cache directory for the current user, if it can be found
+fn default_cache_dir() -> Option<PathBuf> {
+    let env_dir = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    let base = if cfg!(windows) {
+        env_dir("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|h| h.join("Library").join("Caches"))
+    } else {
+        env_dir("XDG_CACHE_HOME").or_else(|| env_dir("HOME").map(|h| h.join(".cache")))
+    };
+    base.map(|b| b.join("mlp"))
+}
+
+/// Arguments that are shared by all `demux` subcommands.
+fn demux_args<'a>() -> Vec<Arg<'a>> {
+    let mut args = vec![
+        stream_arg(),
+        jobs_arg(),
+        Arg::with_name("secondary")
+            .about("Sets the indices of secondary audio streams that follow the TrueHD stream's cuts.")
+            .long_about("Sets the comma-separated indices of secondary audio streams (TrueHD, AC-3 or DTS), or \"all\". Secondary streams are cut wherever the primary TrueHD stream is cut, so that every stream drifts identically relative to the video.")
+            .long("secondary")
+            .required(false)
+            .takes_value(true)
+            .validator(|s| parse_stream_selection(s).map(|_| ())),
+        Arg::with_name("shared-cuts")
+            .about("Cuts every selected TrueHD stream according to the first one.")
+            .long("shared-cuts"),
+        Arg::with_name("resume")
+            .about("Continues an interrupted demux from its checkpoint.")
+            .long_about("Continues an interrupted demux from the checkpoint next to the output file (<OUTPUT>.checkpoint.json), which is written after every completed segment. The same arguments as in the interrupted run must be given.")
+            .long("resume"),
+    ];
+    args.extend(cache_args());
+    args.extend(boundary_args());
+    args
+}
+
+/// Arguments that are shared by all `analyze` subcommands.
+fn analyze_args<'a>() -> Vec<Arg<'a>> {
+    let mut args = vec![stream_arg(), jobs_arg()];
+    args.extend(cache_args());
+    args.extend(boundary_args());
+    args
+}
+
+/// Arguments that control how segment boundaries are decided.
+fn boundary_args<'a>() -> Vec<Arg<'a>> {
+    vec![
+        Arg::with_name("cut-list")
+            .about("Replays the boundary decisions of a cut list file instead of decoding.")
+            .long("cut-list")
+            .value_name("CUT-LIST")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("export-cut-list")
+            .about("Writes the boundary decisions to a cut list file.")
+            .long("export-cut-list")
+            .value_name("CUT-LIST")
+            .takes_value(true)
+            .required(false),
+        Arg::with_name("keep")
+            .about("Keeps all frames at the given segment boundaries.")
+            .long_about("Keeps all frames at the given segment boundaries, whatever the boundary check decides. A boundary is either its index (the first boundary is 1) or the five-digit name of the clip that follows it. Can be given several times, or as a comma-separated list.")
+            .long("keep")
+            .value_name("BOUNDARY")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .required(false)
+            .validator(|s| parse_boundary_ref(s).map(|_| ())),
+        Arg::with_name("delete")
+            .about("Deletes frames at the given segment boundaries.")
+            .long_about("Deletes frames at the given segment boundaries, whatever the boundary check decides. Takes the form BOUNDARY[=N], where N is the number of frames to delete (defaults to 1). Major sync frames are never deleted. Can be given several times, or as a comma-separated list.")
+            .long("delete")
+            .value_name("BOUNDARY[=N]")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .required(false)
+            .validator(|s| parse_delete_override(s).map(|_| ())),
+        Arg::with_name("strategy")
+            .about("Sets the strategy that decides what to do at segment boundaries.")
+            .long_about("Sets the strategy that decides what to do at segment boundaries. \"heuristic\" (the default) compares the audio at each boundary and removes duplicate frames, \"exact\" only removes frames that decode to identical audio, and \"overrun\" ignores the audio and only keeps the audio in sync with the video.")
+            .long("strategy")
+            .takes_value(true)
+            .required(false)
+            .possible_values(&boundary::STRATEGY_NAMES),
+        Arg::with_name("silence-threshold")
+            .about("Sets the amplitude below which a frame counts as silent.")
+            .long_about("Sets the amplitude below which a frame counts as silent, on a 24-bit scale. Defaults to 100, or more for sources with a lower bit depth.")
+            .long("silence-threshold")
+            .value_name("AMPLITUDE")
+            .takes_value(true)
+            .required(false)
+            .validator(|s| s.parse::<u32>().map(|_| ()).map_err(|e| e.to_string())),
+        Arg::with_name("covariance-threshold")
+            .about("Sets the covariance above which two frames contain the same audio.")
+            .long_about("Sets the covariance above which two frames contain the same audio, between 0 and 1. Defaults to 0.95.")
+            .long("covariance-threshold")
+            .value_name("COVARIANCE")
+            .takes_value(true)
+            .required(false)
+            .validator(parse_covariance_threshold),
+        Arg::with_name("overrun-threshold")
+            .about("Sets the overrun in samples at which a silent frame is removed.")
+            .long_about("Sets the overrun in samples at which a silent frame is removed to keep the audio in sync with the video. Defaults to half a frame (20 samples at 48 kHz).")
+            .long("overrun-threshold")
+            .value_name("SAMPLES")
+            .takes_value(true)
+            .required(false)
+            .validator(|s| s.parse::<u32>().map(|_| ()).map_err(|e| e.to_string())),
+    ]
+}
+
+fn parse_covariance_threshold(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(c) if (0.0..=1.0).contains(&c) => Ok(c),
+        Ok(_) => Err(String::from(
+            "The covariance threshold must be between 0 and 1.",
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a segment boundary reference. Blu-ray clip names are always five
+/// digits long, so those are taken as clip names, anything else as an index.
+fn parse_boundary_ref(s: &str) -> Result<BoundaryRef, String> {
+    let s = s.trim().trim_end_matches(".m2ts");
+    if s.len() == 5 && s.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(BoundaryRef::Clip(s.to_string()));
+    }
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("The first segment boundary has index 1.")),
+        Ok(i) => Ok(BoundaryRef::Index(i)),
+        Err(_) => Err(format!(
+            "\"{}\" is neither a boundary index nor a clip name.",
+            s
+        )),
+    }
+}
+
+fn parse_delete_override(s: &str) -> Result<BoundaryOverride, String> {
+    let (boundary, frames) = match s.find('=') {
+        Some(i) => {
+            let frames = s[i + 1..]
+                .trim()
+                .parse::<u32>()
+                .map_err(|e| format!("Invalid frame count: {}", e))?;
+            (&s[..i], frames)
+        }
+        None => (s, 1),
+    };
+    Ok(BoundaryOverride {
+        boundary: parse_boundary_ref(boundary)?,
+        action: OverrideAction::Delete(frames),
+    })
+}
+
+/// The settings of a `demux` subcommand, as given on the command line.
+struct DemuxArgs {
+    stream_selection: Option<StreamSelection>,
+    secondary_selection: Option<StreamSelection>,
+    shared_cuts: bool,
+    resume: bool,
+    jobs: usize,
+    cache: Option<AnalysisCache>,
+    cut_list: Option<PathBuf>,
+    export_cut_list: Option<PathBuf>,
+    overrides: Vec<BoundaryOverride>,
+    strategy: Option<Arc<dyn BoundaryStrategy>>,
+    thresholds: ThresholdOptions,
+    observer: Option<Arc<dyn DemuxObserver>>,
+    cancellation: CancellationToken,
+}
+
+impl DemuxArgs {
+    fn from_matches(
+        matches: &ArgMatches,
+        observer: Option<Arc<dyn DemuxObserver>>,
+        cancellation: CancellationToken,
+    ) -> DemuxArgs {
+        DemuxArgs {
+            stream_selection: matches
+                .value_of("stream-idx")
+                .map(|s| parse_stream_selection(s).unwrap()),
+            secondary_selection: matches
+                .value_of("secondary")
+                .map(|s| parse_stream_selection(s).unwrap()),
+            shared_cuts: matches.is_present("shared-cuts"),
+            resume: matches.is_present("resume"),
+            jobs: matches.value_of("jobs").map_or(1, |s| s.parse().unwrap()),
+            cache: if matches.is_present("no-cache") {
+                None
+            } else {
+                matches
+                    .value_of("cache-dir")
+                    .map(PathBuf::from)
+                    .or_else(default_cache_dir)
+                    .map(AnalysisCache::new)
+            },
+            cut_list: matches.value_of("cut-list").map(PathBuf::from),
+            export_cut_list: matches.value_of("export-cut-list").map(PathBuf::from),
+            overrides: matches
+                .values_of("keep")
+                .into_iter()
+                .flatten()
+                .map(|s| BoundaryOverride {
+                    boundary: parse_boundary_ref(s).unwrap(),
+                    action: OverrideAction::Keep,
+                })
+                .chain(
+                    matches
+                        .values_of("delete")
+                        .into_iter()
+                        .flatten()
+                        .map(|s| parse_delete_override(s).unwrap()),
+                )
+                .collect(),
+            strategy: matches
+                .value_of("strategy")
+                .map(|s| boundary::strategy_by_name(s).unwrap()),
+            thresholds: ThresholdOptions {
+                silence: matches
+                    .value_of("silence-threshold")
+                    .map(|s| s.parse().unwrap()),
+                covariance: matches
+                    .value_of("covariance-threshold")
+                    .map(|s| parse_covariance_threshold(s).unwrap()),
+                overrun: matches
+                    .value_of("overrun-threshold")
+                    .map(|s| s.parse().unwrap()),
+            },
+            observer,
+            cancellation,
+        }
+    }
+
+    fn demux_options(&self, primary: Option<&ThdStreamInfo>) -> anyhow::Result<DemuxOptions> {
+        let cut_list = match self.cut_list {
+            Some(ref path) => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open cut list at {}", path.display()))?;
+                Some(CutList::read(BufReader::new(file))?)
+            }
+            None => None,
+        };
+
+        Ok(DemuxOptions {
+            thd_stream_id: primary.map(|s| s.id),
+            cut_list,
+            overrides: self.overrides.clone(),
+            strategy: self
+                .strategy
+                .clone()
+                .unwrap_or_else(|| DemuxOptions::default().strategy),
+            thresholds: self.thresholds,
+            observer: self.observer.clone(),
+            cancellation: self.cancellation.clone(),
+            checkpoint: None,
+            resume: None,
+            jobs: self.jobs,
+            cache: self.cache.clone(),
+        })
+    }
+}
+
+/// Reads the playlist and resolves the segments of the selected angle. The
+/// playlist may be inside a disc image, as in `disc.iso:/BDMV/PLAYLIST/00800.mpls`.
+/// Returns `None` if the angle doesn't exist.
+fn playlist_segments(sub: &ArgMatches) -> anyhow::Result<Option<(Mpls, Vec<Segment>)>> {
+    let playlist_arg = sub.value_of("playlist").unwrap();
+    let (image, mpls_path) = match udf::split_image_path(playlist_arg) {
+        Some((image_path, inner_path)) => {
+            let image = UdfImage::open(&image_path).with_context(|| {
+                format!("Failed to open disc image at {}", image_path.display())
+            })?;
+            (Some(Arc::new(image)), PathBuf::from(inner_path))
+        }
+        None => (None, PathBuf::from(playlist_arg)),
+    };
+
+    let mpls = match image {
+        Some(ref image) => Mpls::from(image.open_file(&mpls_path.to_string_lossy())?),
+        None => Mpls::from(File::open(&mpls_path)?),
+    }
+    .expect("failed to parse MPLS file.");
+    Ok(angle_segments(sub, &mpls, &mpls_path, image.as_ref()).map(|s| (mpls, s)))
+}
+
+/// Selects the main feature of the disc, or the playlist of the title given
+/// with `--title`, and resolves the segments of the selected angle. Returns
+/// `None` if there's no such playlist, or if the angle doesn't exist.
+fn disc_segments(sub: &ArgMatches) -> anyhow::Result<Option<(Mpls, Vec<Segment>)>> {
+    let disc = Disc::open(sub.value_of("disc").unwrap())?;
+    let (candidates, titles) = disc.candidates(min_duration(sub))?;
+    let selected = match sub.value_of("title").map(|s| s.parse::<usize>().unwrap()) {
+        Some(number) => {
+            let title = match titles.get(number - 1) {
+                Some(t) => t,
+                None => {
+                    error!(
+                        "Title {} doesn't exist on this disc, which has {} title{}.",
+                        number,
+                        titles.len(),
+                        if titles.len() == 1 { "" } else { "s" }
+                    );
+                    return Ok(None);
+                }
+            };
+            match disc::title_playlist(&candidates, number) {
+                Some(c) => c,
+                None if title.bdj => {
+                    error!("Title {} is a BD-J title, so its playlist is unknown. Use the list command to pick a playlist.", number);
+                    return Ok(None);
+                }
+                None => {
+                    error!("The playlist of title {} can't be determined. Use the list command to pick a playlist.", number);
+                    return Ok(None);
+                }
+            }
+        }
+        None => match disc::main_feature(&candidates) {
+            Some(c) => c,
+            None => {
+                error!("Couldn't find a main feature on this disc. Use the list command to see all playlists.");
+                return Ok(None);
+            }
+        },
+    };
+    info!(
+        "Selected playlist {} ({}, {} segments){}.",
+        selected.name,
+        format_duration(selected.duration),
+        selected.segments,
+        if sub.is_present("title") {
+            ""
+        } else {
+            " as the main feature"
+        }
+    );
+
+    let mpls = disc.read_playlist(&selected.name)?;
+    let mpls_path = disc.playlist_path(&selected.name);
+    Ok(angle_segments(sub, &mpls, &mpls_path, disc.image()).map(|s| (mpls, s)))
+}
+
+/// Resolves the segments of the angle selected with `--angle`. Returns `None`
+/// if the angle doesn't exist.
+fn angle_segments(
+    sub: &ArgMatches,
+    mpls: &Mpls,
+    mpls_path: &Path,
+    image: Option<&Arc<UdfImage>>,
+) -> Option<Vec<Segment>> {
+    // turn angle into a 0-based index internally
+    let user_did_supply_angle = sub.occurrences_of("angle") > 0;
+    let angle_arg = sub
+        .value_of("angle")
+        .map(|s| s.parse::<i32>().unwrap())
+        .map(|n| n.max(1) - 1)
+        .unwrap();
+
+    let angles = mpls.angles();
+    if angles.len() > 1 && !user_did_supply_angle {
+        warn!("This playlist contains more than one angle, but you did not select an angle with --angle. Using the default angle 1 ...");
+    }
+    let selected_angle = match angles.get(angle_arg as usize) {
+        None => {
+            error!("Angle {} doesn't exist in this playlist.", angle_arg + 1);
+            return None;
+        }
+        Some(a) => a,
+    };
+
+    debug!(
+        "Playlist has {} {}.",
+        angles.len(),
+        if angles.len() > 1 { "angles" } else { "angle" }
+    );
+    debug!("Using angle {}.", angle_arg + 1);
+
+    Some(get_segments(mpls, selected_angle, mpls_path, image))
+}
+
+/// Resolves the segment files given with `--segment-list` or
+/// `--segment-files`.
+fn segment_list(sub: &ArgMatches) -> Vec<Segment> {
+    let source_dir_path = sub.value_of("stream-dir").map(PathBuf::from).unwrap();
+
+    if let Some(values) = sub.values_of("segment-list") {
+        values
+            .map(|s| {
+                s.parse::<u16>()
+                    .expect("segment list must only contain numbers.")
+            })
+            .map(|s| {
+                let mut p = source_dir_path.clone();
+                p.push(format!("{:0>5}.m2ts", s));
+                p
+            })
+            .map(segment_with_clip_info)
+            .collect()
+    } else if let Some(values) = sub.values_of("segment-files") {
+        values
+            .map(|s| {
+                let mut p = source_dir_path.clone();
+                p.push(s);
+                p
+            })
+            .map(segment_with_clip_info)
+            .collect()
+    } else {
+        // can't happen, clap makes sure of that
+        Vec::new()
+    }
+}
+
+// the segment of a file, with its expected number of video frames from its
+// clip info, if there is any
+fn segment_with_clip_info(path: PathBuf) -> Segment {
+    let mut segment = Segment {
+        path,
+        image: None,
+        video_frames: None,
+        video_metadata: None,
+    };
+    match ClipInfo::read(&segment) {
+        Ok(clip_info) => {
+            segment.video_frames = clip_info.video_frames();
+            segment.video_metadata = clip_info.video_metadata();
+            debug!(
+                "Clip info of {} expects {} seconds of video ({} frames).",
+                segment.clip_name(),
+                clip_info.duration(),
+                segment
+                    .video_frames
+                    .map_or(String::from("unknown"), |n| n.to_string())
+            );
+        }
+        Err(e) => debug!("No clip info for {}: {:#}", segment.clip_name(), e),
+    }
+    segment
+}
+
+/// The languages of the segment's audio streams, by PID, according to its
+/// clip info.
+fn clip_languages(segment: &Segment) -> Vec<(i32, String)> {
+    ClipInfo::read(segment)
+        .map(|c| c.audio_languages())
+        .unwrap_or_default()
+}
+
 fn print_thd_stream_list(streams: &[ThdStreamInfo]) {
     for s in streams {
         info!("{}", s);
     }
 }
 
-fn thd_streams_with_language(streams: &[ThdStreamInfo], mpls: &PlayItem) -> Vec<ThdStreamInfo> {
-    let langs = mpls
-        .stream_number_table
+fn audio_languages(mpls: &PlayItem) -> Vec<(i32, String)> {
+    mpls.stream_number_table
         .primary_audio_streams
         .iter()
         .filter_map(|s| {
@@ -373,18 +1031,23 @@ fn thd_streams_with_language(streams: &[ThdStreamInfo], mpls: &PlayItem) -> Vec<
 
             return None;
         })
-        .collect::<Vec<(i32, String)>>();
+        .collect::<Vec<(i32, String)>>()
+}
+
+fn find_language(langs: &[(i32, String)], id: i32) -> Option<String> {
+    langs
+        .iter()
+        .find_map(|s| if s.0 == id { Some(s.1.clone()) } else { None })
+}
 
+fn thd_streams_with_language(
+    streams: &[ThdStreamInfo],
+    langs: &[(i32, String)],
+) -> Vec<ThdStreamInfo> {
     streams
         .into_iter()
         .map(|stream| {
-            if let Some(lang) = langs.iter().find_map(|s| {
-                if s.0 == stream.id {
-                    Some(s.1.clone())
-                } else {
-                    None
-                }
-            }) {
+            if let Some(lang) = find_language(langs, stream.id) {
                 ThdStreamInfo {
                     language: Some(lang),
                     ..stream.clone()
@@ -396,6 +1059,19 @@ fn thd_streams_with_language(streams: &[ThdStreamInfo], mpls: &PlayItem) -> Vec<
         .collect()
 }
 
+fn audio_streams_with_language(
+    streams: &[AudioStreamInfo],
+    langs: &[(i32, String)],
+) -> Vec<AudioStreamInfo> {
+    streams
+        .iter()
+        .map(|stream| AudioStreamInfo {
+            language: find_language(langs, stream.id),
+            ..stream.clone()
+        })
+        .collect()
+}
+
 fn select_thd_stream(
     streams: &[libav::demux::ThdStreamInfo],
     user_select: Option<i32>,
@@ -434,46 +1110,492 @@ fn select_thd_stream(
     }
 }
 
-fn file_create_with_force_check<P: AsRef<Path>>(
-    path: P,
+#[derive(Debug, Clone, PartialEq)]
+enum StreamSelection {
+    All,
+    Indices(Vec<i32>),
+}
+
+fn parse_stream_selection(s: &str) -> Result<StreamSelection, String> {
+    if s.eq_ignore_ascii_case("all") {
+        return Ok(StreamSelection::All);
+    }
+
+    s.split(',')
+        .map(|i| i.trim().parse::<i32>())
+        .collect::<Result<Vec<i32>, _>>()
+        .map(StreamSelection::Indices)
+        .map_err(|_| {
+            String::from("Must be a number, a comma-separated list of numbers, or \"all\".")
+        })
+}
+
+fn select_thd_streams(
+    streams: &[ThdStreamInfo],
+    user_select: Option<StreamSelection>,
+) -> Result<Vec<ThdStreamInfo>, libav::AVError> {
+    match user_select {
+        Some(StreamSelection::All) => {
+            if streams.is_empty() {
+                Err(libav::AVError::DemuxErr(
+                    libav::DemuxErr::NoTrueHdStreamFound,
+                ))
+            } else {
+                Ok(streams.to_vec())
+            }
+        }
+        Some(StreamSelection::Indices(ref indices)) if indices.len() > 1 => indices
+            .iter()
+            .map(|&i| {
+                streams
+                    .iter()
+                    .find(|s| s.index == i)
+                    .cloned()
+                    .ok_or(libav::AVError::DemuxErr(
+                        libav::DemuxErr::SelectedTrueHdStreamNotFound(i),
+                    ))
+            })
+            .collect(),
+        other => {
+            let user_stream_idx = match other {
+                Some(StreamSelection::Indices(indices)) => indices.first().copied(),
+                _ => None,
+            };
+            let selected = select_thd_stream(streams, user_stream_idx)?;
+            Ok(streams
+                .iter()
+                .filter(|s| Some(s.id) == selected)
+                .cloned()
+                .collect())
+        }
+    }
+}
+
+/// Builds the output path of one stream when several streams are demuxed at
+/// once, e.g. `out.thd` becomes `out.2.eng.thd`.
+fn stream_output_path(
+    output_path: &Path,
+    index: i32,
+    language: Option<&str>,
+    extension: Option<&str>,
+) -> PathBuf {
+    let stem = output_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = extension.map(String::from).unwrap_or_else(|| {
+        output_path
+            .extension()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("thd"))
+    });
+    let file_name = match language {
+        Some(lang) => format!("{}.{}.{}.{}", stem, index, lang, extension),
+        None => format!("{}.{}.{}", stem, index, extension),
+    };
+    output_path.with_file_name(file_name)
+}
+
+fn select_secondary_streams(
+    streams: &[AudioStreamInfo],
+    primary: Option<&ThdStreamInfo>,
+    user_select: Option<StreamSelection>,
+) -> anyhow::Result<Vec<AudioStreamInfo>> {
+    let is_primary = |s: &AudioStreamInfo| primary.is_some_and(|p| p.id == s.id);
+    match user_select {
+        None => Ok(Vec::new()),
+        Some(StreamSelection::All) => {
+            Ok(streams.iter().filter(|s| !is_primary(s)).cloned().collect())
+        }
+        Some(StreamSelection::Indices(indices)) => indices
+            .iter()
+            .map(|&i| {
+                streams
+                    .iter()
+                    .find(|s| s.index == i && !is_primary(s))
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Secondary audio stream with index {} not found.", i)
+                    })
+            })
+            .collect(),
+    }
+}
+
+fn demux_to_files(
+    segments: &[Segment],
+    selected_streams: &[ThdStreamInfo],
+    secondary_streams: &[AudioStreamInfo],
+    demux_args: &DemuxArgs,
+    output_path: &Path,
     force: bool,
-) -> Option<anyhow::Result<File>> {
-    match (path.as_ref().exists(), force) {
-        (true, true) => {
-            warn!(
-                "Overwriting existing output file {}.",
-                path.as_ref().display()
-            );
-            Some(File::create(&path).with_context(|| {
-                format!(
-                    "Failed to create output file at {}",
-                    path.as_ref().display()
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    if selected_streams.is_empty() {
+        return Ok(());
+    }
+    let mut demux_opts = demux_args.demux_options(selected_streams.first())?;
+
+    // with a shared cut map, the first selected TrueHD stream is the primary
+    // stream and every other stream follows its cut map
+    let shared_cuts = demux_args.shared_cuts || !secondary_streams.is_empty();
+    let followers: Vec<AudioStreamInfo> = if shared_cuts {
+        selected_streams[1..]
+            .iter()
+            .map(|s| AudioStreamInfo {
+                index: s.index,
+                id: s.id,
+                codec: AudioCodec::TrueHd,
+                language: s.language.clone(),
+            })
+            .chain(secondary_streams.iter().cloned())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let independent_streams = if shared_cuts {
+        &selected_streams[..1]
+    } else {
+        selected_streams
+    };
+
+    let n_outputs = independent_streams.len() + followers.len();
+    let output_paths: Vec<PathBuf> = if n_outputs == 1 {
+        vec![output_path.to_path_buf()]
+    } else {
+        independent_streams
+            .iter()
+            .map(|s| stream_output_path(output_path, s.index, s.language.as_deref(), None))
+            .chain(followers.iter().map(|s| {
+                stream_output_path(
+                    output_path,
+                    s.index,
+                    s.language.as_deref(),
+                    Some(s.codec.extension()),
                 )
             }))
+            .collect()
+    };
+
+    let stream_ids = independent_streams
+        .iter()
+        .map(|s| s.id)
+        .chain(followers.iter().map(|s| s.id));
+    let checkpoint_path = checkpoint_path(output_path);
+    let resume = if demux_args.resume {
+        read_checkpoint(&checkpoint_path)?
+    } else {
+        None
+    };
+
+    let mut outputs = Vec::with_capacity(n_outputs);
+    match resume {
+        Some(ref checkpoint) => {
+            let files = truncate_to_checkpoint(checkpoint, &checkpoint_path, &output_paths)?;
+            for (id, file) in stream_ids.zip(files) {
+                outputs.push((id, BufWriter::new(file)));
+            }
         }
-        (true, false) => {
-            warn!(
-                "Output file {} already exists. Use the --force to overwrite existing output files.",
-                path.as_ref().display()
+        None => {
+            // don't leave any empty files behind if one of them is refused
+            check_outputs(&output_paths, force)?;
+            // a stale checkpoint must not be mistaken for one of this run
+            if checkpoint_path.exists() {
+                std::fs::remove_file(&checkpoint_path).with_context(|| {
+                    format!(
+                        "Failed to remove checkpoint at {}",
+                        checkpoint_path.display()
+                    )
+                })?;
+            }
+            for (id, path) in stream_ids.zip(output_paths.iter()) {
+                let file = File::create(path).with_context(|| {
+                    format!("Failed to create output file at {}", path.display())
+                })?;
+                outputs.push((id, BufWriter::new(file)));
+            }
+        }
+    }
+    demux_opts.checkpoint = Some(checkpoint_path.clone());
+    demux_opts.resume = resume;
+
+    if shared_cuts {
+        let secondary_outputs = outputs.split_off(1);
+        let primary_output = outputs.remove(0);
+        let (stats, secondary_stats) = keep_resumable_outputs(
+            libav::demux::demux_with_cut_map(
+                segments,
+                &demux_opts,
+                primary_output,
+                secondary_outputs,
+            ),
+            &output_paths,
+            &checkpoint_path,
+        )
+        .context("Failed demuxing audio streams.")?;
+
+        info!(
+            "Stream {} ({}):",
+            independent_streams[0].index,
+            output_paths[0].display()
+        );
+        print_demux_stats(&stats);
+        print_cut_map(&stats.cut_map);
+        export_cut_lists(
+            demux_args,
+            independent_streams,
+            std::slice::from_ref(&stats),
+        )?;
+        for ((stream, path), s) in followers
+            .iter()
+            .zip(output_paths[1..].iter())
+            .zip(secondary_stats.iter())
+        {
+            info!("Stream {} ({}):", stream.index, path.display());
+            print_secondary_stats(s, &stats.cut_map);
+        }
+
+        if format == OutputFormat::Json {
+            report::write_json(
+                "demux",
+                &DemuxReport {
+                    streams: vec![StreamReport::new(
+                        &independent_streams[0],
+                        Some(&output_paths[0]),
+                        segments,
+                        &stats,
+                    )],
+                    secondaries: followers
+                        .iter()
+                        .zip(output_paths[1..].iter())
+                        .zip(secondary_stats.iter())
+                        .map(|((stream, path), s)| SecondaryReport::new(stream, path, s, &stats))
+                        .collect(),
+                },
+            )?;
+        }
+    } else {
+        let all_stats = keep_resumable_outputs(
+            libav::demux::demux_thd_streams(segments, &demux_opts, outputs),
+            &output_paths,
+            &checkpoint_path,
+        )
+        .context("Failed demuxing TrueHD stream.")?;
+
+        for ((stream, path), stats) in independent_streams
+            .iter()
+            .zip(output_paths.iter())
+            .zip(all_stats.iter())
+        {
+            if n_outputs > 1 {
+                info!("Stream {} ({}):", stream.index, path.display());
+            }
+            print_demux_stats(stats);
+        }
+        export_cut_lists(demux_args, independent_streams, &all_stats)?;
+
+        if format == OutputFormat::Json {
+            report::write_json(
+                "demux",
+                &DemuxReport {
+                    streams: independent_streams
+                        .iter()
+                        .zip(output_paths.iter())
+                        .zip(all_stats.iter())
+                        .map(|((stream, path), stats)| {
+                            StreamReport::new(stream, Some(path), segments, stats)
+                        })
+                        .collect(),
+                    secondaries: Vec::new(),
+                },
+            )?;
+        }
+    }
+
+    // the run is complete, so there's nothing left to resume
+    if let Err(e) = std::fs::remove_file(&checkpoint_path) {
+        debug!(
+            "Failed to remove checkpoint at {}: {}",
+            checkpoint_path.display(),
+            e
+        );
+    }
+
+    Ok(())
+}
+
+// the checkpoint of a demux run is written next to its (first) output file
+fn checkpoint_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".checkpoint.json");
+    PathBuf::from(path)
+}
+
+fn read_checkpoint(path: &Path) -> anyhow::Result<Option<Checkpoint>> {
+    if !path.exists() {
+        warn!(
+            "No checkpoint found at {}. Starting from the beginning.",
+            path.display()
+        );
+        return Ok(None);
+    }
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open checkpoint at {}", path.display()))?;
+    let checkpoint = Checkpoint::read(BufReader::new(file))?;
+    info!(
+        "Resuming from checkpoint {} after {} of {} segments.",
+        path.display(),
+        checkpoint.next_segment,
+        checkpoint.clips.len()
+    );
+    Ok(Some(checkpoint))
+}
+
+// cuts off whatever was written after the last completed segment and returns
+// the output files, opened for writing
+fn truncate_to_checkpoint(
+    checkpoint: &Checkpoint,
+    checkpoint_path: &Path,
+    output_paths: &[PathBuf],
+) -> anyhow::Result<Vec<File>> {
+    let output_lens: Vec<u64> = checkpoint
+        .streams
+        .iter()
+        .map(|s| s.output_len)
+        .chain(checkpoint.secondaries.iter().map(|s| s.output_len))
+        .collect();
+    if output_lens.len() != output_paths.len() {
+        anyhow::bail!(
+            "The checkpoint at {} was made for different streams.",
+            checkpoint_path.display()
+        );
+    }
+    output_paths
+        .iter()
+        .zip(output_lens)
+        .map(|(path, len)| {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .with_context(|| format!("Failed to open output file at {}", path.display()))?;
+            file.set_len(len)?;
+            Ok(file)
+        })
+        .collect()
+}
+
+// if the demux run was cancelled, keeps the output up to the last completed
+// segment so that the run can be resumed, or removes the partial output files
+// if no segment was completed yet
+fn keep_resumable_outputs<T>(
+    result: Result<T, AVError>,
+    output_paths: &[PathBuf],
+    checkpoint_path: &Path,
+) -> Result<T, AVError> {
+    if let Err(AVError::Cancelled) = result {
+        if checkpoint_path.exists() {
+            let kept = File::open(checkpoint_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| Ok(Checkpoint::read(BufReader::new(file))?))
+                .and_then(|c| truncate_to_checkpoint(&c, checkpoint_path, output_paths));
+            match kept.map(|_| ()) {
+                Ok(()) => info!(
+                    "Kept the output up to the last completed segment. Use --resume to continue."
+                ),
+                Err(e) => warn!("Failed to truncate the output to the checkpoint: {:#}", e),
+            }
+        } else {
+            for path in output_paths.iter().filter(|p| p.exists()) {
+                match std::fs::remove_file(path) {
+                    Ok(()) => info!("Removed partial output file {}.", path.display()),
+                    Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+    result
+}
+
+fn analyze_segments(
+    segments: &[Segment],
+    selected: &[ThdStreamInfo],
+    demux_args: &DemuxArgs,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let options = demux_args.demux_options(selected.first())?;
+    let stream_ids: Vec<i32> = selected.iter().map(|s| s.id).collect();
+    let all_stats = libav::demux::analyze_thd_streams(segments, &options, &stream_ids)?;
+
+    for (stream, stats) in selected.iter().zip(all_stats.iter()) {
+        info!("Analysis of stream {}:", stream);
+        print_segment_analysis(segments, stats);
+        print_demux_stats(stats);
+    }
+
+    export_cut_lists(demux_args, selected, &all_stats)?;
+
+    if format == OutputFormat::Json {
+        report::write_json(
+            "analyze",
+            &DemuxReport {
+                streams: selected
+                    .iter()
+                    .zip(all_stats.iter())
+                    .map(|(stream, stats)| StreamReport::new(stream, None, segments, stats))
+                    .collect(),
+                secondaries: Vec::new(),
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// writes the boundary decisions of the given streams to the cut list file
+// requested with --export-cut-list, if any. With several streams, every
+// stream gets its own cut list, named like its output file.
+fn export_cut_lists(
+    demux_args: &DemuxArgs,
+    streams: &[ThdStreamInfo],
+    stats: &[libav::DemuxStats],
+) -> anyhow::Result<()> {
+    let path = match demux_args.export_cut_list {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+    for (stream, stats) in streams.iter().zip(stats.iter()) {
+        let path = if streams.len() == 1 {
+            path.clone()
+        } else {
+            stream_output_path(path, stream.index, stream.language.as_deref(), None)
+        };
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create cut list at {}", path.display()))?;
+        CutList::new(Some(stream.id), &stats.cut_map).write(BufWriter::new(file))?;
+        info!("Wrote cut list to {}.", path.display());
+    }
+    Ok(())
+}
+
+// fails if any of the output files exists, unless they may be overwritten
+fn check_outputs(paths: &[PathBuf], force: bool) -> anyhow::Result<()> {
+    for path in paths.iter().filter(|p| p.exists()) {
+        if !force {
+            anyhow::bail!(
+                "Output file {} already exists. Use --force to overwrite existing output files.",
+                path.display()
             );
-            None
         }
-        (false, _) => Some(File::create(&path).with_context(|| {
-            format!(
-                "Failed to create output file at {}",
-                path.as_ref().display()
-            )
-        })),
+        warn!("Overwriting existing output file {}.", path.display());
     }
+    Ok(())
 }
 
 fn count_thd_frames<P: AsRef<Path>>(
     filepath: P,
+    thd_streams: &[ThdStreamInfo],
     stream_idx: Option<i32>,
 ) -> anyhow::Result<Option<(i32, i32, ThdMetadata)>> {
-    let thd_streams = libav::demux::thd_streams(&filepath)?;
-    print_thd_stream_list(&thd_streams);
-    if let Some(stream_pid) = select_thd_stream(&thd_streams, stream_idx)? {
+    if let Some(stream_pid) = select_thd_stream(thd_streams, stream_idx)? {
         info!("Counting output file frames ...");
 
         let mut avctx = libav::AVFormatContext::open(&filepath)?;
@@ -507,16 +1629,100 @@ fn count_thd_frames<P: AsRef<Path>>(
     }
 }
 
+#[derive(Clone)]
 pub struct Segment {
     pub path: PathBuf,
+    /// The disc image `path` is in, if any.
+    pub image: Option<Arc<UdfImage>>,
     pub video_frames: Option<i32>,
+    /// What the playlist or clip info says about the video.
+    pub video_metadata: Option<VideoMetadata>,
 }
 
-fn get_segments(playlist: &Mpls, angle: &mpls::Angle, playlist_path: &PathBuf) -> Vec<Segment> {
+impl Segment {
+    /// The segment's file name without extension, e.g. `00055`.
+    pub fn clip_name(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Opens the segment for demuxing.
+    pub fn open(&self) -> Result<AVFormatContext<'static>, AVError> {
+        let image = match self.image {
+            Some(ref image) => image,
+            None => return AVFormatContext::open(&self.path),
+        };
+
+        let file = image
+            .open_file(&self.path.to_string_lossy())
+            .map_err(io::Error::from)?;
+        AVFormatContext::from_reader(file)
+    }
+
+    /// Opens the segment for demuxing, and counts the access units of its
+    /// video while it's read.
+    pub fn open_counting(
+        &self,
+    ) -> Result<(AVFormatContext<'static>, Arc<Mutex<AccessUnitCounter>>), AVError> {
+        let counter = Arc::new(Mutex::new(AccessUnitCounter::default()));
+        let avctx = match self.image {
+            Some(ref image) => {
+                let file = image
+                    .open_file(&self.path.to_string_lossy())
+                    .map_err(io::Error::from)?;
+                AVFormatContext::from_reader(CountingReader::new(file, counter.clone()))?
+            }
+            None => {
+                let file = File::open(&self.path)?;
+                AVFormatContext::from_reader(CountingReader::new(file, counter.clone()))?
+            }
+        };
+        Ok((avctx, counter))
+    }
+
+    /// The byte offset of an entry point at least `seconds` before the end of
+    /// the segment, according to its clip info.
+    pub fn tail_position(&self, seconds: f64) -> Option<u64> {
+        ClipInfo::read(self).ok()?.tail_offset(seconds)
+    }
+
+    /// Opens the raw bytes of the segment.
+    pub fn reader(&self) -> io::Result<Box<dyn Read>> {
+        match self.image {
+            Some(ref image) => Ok(Box::new(image.open_file(&self.path.to_string_lossy())?)),
+            None => Ok(Box::new(File::open(&self.path)?)),
+        }
+    }
+
+    /// The size and modification time of the segment. Segments inside a disc
+    /// image have the image's modification time.
+    pub fn metadata(&self) -> io::Result<(u64, SystemTime)> {
+        match self.image {
+            Some(ref image) => {
+                let file = image.open_file(&self.path.to_string_lossy())?;
+                let modified = std::fs::metadata(image.path())?.modified()?;
+                Ok((file.len(), modified))
+            }
+            None => {
+                let metadata = std::fs::metadata(&self.path)?;
+                Ok((metadata.len(), metadata.modified()?))
+            }
+        }
+    }
+}
+
+fn get_segments(
+    playlist: &Mpls,
+    angle: &mpls::Angle,
+    playlist_path: &Path,
+    image: Option<&Arc<UdfImage>>,
+) -> Vec<Segment> {
     // find the blu-ray STREAM directory, relative to the
     // playlist path
     let stream_dir = {
-        let mut p = playlist_path.clone();
+        let mut p = playlist_path.to_path_buf();
         p.pop();
         p.pop();
         p.push("STREAM");
@@ -532,47 +1738,68 @@ fn get_segments(playlist: &Mpls, angle: &mpls::Angle, playlist_path: &PathBuf) -
             path.set_extension("m2ts");
             path
         };
-        let video_frames = {
-            let len = play_item.out_time.seconds() - play_item.in_time.seconds();
-            let v_stream = play_item
-                .stream_number_table
-                .primary_video_streams
-                .first()
-                .unwrap();
-            let fps = match v_stream.attrs.stream_type {
-                mpls::StreamType::HdrVideo(_, f, _, _) => f.unwrap().fps(),
-                mpls::StreamType::SdrVideo(_, f) => f.unwrap().fps(),
-                _ => return Vec::new(),
-            };
-            Some((len * fps).round() as i32)
-        };
+        let len = play_item.out_time.seconds() - play_item.in_time.seconds();
+        let v_stream = play_item
+            .stream_number_table
+            .primary_video_streams
+            .first()
+            .unwrap();
+        // without a known frame rate, the video frames are counted instead
+        let video_metadata = mpls_video_metadata(&v_stream.attrs.stream_type);
+        if video_metadata.is_none() {
+            warn!(
+                "Playlist doesn't give the frame rate of clip {}. Counting its video frames instead.",
+                clip.file_name
+            );
+        }
 
         segments.push(Segment {
             path: clip_path,
-            video_frames,
+            image: image.cloned(),
+            video_frames: video_metadata.map(|m| m.frames(len) as i32),
+            video_metadata,
         });
     }
 
     segments
 }
 
-fn print_demux_stats(stats: &libav::DemuxStats) {
-    let (video_frames, audio_frames) = stats
-        .segments
-        .iter()
-        .map(|s| (s.video_frames, s.thd_frames))
-        .fold((0, 0), |(va, aa), (v, a)| (va + v, aa + a));
-    let (video_meta, audio_meta) = (
-        stats.video_metadata().unwrap(),
-        stats.thd_metadata().unwrap(),
-    );
-    let (video_duration, audio_duration) = (
-        video_meta.duration(video_frames),
-        audio_meta.duration(audio_frames),
-    );
+// the frame rate and scan type of a playlist's video stream
+fn mpls_video_metadata(stream_type: &mpls::StreamType) -> Option<VideoMetadata> {
+    let (format, rate) = match stream_type {
+        mpls::StreamType::HdrVideo(format, rate, _, _) => (format, rate),
+        mpls::StreamType::SdrVideo(format, rate) => (format, rate),
+        _ => return None,
+    };
+    // an unknown frame rate leaves the video metadata unknown
+    let (numerator, denominator) = match rate {
+        Some(rate) => (rate.numerator, rate.denominator),
+        None => return None,
+    };
+    let scan = match format {
+        mpls::VideoFormat::Interlaced480
+        | mpls::VideoFormat::Interlaced576
+        | mpls::VideoFormat::Interlaced1080 => ScanType::Interlaced,
+        _ => ScanType::Progressive,
+    };
+    Some(VideoMetadata::new(
+        Framerate {
+            numerator,
+            denominator,
+        },
+        scan,
+    ))
+}
 
-    let target_audio_len = video_duration + stats.segments.last().unwrap().audio_overrun();
-    let samples_off_target = ((audio_duration - target_audio_len) * 48000f64).round() as i32;
+fn print_demux_stats(stats: &libav::DemuxStats) {
+    let summary = match DemuxSummary::new(stats) {
+        Some(s) => s,
+        None => return,
+    };
+    let (video_frames, audio_frames) = (summary.video_frames, summary.thd_frames);
+    let (video_duration, audio_duration) = (summary.video_duration, summary.audio_duration);
+    let target_audio_len = summary.target_audio_duration;
+    let samples_off_target = summary.samples_off_target;
 
     info!(
         "Video length: {:>16} frames ({:.7} seconds)",
@@ -599,6 +1826,73 @@ fn print_demux_stats(stats: &libav::DemuxStats) {
             _ => "(🔴 please file issue at https://github.com/domyd/mlp/issues)",
         }
     );
+    for cut in stats
+        .cut_map
+        .boundaries
+        .iter()
+        .filter(|c| c.reason == CutReason::Override)
+    {
+        info!("Overridden: {}", cut);
+    }
+}
+
+fn print_segment_analysis(segments: &[Segment], stats: &libav::DemuxStats) {
+    let mut total_overrun = 0f64;
+    for (i, (segment, s)) in segments.iter().zip(stats.segments.iter()).enumerate() {
+        if let Some(cut) = stats.cut_map.boundaries.iter().find(|c| c.segment == i) {
+            info!(
+                "  Boundary: covariance {}, {}, {} frame{} removed ({})",
+                cut.covariance
+                    .map_or(String::from("n/a"), |c| format!("{:.7}", c)),
+                if cut.silence { "silent" } else { "not silent" },
+                cut.frames,
+                if cut.frames == 1 { "" } else { "s" },
+                cut.reason
+            );
+        }
+
+        let sample_rate = s.thd_metadata.sample_rate as f64;
+        total_overrun += s.audio_overrun();
+        info!(
+            "Segment {} ({}): {} video frames, {} TrueHD frames, overrun {:.0} samples (total {:.0})",
+            i + 1,
+            segment.clip_name(),
+            s.video_frames.to_formatted_string(&Locale::en),
+            s.thd_frames.to_formatted_string(&Locale::en),
+            s.audio_overrun() * sample_rate,
+            total_overrun * sample_rate
+        );
+        if s.video_frame_counts.disagree() {
+            info!("  Video frame counts disagree: {}", s.video_frame_counts);
+        }
+    }
+}
+
+fn print_cut_map(cut_map: &libav::CutMap) {
+    info!(
+        "Cut map ({:.7} seconds removed in total):",
+        cut_map.total_duration()
+    );
+    for cut in cut_map
+        .boundaries
+        .iter()
+        .filter(|c| c.frames > 0 || c.overlap.is_some())
+    {
+        info!("  {}", cut);
+    }
+}
+
+fn print_secondary_stats(stats: &libav::demux::SecondaryDemuxStats, cut_map: &libav::CutMap) {
+    info!(
+        "Audio length: {:>16} frames ({} removed, {:.7} seconds)",
+        stats.frames.to_formatted_string(&Locale::en),
+        stats.frames_removed,
+        stats.duration_removed
+    );
+    info!(
+        "Drift relative to primary stream: {:.7} seconds",
+        stats.drift(cut_map)
+    );
 }
 
 fn print_frame_count_info(counter: (i32, i32), metadata: &ThdMetadata) {
@@ -657,7 +1951,64 @@ fn print_playlist_info(playlist: &Mpls) {
     }
 }
 
-fn setup_logging(verbosity_level: i32, log_ffmpeg: bool) {
+fn print_playlist_candidates(candidates: &[PlaylistCandidate]) {
+    for c in candidates {
+        let status = match c.rejected {
+            None => String::from(if c.truehd { "TrueHD" } else { "no TrueHD" }),
+            Some(Rejection::Duplicate { ref of }) => format!("duplicate of {}", of),
+            Some(Rejection::TooShort) => String::from("too short"),
+            Some(Rejection::Looping) => String::from("looping"),
+        };
+        let titles = match c.titles.len() {
+            0 => String::new(),
+            1 => format!(", title {}", c.titles[0]),
+            _ => format!(
+                ", titles {}",
+                c.titles
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        info!(
+            "{:<12} {:>9} {:>4} segment{} {:>2} angle{}  {}{}",
+            c.name,
+            format_duration(c.duration),
+            c.segments,
+            if c.segments == 1 { " " } else { "s" },
+            c.angles,
+            if c.angles == 1 { " " } else { "s" },
+            status,
+            titles
+        );
+    }
+}
+
+fn print_titles(titles: &[Title]) {
+    for t in titles {
+        if t.bdj {
+            info!("Title {}: BD-J, playlist unknown", t.number);
+        } else if t.playlists.is_empty() {
+            info!("Title {}: playlist chosen at runtime", t.number);
+        } else {
+            info!("Title {}: {}", t.number, t.playlists.join(", "));
+        }
+    }
+}
+
+// formats seconds as h:mm:ss
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn setup_logging(verbosity_level: i32, log_ffmpeg: bool, format: OutputFormat) {
     let verbosity = match verbosity_level {
         0 => LevelFilter::Info,
         1 => LevelFilter::Debug,
@@ -684,6 +2035,69 @@ fn setup_logging(verbosity_level: i32, log_ffmpeg: bool) {
         }
         builder.build()
     };
-    TermLogger::init(verbosity, logger_config, TerminalMode::Mixed).unwrap();
+    // stdout is reserved for the JSON document
+    let terminal_mode = match format {
+        OutputFormat::Text => TerminalMode::Mixed,
+        OutputFormat::Json => TerminalMode::Stderr,
+    };
+    TermLogger::init(verbosity, logger_config, terminal_mode).unwrap();
     libav::av_log::configure_rust_log(ffmpeg_log_level);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libav::checkpoint::StreamCheckpoint;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn cancelled_run_can_be_resumed() {
+        let dir = std::env::temp_dir().join(format!("mlp-resume-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let output_path = dir.join("audio.thd");
+        let checkpoint_path = checkpoint_path(&output_path);
+        let output_paths = vec![output_path.clone()];
+
+        // the first segment was completed, and the run was cancelled while
+        // writing the second one
+        std::fs::write(&output_path, vec![0xaa; 1500]).unwrap();
+        let checkpoint = Checkpoint {
+            version: libav::checkpoint::CHECKPOINT_VERSION,
+            clips: vec!["00055".into(), "00056".into()],
+            next_segment: 1,
+            streams: vec![StreamCheckpoint {
+                stream_id: 0x1100,
+                output_len: 1000,
+                segments: Vec::new(),
+                boundaries: Vec::new(),
+                previous_segment: None,
+            }],
+            secondaries: Vec::new(),
+            overrides_used: Vec::new(),
+        };
+        checkpoint.save(&checkpoint_path).unwrap();
+
+        let result: Result<(), AVError> =
+            keep_resumable_outputs(Err(AVError::Cancelled), &output_paths, &checkpoint_path);
+        assert!(matches!(result, Err(AVError::Cancelled)));
+        assert!(checkpoint_path.exists());
+        assert_eq!(std::fs::metadata(&output_path).unwrap().len(), 1000);
+
+        let resumed = read_checkpoint(&checkpoint_path).unwrap().unwrap();
+        assert_eq!(resumed.next_segment, 1);
+        let mut files = truncate_to_checkpoint(&resumed, &checkpoint_path, &output_paths).unwrap();
+        let mut output = BufWriter::new(files.remove(0));
+        output
+            .seek(SeekFrom::Start(resumed.streams[0].output_len))
+            .unwrap();
+        output.write_all(&[0xbb; 700]).unwrap();
+        drop(output);
+
+        let written = std::fs::read(&output_path).unwrap();
+        assert_eq!(written.len(), 1700);
+        assert!(written[..1000].iter().all(|&b| b == 0xaa));
+        assert!(written[1000..].iter().all(|&b| b == 0xbb));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Turns the events of a demux run into a progress bar or an NDJSON stream on
+//! stderr.
+
+use crate::libav::{DemuxEvent, DemuxObserver};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{io::Write, sync::Arc};
+
+pub const PROGRESS_NAMES: [&str; 3] = ["bar", "ndjson", "none"];
+
+/// Returns the observer for the given `--progress` setting.
+pub fn observer_by_name(name: &str) -> Option<Arc<dyn DemuxObserver>> {
+    match name {
+        "bar" => Some(Arc::new(ProgressBarObserver::new())),
+        "ndjson" => Some(Arc::new(NdjsonObserver)),
+        _ => None,
+    }
+}
+
+/// Draws a single progress bar across all segments.
+pub struct ProgressBarObserver {
+    bar: ProgressBar,
+}
+
+impl ProgressBarObserver {
+    pub fn new() -> ProgressBarObserver {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} {eta}")
+                .progress_chars("#>-"),
+        );
+        ProgressBarObserver { bar }
+    }
+}
+
+impl std::fmt::Debug for ProgressBarObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressBarObserver")
+    }
+}
+
+impl DemuxObserver for ProgressBarObserver {
+    fn on_event(&self, event: &DemuxEvent) {
+        if let DemuxEvent::Progress {
+            bytes, total_bytes, ..
+        } = *event
+        {
+            self.bar.set_length(total_bytes);
+            self.bar.set_position(bytes);
+            if total_bytes > 0 && bytes >= total_bytes {
+                self.bar.finish_and_clear();
+            }
+        }
+    }
+}
+
+/// Writes every event as a single line of JSON to stderr.
+#[derive(Debug)]
+pub struct NdjsonObserver;
+
+impl DemuxObserver for NdjsonObserver {
+    fn on_event(&self, event: &DemuxEvent) {
+        if let Ok(line) = serde_json::to_string(event) {
+            let stderr = std::io::stderr();
+            let mut stderr = stderr.lock();
+            let _ = writeln!(stderr, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Machine-readable reports for `--format json`. Every command writes a single
+//! JSON document to stdout, while all log output goes to stderr.
+
+use crate::{
+    disc::{PlaylistCandidate, Title},
+    libav::{
+        demux::{AudioStreamInfo, SecondaryDemuxStats, SegmentDemuxStats, ThdStreamInfo},
+        truehd::ThdMetadata,
+        BoundaryCut, DemuxStats, MediaDuration,
+    },
+    Segment,
+};
+use serde::Serialize;
+use std::{io::Write, path::Path};
+
+/// The version of the report schema. Bump this whenever a field is removed or
+/// changes its meaning. Adding fields doesn't require a new version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> OutputFormat {
+        match name {
+            "json" => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a, T: Serialize> {
+    schema_version: u32,
+    command: &'a str,
+    #[serde(flatten)]
+    body: &'a T,
+}
+
+/// Writes the report of the given command to stdout.
+pub fn write_json<T: Serialize>(command: &str, body: &T) -> anyhow::Result<()> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(
+        &mut stdout,
+        &Report {
+            schema_version: SCHEMA_VERSION,
+            command,
+            body,
+        },
+    )?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// Lengths and sync of a whole demux run. Durations are in seconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct DemuxSummary {
+    pub video_frames: u32,
+    pub thd_frames: u32,
+    pub video_duration: f64,
+    pub audio_duration: f64,
+    pub target_audio_duration: f64,
+    pub samples_off_target: i32,
+    /// Duration of all frames removed at segment boundaries.
+    pub removed_duration: f64,
+}
+
+impl DemuxSummary {
+    pub fn new(stats: &DemuxStats) -> Option<DemuxSummary> {
+        let (video_frames, thd_frames) = stats
+            .segments
+            .iter()
+            .map(|s| (s.video_frames, s.thd_frames))
+            .fold((0, 0), |(va, aa), (v, a)| (va + v, aa + a));
+        let (video_meta, audio_meta) = (stats.video_metadata()?, stats.thd_metadata()?);
+        let (video_duration, audio_duration) = (
+            video_meta.duration(video_frames),
+            audio_meta.duration(thd_frames),
+        );
+
+        let target_audio_duration = video_duration + stats.segments.last()?.audio_overrun();
+        let samples_off_target =
+            ((audio_duration - target_audio_duration) * 48000f64).round() as i32;
+
+        Some(DemuxSummary {
+            video_frames,
+            thd_frames,
+            video_duration,
+            audio_duration,
+            target_audio_duration,
+            samples_off_target,
+            removed_duration: stats.cut_map.total_duration(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct SegmentReport<'a> {
+    index: usize,
+    clip: String,
+    #[serde(flatten)]
+    stats: &'a SegmentDemuxStats,
+    video_duration: f64,
+    audio_duration: f64,
+    /// How far the audio of this segment runs past its video, in seconds.
+    overrun: f64,
+}
+
+/// The outcome of demuxing or analyzing a single TrueHD stream.
+#[derive(Serialize)]
+pub struct StreamReport<'a> {
+    stream: &'a ThdStreamInfo,
+    output: Option<&'a Path>,
+    segments: Vec<SegmentReport<'a>>,
+    totals: Option<DemuxSummary>,
+    boundaries: &'a [BoundaryCut],
+}
+
+impl<'a> StreamReport<'a> {
+    pub fn new(
+        stream: &'a ThdStreamInfo,
+        output: Option<&'a Path>,
+        segments: &[Segment],
+        stats: &'a DemuxStats,
+    ) -> StreamReport<'a> {
+        StreamReport {
+            stream,
+            output,
+            segments: segments
+                .iter()
+                .zip(stats.segments.iter())
+                .enumerate()
+                .map(|(index, (segment, s))| SegmentReport {
+                    index,
+                    clip: segment.clip_name(),
+                    stats: s,
+                    video_duration: s.video_duration(),
+                    audio_duration: s.audio_duration(),
+                    overrun: s.audio_overrun(),
+                })
+                .collect(),
+            totals: DemuxSummary::new(stats),
+            boundaries: &stats.cut_map.boundaries,
+        }
+    }
+}
+
+/// The outcome of cutting a secondary audio stream along with a primary
+/// TrueHD stream.
+#[derive(Serialize)]
+pub struct SecondaryReport<'a> {
+    stream: &'a AudioStreamInfo,
+    output: &'a Path,
+    #[serde(flatten)]
+    stats: &'a SecondaryDemuxStats,
+    /// Drift relative to the primary stream, in seconds.
+    drift: f64,
+}
+
+impl<'a> SecondaryReport<'a> {
+    pub fn new(
+        stream: &'a AudioStreamInfo,
+        output: &'a Path,
+        stats: &'a SecondaryDemuxStats,
+        primary: &DemuxStats,
+    ) -> SecondaryReport<'a> {
+        SecondaryReport {
+            stream,
+            output,
+            stats,
+            drift: stats.drift(&primary.cut_map),
+        }
+    }
+}
+
+/// The report of the `demux` and `analyze` commands.
+#[derive(Serialize)]
+pub struct DemuxReport<'a> {
+    pub streams: Vec<StreamReport<'a>>,
+    pub secondaries: Vec<SecondaryReport<'a>>,
+}
+
+/// The report of the `info` command.
+#[derive(Serialize)]
+pub struct FrameCountReport<'a> {
+    pub streams: &'a [ThdStreamInfo],
+    pub metadata: ThdMetadata,
+    pub frames: i32,
+    pub major_frames: i32,
+    pub minor_frames: i32,
+    pub samples: i64,
+    /// Duration in seconds.
+    pub duration: f64,
+}
+
+impl<'a> FrameCountReport<'a> {
+    pub fn new(
+        streams: &'a [ThdStreamInfo],
+        (frames, major_frames): (i32, i32),
+        metadata: ThdMetadata,
+    ) -> FrameCountReport<'a> {
+        FrameCountReport {
+            streams,
+            metadata,
+            frames,
+            major_frames,
+            minor_frames: frames - major_frames,
+            samples: frames as i64 * metadata.frame_size as i64,
+            duration: metadata.duration(frames as u32),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AngleReport {
+    /// The angle index, starting at 1.
+    pub index: u8,
+    /// The clip names of the angle's segments.
+    pub segments: Vec<String>,
+}
+
+/// The report of `demux playlist` without an output file.
+#[derive(Serialize)]
+pub struct PlaylistReport<'a> {
+    pub streams: &'a [ThdStreamInfo],
+    pub angles: Vec<AngleReport>,
+}
+
+/// The report of the `list` command.
+#[derive(Serialize)]
+pub struct PlaylistListReport<'a> {
+    /// The name of the best main feature candidate.
+    pub main_feature: Option<&'a str>,
+    /// All playlists, main feature candidates first.
+    pub playlists: &'a [PlaylistCandidate],
+    pub titles: &'a [Title],
+}
@@ -0,0 +1,388 @@
+//! The descriptors of ECMA-167 (and their UDF 2.50 restrictions) that are
+//! needed to find and read files. All multi-byte fields are little-endian.
+
+use super::UdfError;
+
+pub const SECTOR_SIZE: u64 = 2048;
+
+pub const TAG_ANCHOR_VOLUME_DESCRIPTOR_POINTER: u16 = 2;
+pub const TAG_PARTITION_DESCRIPTOR: u16 = 5;
+pub const TAG_LOGICAL_VOLUME_DESCRIPTOR: u16 = 6;
+pub const TAG_TERMINATING_DESCRIPTOR: u16 = 8;
+pub const TAG_FILE_SET_DESCRIPTOR: u16 = 256;
+pub const TAG_FILE_IDENTIFIER_DESCRIPTOR: u16 = 257;
+pub const TAG_ALLOCATION_EXTENT_DESCRIPTOR: u16 = 258;
+pub const TAG_FILE_ENTRY: u16 = 261;
+pub const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+
+// file characteristics of a file identifier descriptor
+const FID_DELETED: u8 = 0x04;
+const FID_PARENT: u8 = 0x08;
+
+pub fn le_u16(b: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([b[offset], b[offset + 1]])
+}
+
+pub fn le_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([b[offset], b[offset + 1], b[offset + 2], b[offset + 3]])
+}
+
+pub fn le_u64(b: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&b[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// fails with `InvalidDescriptor` unless `b` holds at least `len` bytes
+fn ensure_len(b: &[u8], len: usize, what: &str) -> Result<(), UdfError> {
+    if b.len() < len {
+        Err(UdfError::InvalidDescriptor(format!(
+            "{} is truncated",
+            what
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the identifier of the descriptor tag at the start of `b`, or `None`
+/// if there's no valid tag.
+pub fn tag_id(b: &[u8]) -> Option<u16> {
+    if b.len() < 16 {
+        return None;
+    }
+    // the checksum covers the tag itself, except for the checksum byte
+    let checksum = b[..16]
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 4)
+        .fold(0u8, |acc, (_, &x)| acc.wrapping_add(x));
+    if checksum == b[4] {
+        Some(le_u16(b, 0))
+    } else {
+        None
+    }
+}
+
+/// Fails unless `b` starts with a valid tag with the given identifier.
+pub fn expect_tag(b: &[u8], id: u16, what: &str) -> Result<(), UdfError> {
+    match tag_id(b) {
+        Some(found) if found == id => Ok(()),
+        _ => Err(UdfError::InvalidDescriptor(format!("expected a {}", what))),
+    }
+}
+
+/// An address of a logical block within a partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LbAddr {
+    pub block: u32,
+    /// The index of the partition map.
+    pub partition: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtentKind {
+    Recorded,
+    /// Allocated or not, but not recorded. Reads as zeros.
+    NotRecorded,
+    /// Points to the next extent of allocation descriptors.
+    Continuation,
+}
+
+/// A `short_ad` or a `long_ad`. Short ones get the partition of the ICB they
+/// were recorded in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllocationDescriptor {
+    pub length: u32,
+    pub kind: ExtentKind,
+    pub location: LbAddr,
+}
+
+impl AllocationDescriptor {
+    fn new(raw_length: u32, location: LbAddr) -> AllocationDescriptor {
+        AllocationDescriptor {
+            length: raw_length & 0x3FFF_FFFF,
+            kind: match raw_length >> 30 {
+                0 => ExtentKind::Recorded,
+                3 => ExtentKind::Continuation,
+                _ => ExtentKind::NotRecorded,
+            },
+            location,
+        }
+    }
+
+    pub fn parse_long(b: &[u8]) -> AllocationDescriptor {
+        AllocationDescriptor::new(
+            le_u32(b, 0),
+            LbAddr {
+                block: le_u32(b, 4),
+                partition: le_u16(b, 8),
+            },
+        )
+    }
+
+    fn parse_short(b: &[u8], partition: u16) -> AllocationDescriptor {
+        AllocationDescriptor::new(
+            le_u32(b, 0),
+            LbAddr {
+                block: le_u32(b, 4),
+                partition,
+            },
+        )
+    }
+}
+
+/// How the allocation descriptors of a file entry are recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdFormat {
+    Short,
+    Long,
+    /// The file's data is recorded in place of the allocation descriptors.
+    Embedded,
+}
+
+/// Parses a sequence of allocation descriptors, up to the first one with a
+/// length of zero.
+pub fn parse_allocation_descriptors(
+    b: &[u8],
+    format: AdFormat,
+    partition: u16,
+) -> Vec<AllocationDescriptor> {
+    let size = match format {
+        AdFormat::Short => 8,
+        AdFormat::Long => 16,
+        AdFormat::Embedded => return Vec::new(),
+    };
+    b.chunks_exact(size)
+        .map(|b| match format {
+            AdFormat::Short => AllocationDescriptor::parse_short(b, partition),
+            _ => AllocationDescriptor::parse_long(b),
+        })
+        .take_while(|ad| ad.length > 0)
+        .collect()
+}
+
+pub struct PartitionDescriptor {
+    pub number: u16,
+    /// The first sector of the partition.
+    pub start: u32,
+}
+
+impl PartitionDescriptor {
+    pub fn parse(b: &[u8]) -> Result<PartitionDescriptor, UdfError> {
+        ensure_len(b, 192, "partition descriptor")?;
+        Ok(PartitionDescriptor {
+            number: le_u16(b, 22),
+            start: le_u32(b, 188),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartitionMap {
+    /// A type 1 map, or a sparable partition, whose sparing tables we ignore
+    /// since images of pressed discs don't have any defects.
+    Physical { partition: u16 },
+    /// The UDF 2.50 metadata partition, whose blocks are those of the
+    /// metadata file in the given physical partition.
+    Metadata {
+        partition: u16,
+        file: u32,
+        mirror: u32,
+    },
+    /// A partition type we can't read, e.g. the virtual partition of
+    /// sequentially recorded discs.
+    Unsupported(String),
+}
+
+pub struct LogicalVolumeDescriptor {
+    pub block_size: u32,
+    /// Where the file set descriptor is.
+    pub file_set: AllocationDescriptor,
+    pub partition_maps: Vec<PartitionMap>,
+}
+
+impl LogicalVolumeDescriptor {
+    pub fn parse(b: &[u8]) -> Result<LogicalVolumeDescriptor, UdfError> {
+        ensure_len(b, 440, "logical volume descriptor")?;
+        let map_table_length = le_u32(b, 264) as usize;
+        let map_count = le_u32(b, 268) as usize;
+        ensure_len(b, 440 + map_table_length, "partition map table")?;
+
+        let mut partition_maps = Vec::with_capacity(map_count);
+        let mut maps = &b[440..440 + map_table_length];
+        for _ in 0..map_count {
+            if maps.len() < 2 || maps[1] < 2 || maps.len() < maps[1] as usize {
+                return Err(UdfError::InvalidDescriptor(String::from(
+                    "partition map is truncated",
+                )));
+            }
+            let (map, rest) = maps.split_at(maps[1] as usize);
+            partition_maps.push(PartitionMap::parse(map));
+            maps = rest;
+        }
+
+        Ok(LogicalVolumeDescriptor {
+            block_size: le_u32(b, 212),
+            file_set: AllocationDescriptor::parse_long(&b[248..264]),
+            partition_maps,
+        })
+    }
+}
+
+impl PartitionMap {
+    fn parse(b: &[u8]) -> PartitionMap {
+        match (b[0], b.len()) {
+            (1, 6) => PartitionMap::Physical {
+                partition: le_u16(b, 4),
+            },
+            (2, 64) => {
+                let identifier = entity_identifier(&b[4..36]);
+                match identifier.as_str() {
+                    "*UDF Metadata Partition" => PartitionMap::Metadata {
+                        partition: le_u16(b, 38),
+                        file: le_u32(b, 40),
+                        mirror: le_u32(b, 44),
+                    },
+                    "*UDF Sparable Partition" => PartitionMap::Physical {
+                        partition: le_u16(b, 38),
+                    },
+                    _ => PartitionMap::Unsupported(identifier),
+                }
+            }
+            (kind, _) => PartitionMap::Unsupported(format!("partition map type {}", kind)),
+        }
+    }
+}
+
+/// The identifier of a `regid`, without its suffix.
+fn entity_identifier(b: &[u8]) -> String {
+    let identifier = &b[1..24];
+    let end = identifier
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(identifier.len());
+    String::from_utf8_lossy(&identifier[..end]).into_owned()
+}
+
+/// Returns the location of the root directory's ICB from a file set
+/// descriptor.
+pub fn parse_file_set_descriptor(b: &[u8]) -> Result<AllocationDescriptor, UdfError> {
+    expect_tag(b, TAG_FILE_SET_DESCRIPTOR, "file set descriptor")?;
+    ensure_len(b, 416, "file set descriptor")?;
+    Ok(AllocationDescriptor::parse_long(&b[400..416]))
+}
+
+/// A file entry or an extended file entry.
+pub struct FileEntry {
+    pub is_directory: bool,
+    pub length: u64,
+    pub format: AdFormat,
+    /// The allocation descriptors, or the file's data if it's embedded.
+    pub allocation_descriptors: Vec<u8>,
+}
+
+impl FileEntry {
+    pub fn parse(b: &[u8]) -> Result<FileEntry, UdfError> {
+        let header_len = match tag_id(b) {
+            Some(TAG_FILE_ENTRY) => 176,
+            Some(TAG_EXTENDED_FILE_ENTRY) => 216,
+            _ => {
+                return Err(UdfError::InvalidDescriptor(String::from(
+                    "expected a file entry",
+                )))
+            }
+        };
+        ensure_len(b, header_len, "file entry")?;
+        let ea_length = le_u32(b, header_len - 8) as usize;
+        let ad_length = le_u32(b, header_len - 4) as usize;
+        let ad_start = header_len + ea_length;
+        ensure_len(b, ad_start + ad_length, "file entry")?;
+
+        let format = match le_u16(b, 34) & 0x7 {
+            0 => AdFormat::Short,
+            1 => AdFormat::Long,
+            3 => AdFormat::Embedded,
+            _ => {
+                return Err(UdfError::InvalidDescriptor(String::from(
+                    "extended allocation descriptors aren't supported",
+                )))
+            }
+        };
+
+        Ok(FileEntry {
+            is_directory: b[27] == FILE_TYPE_DIRECTORY,
+            length: le_u64(b, 56),
+            format,
+            allocation_descriptors: b[ad_start..ad_start + ad_length].to_vec(),
+        })
+    }
+}
+
+/// Returns the allocation descriptors that an allocation extent descriptor
+/// continues with.
+pub fn allocation_extent_descriptors(b: &[u8]) -> Result<&[u8], UdfError> {
+    expect_tag(
+        b,
+        TAG_ALLOCATION_EXTENT_DESCRIPTOR,
+        "allocation extent descriptor",
+    )?;
+    ensure_len(b, 24, "allocation extent descriptor")?;
+    let length = le_u32(b, 20) as usize;
+    ensure_len(b, 24 + length, "allocation extent descriptor")?;
+    Ok(&b[24..24 + length])
+}
+
+/// A file or directory, as listed in its parent directory.
+#[derive(Debug, Clone)]
+pub struct FileIdentifier {
+    pub name: String,
+    pub icb: AllocationDescriptor,
+}
+
+/// Parses the file identifier descriptors of a directory, leaving out deleted
+/// files and the parent directory.
+pub fn parse_file_identifiers(mut b: &[u8]) -> Result<Vec<FileIdentifier>, UdfError> {
+    let mut identifiers = Vec::new();
+    while b.len() >= 38 {
+        expect_tag(
+            b,
+            TAG_FILE_IDENTIFIER_DESCRIPTOR,
+            "file identifier descriptor",
+        )?;
+        let characteristics = b[18];
+        let name_length = b[19] as usize;
+        let impl_use_length = le_u16(b, 36) as usize;
+        let name_start = 38 + impl_use_length;
+        // every descriptor is padded to a multiple of four bytes
+        let length = (name_start + name_length + 3) & !3;
+        ensure_len(b, name_start + name_length, "file identifier descriptor")?;
+
+        if characteristics & (FID_DELETED | FID_PARENT) == 0 {
+            identifiers.push(FileIdentifier {
+                name: decode_cs0(&b[name_start..name_start + name_length]),
+                icb: AllocationDescriptor::parse_long(&b[20..36]),
+            });
+        }
+        b = &b[length.min(b.len())..];
+    }
+    Ok(identifiers)
+}
+
+/// Decodes an OSTA compressed Unicode string, which starts with the number of
+/// bits per character.
+pub fn decode_cs0(b: &[u8]) -> String {
+    match b.split_first() {
+        Some((8, chars)) => chars.iter().map(|&c| c as char).collect(),
+        Some((16, chars)) => {
+            let units: Vec<u16> = chars
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// A contiguous range of a file's data.
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    /// The byte offset in the image, or `None` if the extent isn't recorded
+    /// and reads as zeros.
+    pub offset: Option<u64>,
+    pub length: u64,
+}
+
+#[derive(Debug)]
+pub enum FileData {
+    Extents(Vec<Extent>),
+    /// Small files may be recorded in their file entry.
+    Embedded(Vec<u8>),
+}
+
+/// A file inside a UDF image.
+#[derive(Debug)]
+pub struct UdfFile {
+    image: File,
+    data: FileData,
+    len: u64,
+    position: u64,
+}
+
+impl UdfFile {
+    pub(super) fn new(image: File, data: FileData, len: u64) -> UdfFile {
+        UdfFile {
+            image,
+            data,
+            len,
+            position: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Read for UdfFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let max = (buf.len() as u64).min(remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+
+        let read = match self.data {
+            FileData::Embedded(ref bytes) => {
+                let start = self.position as usize;
+                let n = max.min(bytes.len().saturating_sub(start));
+                buf[..n].copy_from_slice(&bytes[start..start + n]);
+                n
+            }
+            FileData::Extents(ref extents) => {
+                // find the extent that contains the current position
+                let mut start = 0u64;
+                let extent = extents.iter().find(|e| {
+                    start += e.length;
+                    start > self.position
+                });
+                let extent = match extent {
+                    Some(e) => e,
+                    None => return Ok(0),
+                };
+                let offset = self.position - (start - extent.length);
+                let n = max.min((extent.length - offset) as usize);
+                match extent.offset {
+                    Some(o) => {
+                        self.image.seek(SeekFrom::Start(o + offset))?;
+                        self.image.read(&mut buf[..n])?
+                    }
+                    None => {
+                        buf[..n].iter_mut().for_each(|b| *b = 0);
+                        n
+                    }
+                }
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for UdfFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => checked_offset(self.len, n),
+            SeekFrom::Current(n) => checked_offset(self.position, n),
+        };
+        match position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
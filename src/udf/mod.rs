@@ -0,0 +1,473 @@
+//! A reader for the UDF 2.50 file system of Blu-ray discs, so that playlists
+//! and clips can be read straight from ISO images without mounting them.
+
+mod descriptors;
+mod file;
+
+pub use file::UdfFile;
+
+use descriptors::{
+    allocation_extent_descriptors, parse_allocation_descriptors, parse_file_identifiers,
+    parse_file_set_descriptor, tag_id, AdFormat, AllocationDescriptor, ExtentKind, FileEntry,
+    LbAddr, LogicalVolumeDescriptor, PartitionDescriptor, PartitionMap, SECTOR_SIZE,
+    TAG_ANCHOR_VOLUME_DESCRIPTOR_POINTER, TAG_LOGICAL_VOLUME_DESCRIPTOR, TAG_PARTITION_DESCRIPTOR,
+    TAG_TERMINATING_DESCRIPTOR,
+};
+use file::{Extent, FileData};
+use log::{debug, warn};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// The sector of the first anchor volume descriptor pointer.
+const ANCHOR_SECTOR: u64 = 256;
+
+/// The number of allocation extent descriptors we follow for a single file,
+/// which guards against loops in broken images.
+const MAX_ALLOCATION_EXTENTS: usize = 1024;
+
+#[derive(Debug)]
+pub enum UdfError {
+    Io(io::Error),
+    /// The image doesn't contain an anchor volume descriptor pointer.
+    NotUdf,
+    InvalidDescriptor(String),
+    UnsupportedPartition(String),
+    FileNotFound(String),
+    NotADirectory(String),
+}
+
+impl From<io::Error> for UdfError {
+    fn from(err: io::Error) -> Self {
+        UdfError::Io(err)
+    }
+}
+
+impl From<UdfError> for io::Error {
+    fn from(err: UdfError) -> Self {
+        match err {
+            UdfError::Io(e) => e,
+            UdfError::FileNotFound(_) => io::Error::new(io::ErrorKind::NotFound, err),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+impl std::error::Error for UdfError {}
+
+impl Display for UdfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UdfError::Io(e) => write!(f, "{}", e),
+            UdfError::NotUdf => write!(f, "The image doesn't contain a UDF file system."),
+            UdfError::InvalidDescriptor(s) => write!(f, "Invalid UDF file system: {}.", s),
+            UdfError::UnsupportedPartition(s) => {
+                write!(f, "UDF partitions of type \"{}\" aren't supported.", s)
+            }
+            UdfError::FileNotFound(s) => write!(f, "{} doesn't exist in the image.", s),
+            UdfError::NotADirectory(s) => write!(f, "{} isn't a directory.", s),
+        }
+    }
+}
+
+/// Where the logical blocks of a partition map are in the image.
+#[derive(Debug)]
+enum Partition {
+    Physical {
+        // the byte offset of the partition's first block
+        start: u64,
+    },
+    Metadata {
+        // the extents of the metadata file, whose contents are the blocks of
+        // the partition
+        extents: Vec<Extent>,
+    },
+}
+
+/// A UDF file system in an image file. Files are read through their own file
+/// handles, so an image can be shared between threads.
+#[derive(Debug)]
+pub struct UdfImage {
+    path: PathBuf,
+    partitions: Vec<Partition>,
+    root: AllocationDescriptor,
+}
+
+impl UdfImage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<UdfImage, UdfError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path)?;
+
+        // the anchor is at sector 256, but may also be at the end of the image
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+        let anchor = [
+            ANCHOR_SECTOR,
+            sectors.saturating_sub(1),
+            sectors.saturating_sub(257),
+        ]
+        .iter()
+        .filter(|&&s| s > 0 && s < sectors)
+        .filter_map(|&s| read_sector(&mut file, s).ok())
+        .find(|b| tag_id(b) == Some(TAG_ANCHOR_VOLUME_DESCRIPTOR_POINTER))
+        .ok_or(UdfError::NotUdf)?;
+
+        // try the main volume descriptor sequence first, then the reserve one
+        let main = read_volume_descriptors(&mut file, &anchor[16..24]);
+        let (lvd, partition_descriptors) = match main {
+            Ok(d) => d,
+            Err(e) => {
+                warn!(
+                    "Main volume descriptor sequence is invalid ({}), using the reserve sequence.",
+                    e
+                );
+                read_volume_descriptors(&mut file, &anchor[24..32])?
+            }
+        };
+        if lvd.block_size as u64 != SECTOR_SIZE {
+            return Err(UdfError::InvalidDescriptor(format!(
+                "logical blocks of {} bytes aren't supported",
+                lvd.block_size
+            )));
+        }
+
+        let mut image = UdfImage {
+            path,
+            partitions: Vec::with_capacity(lvd.partition_maps.len()),
+            root: lvd.file_set,
+        };
+        let physical_start = |number: u16| {
+            partition_descriptors
+                .iter()
+                .find(|p| p.number == number)
+                .map(|p| p.start as u64 * SECTOR_SIZE)
+                .ok_or_else(|| {
+                    UdfError::InvalidDescriptor(format!("partition {} doesn't exist", number))
+                })
+        };
+
+        // physical partitions first, since the metadata file is read from one
+        let mut metadata_maps = Vec::new();
+        for (i, map) in lvd.partition_maps.iter().enumerate() {
+            match *map {
+                PartitionMap::Physical { partition } => {
+                    image.partitions.push(Partition::Physical {
+                        start: physical_start(partition)?,
+                    });
+                }
+                PartitionMap::Metadata { partition, .. } => {
+                    // the metadata file is recorded in the physical partition
+                    // the metadata partition is built on. Until it has been
+                    // read, the map points to that physical partition, so that
+                    // the file's own allocation descriptors resolve there.
+                    image.partitions.push(Partition::Physical {
+                        start: physical_start(partition)?,
+                    });
+                    metadata_maps.push(i);
+                }
+                PartitionMap::Unsupported(ref kind) => {
+                    return Err(UdfError::UnsupportedPartition(kind.clone()))
+                }
+            }
+        }
+        for i in metadata_maps {
+            if let PartitionMap::Metadata {
+                file: file_block,
+                mirror,
+                ..
+            } = lvd.partition_maps[i]
+            {
+                let extents = image
+                    .metadata_extents(&mut file, i as u16, file_block)
+                    .or_else(|e| {
+                        warn!("Metadata file is invalid ({}), using its mirror.", e);
+                        image.metadata_extents(&mut file, i as u16, mirror)
+                    })?;
+                image.partitions[i] = Partition::Metadata { extents };
+            }
+        }
+
+        let file_set = image.read_extent(&mut file, &image.root)?;
+        image.root = parse_file_set_descriptor(&file_set)?;
+        debug!("Opened UDF image {}.", image.path.display());
+        Ok(image)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the file at the given path inside the image. Both `/` and `\`
+    /// separate directories, and names are compared case-insensitively.
+    pub fn open_file(&self, path: &str) -> Result<UdfFile, UdfError> {
+        let mut file = File::open(&self.path)?;
+        let (icb, entry) = self.find(&mut file, path)?;
+        let data = self.file_data(&mut file, &icb, &entry)?;
+        Ok(UdfFile::new(file, data, entry.length))
+    }
+
+    /// Lists the names of the files and directories in the directory at the
+    /// given path inside the image.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, UdfError> {
+        let mut file = File::open(&self.path)?;
+        let (icb, entry) = self.find(&mut file, path)?;
+        if !entry.is_directory {
+            return Err(UdfError::NotADirectory(path.to_string()));
+        }
+        let data = self.read_all(&mut file, &icb, &entry)?;
+        Ok(parse_file_identifiers(&data)?
+            .into_iter()
+            .map(|f| f.name)
+            .collect())
+    }
+
+    // walks the directories down to the file entry at the given path
+    fn find(
+        &self,
+        file: &mut File,
+        path: &str,
+    ) -> Result<(AllocationDescriptor, FileEntry), UdfError> {
+        let mut icb = self.root;
+        let mut entry = self.read_file_entry(file, &icb)?;
+        let mut current = String::from("/");
+        for name in path.split(&['/', '\\'][..]).filter(|s| !s.is_empty()) {
+            if !entry.is_directory {
+                return Err(UdfError::NotADirectory(current));
+            }
+            let data = self.read_all(file, &icb, &entry)?;
+            current = format!("{}/{}", current.trim_end_matches('/'), name);
+            icb = parse_file_identifiers(&data)?
+                .into_iter()
+                .find(|f| f.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| UdfError::FileNotFound(current.clone()))?
+                .icb;
+            entry = self.read_file_entry(file, &icb)?;
+        }
+        Ok((icb, entry))
+    }
+
+    fn read_file_entry(
+        &self,
+        file: &mut File,
+        icb: &AllocationDescriptor,
+    ) -> Result<FileEntry, UdfError> {
+        let sector = self.read_extent(file, icb)?;
+        FileEntry::parse(&sector)
+    }
+
+    // resolves where the data of a file entry is, following any allocation
+    // extent descriptors
+    fn file_data(
+        &self,
+        file: &mut File,
+        icb: &AllocationDescriptor,
+        entry: &FileEntry,
+    ) -> Result<FileData, UdfError> {
+        if entry.format == AdFormat::Embedded {
+            return Ok(FileData::Embedded(entry.allocation_descriptors.clone()));
+        }
+
+        let mut extents = Vec::new();
+        let mut descriptors = parse_allocation_descriptors(
+            &entry.allocation_descriptors,
+            entry.format,
+            icb.location.partition,
+        );
+        for _ in 0..MAX_ALLOCATION_EXTENTS {
+            let mut next = None;
+            for ad in descriptors {
+                match ad.kind {
+                    ExtentKind::Recorded => {
+                        extents.extend(self.resolve(ad.location, ad.length as u64)?)
+                    }
+                    ExtentKind::NotRecorded => extents.push(Extent {
+                        offset: None,
+                        length: ad.length as u64,
+                    }),
+                    ExtentKind::Continuation => {
+                        next = Some(ad);
+                        break;
+                    }
+                }
+            }
+            match next {
+                Some(ad) => {
+                    let sector = self.read_extent(file, &ad)?;
+                    descriptors = parse_allocation_descriptors(
+                        allocation_extent_descriptors(&sector)?,
+                        entry.format,
+                        icb.location.partition,
+                    );
+                }
+                None => return Ok(FileData::Extents(extents)),
+            }
+        }
+        Err(UdfError::InvalidDescriptor(String::from(
+            "too many allocation extent descriptors",
+        )))
+    }
+
+    // reads the whole contents of a (small) file, e.g. a directory
+    fn read_all(
+        &self,
+        file: &mut File,
+        icb: &AllocationDescriptor,
+        entry: &FileEntry,
+    ) -> Result<Vec<u8>, UdfError> {
+        let data = self.file_data(file, icb, entry)?;
+        let mut reader = UdfFile::new(file.try_clone()?, data, entry.length);
+        let mut bytes = Vec::with_capacity(entry.length as usize);
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // reads the extents of the metadata file of the given metadata partition
+    fn metadata_extents(
+        &self,
+        file: &mut File,
+        partition: u16,
+        block: u32,
+    ) -> Result<Vec<Extent>, UdfError> {
+        let icb = AllocationDescriptor {
+            length: SECTOR_SIZE as u32,
+            kind: ExtentKind::Recorded,
+            location: LbAddr { block, partition },
+        };
+        let entry = self.read_file_entry(file, &icb)?;
+        match self.file_data(file, &icb, &entry)? {
+            FileData::Extents(extents) => Ok(extents),
+            FileData::Embedded(_) => Err(UdfError::InvalidDescriptor(String::from(
+                "the metadata file is embedded",
+            ))),
+        }
+    }
+
+    // reads the first sector of the given extent
+    fn read_extent(&self, file: &mut File, ad: &AllocationDescriptor) -> Result<Vec<u8>, UdfError> {
+        let extent = self
+            .resolve(ad.location, SECTOR_SIZE)?
+            .into_iter()
+            .next()
+            .and_then(|e| e.offset)
+            .ok_or_else(|| {
+                UdfError::InvalidDescriptor(String::from("descriptor isn't recorded"))
+            })?;
+        file.seek(SeekFrom::Start(extent))?;
+        let mut sector = vec![0u8; SECTOR_SIZE as usize];
+        file.read_exact(&mut sector)?;
+        Ok(sector)
+    }
+
+    // maps `length` bytes starting at the given logical block to where they
+    // are in the image
+    fn resolve(&self, location: LbAddr, length: u64) -> Result<Vec<Extent>, UdfError> {
+        let partition = self
+            .partitions
+            .get(location.partition as usize)
+            .ok_or_else(|| {
+                UdfError::InvalidDescriptor(format!(
+                    "partition map {} doesn't exist",
+                    location.partition
+                ))
+            })?;
+        let offset = location.block as u64 * SECTOR_SIZE;
+        match partition {
+            Partition::Physical { start } => Ok(vec![Extent {
+                offset: Some(start + offset),
+                length,
+            }]),
+            Partition::Metadata { extents } => {
+                let mut resolved = Vec::new();
+                let (mut offset, mut remaining) = (offset, length);
+                for extent in extents {
+                    if remaining == 0 {
+                        break;
+                    }
+                    if offset >= extent.length {
+                        offset -= extent.length;
+                        continue;
+                    }
+                    let length = (extent.length - offset).min(remaining);
+                    resolved.push(Extent {
+                        offset: extent.offset.map(|o| o + offset),
+                        length,
+                    });
+                    remaining -= length;
+                    offset = 0;
+                }
+                if remaining > 0 {
+                    return Err(UdfError::InvalidDescriptor(String::from(
+                        "block is outside of the metadata partition",
+                    )));
+                }
+                Ok(resolved)
+            }
+        }
+    }
+}
+
+fn read_sector(file: &mut File, sector: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    let mut b = vec![0u8; SECTOR_SIZE as usize];
+    file.read_exact(&mut b)?;
+    Ok(b)
+}
+
+// reads the logical volume descriptor and all partition descriptors of the
+// volume descriptor sequence in the given `extent_ad`
+fn read_volume_descriptors(
+    file: &mut File,
+    extent: &[u8],
+) -> Result<(LogicalVolumeDescriptor, Vec<PartitionDescriptor>), UdfError> {
+    let length = descriptors::le_u32(extent, 0) as u64;
+    let location = descriptors::le_u32(extent, 4) as u64;
+
+    let mut lvd = None;
+    let mut partitions = Vec::new();
+    for sector in location..location + length / SECTOR_SIZE {
+        let b = read_sector(file, sector)?;
+        match tag_id(&b) {
+            Some(TAG_PARTITION_DESCRIPTOR) => partitions.push(PartitionDescriptor::parse(&b)?),
+            Some(TAG_LOGICAL_VOLUME_DESCRIPTOR) if lvd.is_none() => {
+                lvd = Some(LogicalVolumeDescriptor::parse(&b)?)
+            }
+            Some(TAG_TERMINATING_DESCRIPTOR) => break,
+            _ => {}
+        }
+    }
+
+    match lvd {
+        Some(lvd) => Ok((lvd, partitions)),
+        None => Err(UdfError::InvalidDescriptor(String::from(
+            "no logical volume descriptor found",
+        ))),
+    }
+}
+
+/// Splits a path of the form `disc.iso:/BDMV/PLAYLIST/00800.mpls` into the
+/// path of the image and the path inside of it. Returns `None` for any other
+/// path.
+pub fn split_image_path(path: &str) -> Option<(PathBuf, &str)> {
+    let lower = path.to_ascii_lowercase();
+    let end = lower.find(".iso:")? + ".iso".len();
+    Some((PathBuf::from(&path[..end]), &path[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_paths_are_split() {
+        assert_eq!(
+            split_image_path(r"C:\Discs\MOVIE.ISO:/BDMV/PLAYLIST/00800.mpls"),
+            Some((
+                PathBuf::from(r"C:\Discs\MOVIE.ISO"),
+                "/BDMV/PLAYLIST/00800.mpls"
+            ))
+        );
+        assert_eq!(split_image_path(r"C:\BDMV\PLAYLIST\00800.mpls"), None);
+    }
+}